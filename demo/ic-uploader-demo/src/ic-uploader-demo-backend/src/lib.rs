@@ -22,7 +22,8 @@ thread_local! {
 // Re-export storage functions for Candid
 pub use storage::{
     append_chunk, buffer_size, clear_buffer, save_to_stable, load_from_stable,
-    get_data, get_stable_data,
+    get_data, get_stable_data, begin_upload, put_chunk, commit_upload,
+    sha256, chunk_hashes, size, get_chunk, read,
 };
 
 ic_cdk::export_candid!();
\ No newline at end of file
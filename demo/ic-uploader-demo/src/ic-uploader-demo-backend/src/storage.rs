@@ -1,14 +1,29 @@
 // src/storage.rs
 //! Ultra-simple storage: one heap buffer, stable storage with keys
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use sha2::{Digest, Sha256};
 use crate::REGISTRIES;
 
+/// Size of the pieces returned by `get_chunk`, below the ~2 MB reply limit
+const READ_CHUNK_SIZE: usize = 2_000_000;
+
+/// An upload in progress, opened by `begin_upload`
+struct UploadSession {
+    name: String,
+    total_size: u64,
+    chunk_count: u32,
+    sha256: Vec<u8>,
+    chunks: HashMap<u32, Vec<u8>>,
+}
+
 // Single buffer in heap - only one large object at a time
 thread_local! {
     static BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::new());
     static BUFFER_MAP: RefCell<HashMap<u32, Vec<u8>>> = RefCell::new(HashMap::new());
+    static SESSIONS: RefCell<HashMap<u64, UploadSession>> = RefCell::new(HashMap::new());
+    static NEXT_SESSION_ID: Cell<u64> = Cell::new(0);
 }
 
 // ─────────────────────────────────────────────────────
@@ -109,6 +124,103 @@ pub fn remove_parallel_chunk(chunk_id: u32) -> bool {
     })
 }
 
+// ─────────────────────────────────────────────────────
+//  IC Canister Endpoints - Upload Sessions
+// ─────────────────────────────────────────────────────
+
+/// Open an upload session; chunks of different sessions never mix
+#[ic_cdk::update]
+pub fn begin_upload(name: String, total_size: u64, chunk_count: u32, sha256: Vec<u8>) -> u64 {
+    let session_id = NEXT_SESSION_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+
+    SESSIONS.with(|sessions| {
+        sessions.borrow_mut().insert(session_id, UploadSession {
+            name,
+            total_size,
+            chunk_count,
+            sha256,
+            chunks: HashMap::new(),
+        });
+    });
+
+    session_id
+}
+
+/// Store one chunk of a session (traps on an unknown session or index)
+#[ic_cdk::update]
+pub fn put_chunk(session_id: u64, index: u32, chunk: Vec<u8>) {
+    SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let Some(session) = sessions.get_mut(&session_id) else {
+            ic_cdk::trap(&format!("Unknown upload session: {}", session_id));
+        };
+        if index >= session.chunk_count {
+            ic_cdk::trap(&format!("Chunk {} out of range for session {} ({} chunks)", index, session_id, session.chunk_count));
+        }
+        session.chunks.insert(index, chunk);
+    });
+}
+
+/// Get list of chunk indices stored for a session
+#[ic_cdk::query]
+pub fn session_chunk_ids(session_id: u64) -> Vec<u32> {
+    SESSIONS.with(|sessions| {
+        let mut ids: Vec<u32> = sessions
+            .borrow()
+            .get(&session_id)
+            .map(|session| session.chunks.keys().copied().collect())
+            .unwrap_or_default();
+        ids.sort();
+        ids
+    })
+}
+
+/// Assemble a complete session, verify its size and SHA-256, and save it to stable storage under its name
+#[ic_cdk::update]
+pub fn commit_upload(session_id: u64) -> Result<(), String> {
+    let session = SESSIONS.with(|sessions| sessions.borrow_mut().remove(&session_id))
+        .ok_or_else(|| format!("Unknown upload session: {}", session_id))?;
+
+    let missing: Vec<u32> = (0..session.chunk_count)
+        .filter(|index| !session.chunks.contains_key(index))
+        .collect();
+    if !missing.is_empty() {
+        let error = format!("Session {} is missing chunks {:?}", session_id, missing);
+        // Keep the session so the missing chunks can still be uploaded
+        SESSIONS.with(|sessions| sessions.borrow_mut().insert(session_id, session));
+        return Err(error);
+    }
+
+    let mut chunks = session.chunks;
+    let mut data = Vec::with_capacity(session.total_size as usize);
+    for index in 0..session.chunk_count {
+        data.extend(chunks.remove(&index).unwrap_or_default());
+    }
+
+    if data.len() as u64 != session.total_size {
+        return Err(format!("Session {} holds {} bytes, expected {}", session_id, data.len(), session.total_size));
+    }
+    if Sha256::digest(&data).as_slice() != session.sha256.as_slice() {
+        return Err(format!("Session {} failed SHA-256 verification", session_id));
+    }
+
+    REGISTRIES.with(|map| {
+        map.borrow_mut().insert(session.name, data);
+    });
+
+    Ok(())
+}
+
+/// Abandon a session and free its chunks
+#[ic_cdk::update]
+pub fn abort_upload(session_id: u64) -> bool {
+    SESSIONS.with(|sessions| sessions.borrow_mut().remove(&session_id).is_some())
+}
+
 // ─────────────────────────────────────────────────────
 //  IC Canister Endpoints - Enhanced Stable Storage
 // ─────────────────────────────────────────────────────
@@ -207,6 +319,62 @@ pub fn get_stable_data(key: String) -> Result<Vec<u8>, String> {
     })
 }
 
+// ─────────────────────────────────────────────────────
+//  IC Canister Endpoints - Ranged Reads
+// ─────────────────────────────────────────────────────
+
+/// Size of the data saved under a key in stable storage
+#[ic_cdk::query]
+pub fn size(key: String) -> Option<u64> {
+    REGISTRIES.with(|map| map.borrow().get(&key).map(|data| data.len() as u64))
+}
+
+/// Piece `index` of the data saved under a key, in pieces of 2,000,000 bytes
+#[ic_cdk::query]
+pub fn get_chunk(key: String, index: u32) -> Option<Vec<u8>> {
+    REGISTRIES.with(|map| {
+        let data = map.borrow().get(&key)?;
+        data.chunks(READ_CHUNK_SIZE).nth(index as usize).map(|chunk| chunk.to_vec())
+    })
+}
+
+/// Up to `len` bytes (at most 2,000,000) of the data saved under a key, starting at `offset`
+#[ic_cdk::query]
+pub fn read(key: String, offset: u64, len: u64) -> Option<Vec<u8>> {
+    REGISTRIES.with(|map| {
+        let data = map.borrow().get(&key)?;
+        let start = usize::min(offset as usize, data.len());
+        let end = usize::min(start + usize::min(len as usize, READ_CHUNK_SIZE), data.len());
+        Some(data[start..end].to_vec())
+    })
+}
+
+// ─────────────────────────────────────────────────────
+//  IC Canister Endpoints - Integrity Verification
+// ─────────────────────────────────────────────────────
+
+/// SHA-256 of the data saved under a key in stable storage
+#[ic_cdk::query]
+pub fn sha256(key: String) -> Option<Vec<u8>> {
+    REGISTRIES.with(|map| {
+        map.borrow().get(&key).map(|data| Sha256::digest(&data).to_vec())
+    })
+}
+
+/// SHA-256 of every `chunk_size` bytes of the data saved under a key, to locate corrupted chunks
+#[ic_cdk::query]
+pub fn chunk_hashes(key: String, chunk_size: u64) -> Result<Vec<Vec<u8>>, String> {
+    if chunk_size == 0 {
+        return Err("chunk_size must be greater than zero".to_string());
+    }
+
+    REGISTRIES.with(|map| {
+        let data = map.borrow().get(&key)
+            .ok_or_else(|| format!("No data found in stable storage for key: {}", key))?;
+        Ok(data.chunks(chunk_size as usize).map(|chunk| Sha256::digest(chunk).to_vec()).collect())
+    })
+}
+
 // ─────────────────────────────────────────────────────
 //  Helper Functions for Debugging and Monitoring
 // ─────────────────────────────────────────────────────
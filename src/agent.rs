@@ -0,0 +1,353 @@
+//! Native in-process transport for Internet Computer canisters
+//!
+//! This module provides an `ic-agent` based alternative to shelling out to
+//! `dfx canister call`, so chunks can be signed and submitted without paying
+//! the `dfx` process startup cost for every call.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+
+use candid::{idl_hash, IDLArgs, IDLValue, Principal};
+use ic_agent::identity::{BasicIdentity, Secp256k1Identity};
+use ic_agent::{Agent, AgentError, Identity};
+use tokio::runtime::Runtime;
+
+use crate::cancel::CancellationToken;
+use crate::create_error_string;
+use crate::error::UploadError;
+use crate::transport::{CallError, Transport};
+
+/// Replica URL used for the `local` network (and when no network is given)
+pub const LOCAL_NETWORK_URL: &str = "http://127.0.0.1:4943";
+
+/// Boundary node URL used for the `ic` network
+pub const IC_NETWORK_URL: &str = "https://icp-api.io";
+
+/// How often a call in flight checks whether it was cancelled or timed out
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// An `ic-agent` backed client that submits canister calls in-process
+pub struct NativeAgent {
+    /// Tokio runtime driving the asynchronous agent
+    runtime: Runtime,
+    /// The underlying agent
+    agent: Agent,
+    /// URL of the replica or boundary node the agent talks to
+    url: String,
+    /// Canister names resolved from `canister_ids.json`, keyed by name
+    canister_ids: HashMap<String, Principal>,
+    /// How long a call may take before it is abandoned; `None` waits forever
+    timeout: Option<Duration>,
+    /// Token that abandons the calls in flight once cancelled
+    cancellation: Option<CancellationToken>,
+}
+
+impl fmt::Debug for NativeAgent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeAgent")
+            .field("url", &self.url)
+            .field("canister_ids", &self.canister_ids)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl NativeAgent {
+    /// Creates a new agent for the given network.
+    ///
+    /// The root key is fetched from the replica only when it runs on this
+    /// machine; every other URL is verified against the mainnet root key.
+    ///
+    /// # Arguments
+    ///
+    /// * `network` - An optional network type (`local`, `ic`, or a replica URL).
+    /// * `identity_pem` - An optional PEM file used to sign calls. Calls are anonymous when absent.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the agent or an error message.
+    pub fn new(network: Option<&str>, identity_pem: Option<&Path>) -> Result<Self, UploadError> {
+        let url = network_url(network);
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| UploadError::io("Failed to start async runtime", e))?;
+
+        let mut builder = Agent::builder().with_url(url.clone());
+        if let Some(path) = identity_pem {
+            builder = builder.with_boxed_identity(load_identity(path)?);
+        }
+        let agent = builder
+            .build()
+            .map_err(|e| UploadError::Other(create_error_string(&format!("Failed to create agent: {}", e))))?;
+
+        // Only the mainnet root key is baked into the agent; any other network's key
+        // would be trusted as served, so it is only fetched from a local replica
+        if is_local_url(&url) {
+            runtime
+                .block_on(agent.fetch_root_key())
+                .map_err(|e| UploadError::from(agent_error_to_call_error(e)).context(format!("Failed to fetch root key from {}", url)))?;
+        }
+
+        let canister_ids = load_canister_ids(network);
+
+        Ok(Self {
+            runtime,
+            agent,
+            url,
+            canister_ids,
+            timeout: None,
+            cancellation: None,
+        })
+    }
+
+    /// Abandons calls that take longer than `timeout` and fails them with [`CallError::Timeout`]
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Abandons the calls in flight once `token`, if any, is cancelled
+    pub fn with_cancellation(mut self, token: Option<CancellationToken>) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Resolves a canister name or principal text to a `Principal`.
+    ///
+    /// Names are looked up in the `canister_ids.json` file that `dfx` maintains
+    /// for the selected network.
+    pub fn resolve_canister(&self, canister_name: &str) -> Result<Principal, UploadError> {
+        if let Some(principal) = self.canister_ids.get(canister_name) {
+            return Ok(*principal);
+        }
+
+        Principal::from_text(canister_name).map_err(|_| {
+            UploadError::Other(create_error_string(&format!(
+                "Unknown canister '{}': not a principal and not found in canister_ids.json",
+                canister_name
+            )))
+        })
+    }
+}
+
+impl NativeAgent {
+    /// Waits for a call to complete, dropping it once it exceeds the timeout or is cancelled
+    fn block_on_call<F>(&self, call: F) -> Result<Vec<u8>, CallError>
+    where
+        F: Future<Output = Result<Vec<u8>, AgentError>>,
+    {
+        self.runtime.block_on(async {
+            let started = tokio::time::Instant::now();
+            let mut call = std::pin::pin!(call);
+            loop {
+                if let Ok(result) = tokio::time::timeout(POLL_INTERVAL, &mut call).await {
+                    return result.map_err(agent_error_to_call_error);
+                }
+                if self.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                    return Err(CallError::Transport(create_error_string("Call cancelled")));
+                }
+                if let Some(timeout) = self.timeout.filter(|&timeout| started.elapsed() >= timeout) {
+                    return Err(CallError::Timeout(timeout));
+                }
+            }
+        })
+    }
+}
+
+impl Transport for NativeAgent {
+    fn call(&self, canister_name: &str, method: &str, args: &[u8]) -> Result<Vec<u8>, CallError> {
+        let canister_id = self
+            .resolve_canister(canister_name)
+            .map_err(|e| CallError::Transport(e.to_string()))?;
+
+        let mut builder = self.agent.update(&canister_id, method).with_arg(args);
+        // Management canister calls are routed to the subnet of the canister they concern
+        if canister_id == Principal::management_canister() {
+            if let Some(effective_canister_id) = effective_canister_id(args) {
+                builder = builder.with_effective_canister_id(effective_canister_id);
+            }
+        }
+
+        self.block_on_call(builder.call_and_wait())
+    }
+
+    fn query(&self, canister_name: &str, method: &str, args: &[u8]) -> Result<Vec<u8>, CallError> {
+        let canister_id = self
+            .resolve_canister(canister_name)
+            .map_err(|e| CallError::Transport(e.to_string()))?;
+
+        self.block_on_call(self.agent.query(&canister_id, method).with_arg(args).call())
+    }
+
+    fn canister_id(&self, canister_name: &str) -> Result<Principal, UploadError> {
+        self.resolve_canister(canister_name)
+    }
+
+    fn metadata(&self, canister_name: &str, name: &str) -> Result<Vec<u8>, UploadError> {
+        let canister_id = self.resolve_canister(canister_name)?;
+
+        self.block_on_call(self.agent.read_state_canister_metadata(canister_id, name))
+            .map_err(|e| UploadError::from(e).context(format!("Failed to read {} metadata of {}", name, canister_name)))
+    }
+}
+
+/// The canister a management canister call concerns, read from the
+/// `canister_id` or `target_canister` field of its record argument
+fn effective_canister_id(args: &[u8]) -> Option<Principal> {
+    let args = IDLArgs::from_bytes(args).ok()?;
+    let IDLValue::Record(fields) = args.args.first()? else {
+        return None;
+    };
+
+    fields.iter().find_map(|field| match &field.val {
+        IDLValue::Principal(principal)
+            if [idl_hash("canister_id"), idl_hash("target_canister")].contains(&field.id.get_id()) =>
+        {
+            Some(*principal)
+        }
+        _ => None,
+    })
+}
+
+/// Classifies an `ic-agent` error as a rejection or a transport failure
+fn agent_error_to_call_error(error: AgentError) -> CallError {
+    match error {
+        AgentError::CertifiedReject { reject, .. } | AgentError::UncertifiedReject { reject, .. } => {
+            CallError::Rejected {
+                reject_code: Some(reject.reject_code as u8),
+                error_code: reject.error_code,
+                message: reject.reject_message,
+            }
+        }
+        other => CallError::Transport(other.to_string()),
+    }
+}
+
+/// Maps a dfx-style network name to a replica URL
+fn network_url(network: Option<&str>) -> String {
+    match network {
+        None | Some("local") => LOCAL_NETWORK_URL.to_string(),
+        Some("ic") => IC_NETWORK_URL.to_string(),
+        Some(url) => url.to_string(),
+    }
+}
+
+/// Whether a replica URL points at this machine, e.g. `http://127.0.0.1:4943` or `http://localhost:8000`
+fn is_local_url(url: &str) -> bool {
+    let authority = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = authority.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = match authority.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => authority.split(':').next().unwrap_or_default(),
+    };
+
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Loads a signing identity from a PEM file, trying Ed25519 then secp256k1 keys
+fn load_identity(path: &Path) -> Result<Box<dyn Identity>, UploadError> {
+    if let Ok(identity) = BasicIdentity::from_pem_file(path) {
+        return Ok(Box::new(identity));
+    }
+
+    Secp256k1Identity::from_pem_file(path)
+        .map(|identity| Box::new(identity) as Box<dyn Identity>)
+        .map_err(|e| UploadError::Other(create_error_string(&format!("Failed to load identity from {}: {}", path.display(), e))))
+}
+
+/// Reads canister name to principal mappings for a network.
+///
+/// `dfx` keeps local canister IDs in `.dfx/<network>/canister_ids.json` and
+/// mainnet IDs in `canister_ids.json` at the project root.
+fn load_canister_ids(network: Option<&str>) -> HashMap<String, Principal> {
+    let network_name = network.unwrap_or("local");
+    let candidates = [
+        format!(".dfx/{}/canister_ids.json", network_name),
+        "canister_ids.json".to_string(),
+    ];
+
+    let mut ids = HashMap::new();
+    for candidate in candidates.iter().rev() {
+        let Ok(content) = fs::read_to_string(candidate) else {
+            continue;
+        };
+        ids.extend(parse_canister_ids(&content, network_name));
+    }
+    ids
+}
+
+/// Parses the `{ "<name>": { "<network>": "<principal>" } }` layout of `canister_ids.json`
+fn parse_canister_ids(content: &str, network_name: &str) -> HashMap<String, Principal> {
+    let Ok(serde_json::Value::Object(canisters)) = serde_json::from_str(content) else {
+        return HashMap::new();
+    };
+
+    canisters
+        .into_iter()
+        .filter_map(|(name, networks)| {
+            let id = networks.get(network_name)?.as_str()?;
+            Principal::from_text(id).ok().map(|principal| (name, principal))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::{CandidType, Encode};
+
+    #[test]
+    fn test_network_url() {
+        assert_eq!(network_url(None), LOCAL_NETWORK_URL);
+        assert_eq!(network_url(Some("local")), LOCAL_NETWORK_URL);
+        assert_eq!(network_url(Some("ic")), IC_NETWORK_URL);
+        assert_eq!(network_url(Some("http://localhost:8000")), "http://localhost:8000");
+    }
+
+    #[test]
+    fn test_root_key_is_only_fetched_locally() {
+        assert!(is_local_url(&network_url(None)));
+        assert!(is_local_url("http://localhost:8000/api"));
+        assert!(is_local_url("http://[::1]:4943"));
+        assert!(!is_local_url(IC_NETWORK_URL));
+        assert!(!is_local_url("https://ic0.app"));
+        assert!(!is_local_url("https://127.0.0.1.example.com"));
+    }
+
+    #[test]
+    fn test_effective_canister_id() {
+        #[derive(CandidType)]
+        struct ClearChunkStoreArgs {
+            canister_id: Principal,
+        }
+
+        let target = Principal::from_text("bkyz2-fmaaa-aaaaa-qaaaq-cai").unwrap();
+        let args = Encode!(&ClearChunkStoreArgs { canister_id: target }).unwrap();
+
+        assert_eq!(effective_canister_id(&args), Some(target));
+        assert_eq!(effective_canister_id(&Encode!(&"model").unwrap()), None);
+    }
+
+    #[test]
+    fn test_parse_canister_ids() {
+        let content = r#"{
+            "backend": { "local": "bkyz2-fmaaa-aaaaa-qaaaq-cai", "ic": "ryjl3-tyaaa-aaaaa-aaaba-cai" },
+            "frontend": { "ic": "r7inp-6aaaa-aaaaa-aaabq-cai" }
+        }"#;
+
+        let local = parse_canister_ids(content, "local");
+        assert_eq!(local.len(), 1);
+        assert_eq!(local["backend"].to_text(), "bkyz2-fmaaa-aaaaa-qaaaq-cai");
+
+        let ic = parse_canister_ids(content, "ic");
+        assert_eq!(ic.len(), 2);
+        assert_eq!(ic["frontend"].to_text(), "r7inp-6aaaa-aaaaa-aaabq-cai");
+    }
+}
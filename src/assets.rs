@@ -0,0 +1,447 @@
+//! Uploads to the certified asset canister
+//!
+//! The standard IC asset canister does not take anonymous appended chunks. An
+//! upload opens a batch, stores every chunk in it, and commits the batch with
+//! the operations that turn those chunks into assets:
+//!
+//! ```text
+//! create_batch : (record {}) -> (record { batch_id : nat });
+//! create_chunk : (record { batch_id : nat; content : blob }) -> (record { chunk_id : nat });
+//! commit_batch : (record { batch_id : nat; operations : vec BatchOperationKind }) -> ();
+//! ```
+//!
+//! Chunks are stored through the parallel uploader, so concurrency, rate
+//! limiting and retries work as for any other parallel upload.
+
+use std::path::{Path, PathBuf};
+
+use candid::{CandidType, Decode, Deserialize, Encode, Nat};
+use serde_bytes::ByteBuf;
+
+use crate::error::UploadError;
+use crate::parallel::{run_parallel, ParallelUploadConfig};
+use crate::session::sha256_source;
+use crate::source::{ChunkSource, ReaderChunkSource};
+use crate::transport::Transport;
+use crate::{create_error_string, UploadParams};
+
+/// Method that opens a batch
+pub const CREATE_BATCH_METHOD: &str = "create_batch";
+
+/// Method that stores one chunk of a batch
+pub const CREATE_CHUNK_METHOD: &str = "create_chunk";
+
+/// Method that applies the operations of a batch
+pub const COMMIT_BATCH_METHOD: &str = "commit_batch";
+
+/// Content type used when none is given and the extension is unknown
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// A local file and the asset it becomes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetFile {
+    /// Path of the local file
+    pub path: PathBuf,
+    /// Asset key, e.g. `/index.html`
+    pub key: String,
+    /// Content type served with the asset
+    pub content_type: String,
+}
+
+impl AssetFile {
+    /// Creates an asset, guessing its content type from the file extension.
+    pub fn new<P: AsRef<Path>>(path: P, key: &str) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let content_type = content_type_for(&path).to_string();
+
+        Self {
+            path,
+            key: key.to_string(),
+            content_type,
+        }
+    }
+
+    /// Overrides the guessed content type.
+    pub fn with_content_type(mut self, content_type: &str) -> Self {
+        self.content_type = content_type.to_string();
+        self
+    }
+}
+
+/// Summary of a committed batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetUploadSummary {
+    /// The committed batch
+    pub batch_id: Nat,
+    /// Number of assets written
+    pub assets: usize,
+    /// Number of chunks stored
+    pub chunks: usize,
+}
+
+/// Guesses the content type of a file from its extension
+pub fn content_type_for(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js" | "mjs") => "text/javascript",
+        Some("json" | "map") => "application/json",
+        Some("txt") => "text/plain",
+        Some("md") => "text/markdown",
+        Some("xml") => "application/xml",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        _ => DEFAULT_CONTENT_TYPE,
+    }
+}
+
+#[derive(CandidType)]
+struct CreateBatchArguments {}
+
+#[derive(CandidType, Deserialize)]
+struct CreateBatchResponse {
+    batch_id: Nat,
+}
+
+#[derive(CandidType)]
+struct CreateChunkArguments<'a> {
+    batch_id: Nat,
+    content: &'a serde_bytes::Bytes,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CreateChunkResponse {
+    chunk_id: Nat,
+}
+
+#[derive(CandidType)]
+struct DeleteAssetArguments {
+    key: String,
+}
+
+#[derive(CandidType)]
+struct CreateAssetArguments {
+    key: String,
+    content_type: String,
+    max_age: Option<u64>,
+    headers: Option<Vec<(String, String)>>,
+    enable_aliasing: Option<bool>,
+    allow_raw_access: Option<bool>,
+}
+
+#[derive(CandidType)]
+struct SetAssetContentArguments {
+    key: String,
+    content_encoding: String,
+    chunk_ids: Vec<Nat>,
+    sha256: Option<ByteBuf>,
+}
+
+#[derive(CandidType)]
+enum BatchOperationKind {
+    DeleteAsset(DeleteAssetArguments),
+    CreateAsset(CreateAssetArguments),
+    SetAssetContent(SetAssetContentArguments),
+}
+
+#[derive(CandidType)]
+struct CommitBatchArguments {
+    batch_id: Nat,
+    operations: Vec<BatchOperationKind>,
+}
+
+/// Opens a batch on the asset canister.
+pub fn create_batch<T: Transport + ?Sized>(transport: &T, canister_name: &str) -> Result<Nat, UploadError> {
+    let args = Encode!(&CreateBatchArguments {})
+        .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))?;
+
+    let reply = transport
+        .call(canister_name, CREATE_BATCH_METHOD, &args)
+        .map_err(|e| UploadError::from(e).context(format!("{} failed", CREATE_BATCH_METHOD)))?;
+
+    Decode!(&reply, CreateBatchResponse)
+        .map(|response| response.batch_id)
+        .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to decode {} reply: {}", CREATE_BATCH_METHOD, e))))
+}
+
+/// Encodes the `create_chunk` argument for one chunk of a batch
+pub fn encode_create_chunk_args(batch_id: &Nat, data: &[u8]) -> Result<Vec<u8>, UploadError> {
+    Encode!(&CreateChunkArguments {
+        batch_id: batch_id.clone(),
+        content: serde_bytes::Bytes::new(data),
+    })
+    .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))
+}
+
+/// Stores every chunk of `source` in a batch.
+///
+/// # Arguments
+///
+/// * `transport` - The transport used to submit the calls.
+/// * `canister_name` - The name or principal of the asset canister.
+/// * `batch_id` - The batch the chunks belong to.
+/// * `key` - Key of the asset, used in progress output.
+/// * `source` - Source the chunks are read from.
+/// * `config` - Concurrency, rate, retry, dispatch order, reply policy, maximum duration,
+///   cancellation and observer settings; its journal, session, argument template and
+///   prepare, finalize and verify calls are ignored.
+///
+/// # Returns
+///
+/// A `Result` containing the canister's chunk IDs in source order or an error message.
+pub fn create_chunks<T: Transport + ?Sized, S: ChunkSource + ?Sized>(
+    transport: &T,
+    canister_name: &str,
+    batch_id: &Nat,
+    key: &str,
+    source: &S,
+    config: &ParallelUploadConfig,
+) -> Result<Vec<Nat>, UploadError> {
+    if source.chunk_count() == 0 {
+        return Ok(Vec::new());
+    }
+
+    let params = UploadParams {
+        name: key,
+        canister_name,
+        canister_method: CREATE_CHUNK_METHOD,
+        network: None,
+    };
+    let encode = |_: u32, data: &[u8]| encode_create_chunk_args(batch_id, data);
+    let chunk_ids: Vec<u32> = (0..source.chunk_count() as u32).collect();
+
+    let mut run = run_parallel(transport, &params, source, chunk_ids, &config.for_chunk_calls(), &encode);
+
+    if let Some(error) = run.take_failure(|failed| format!("Failed to store chunks {:?} of {}", failed, key)) {
+        return Err(error);
+    }
+
+    run.replies.sort_by_key(|(chunk_id, _)| *chunk_id);
+    run.replies
+        .iter()
+        .map(|(_, reply)| {
+            Decode!(reply, CreateChunkResponse)
+                .map(|response| response.chunk_id)
+                .map_err(|e| {
+                    UploadError::Candid(create_error_string(&format!("Failed to decode {} reply: {}", CREATE_CHUNK_METHOD, e)))
+                })
+        })
+        .collect()
+}
+
+/// Uploads files to an asset canister in a single batch.
+///
+/// Every asset is deleted and recreated, so an existing asset with a
+/// different content type is replaced rather than rejected.
+///
+/// # Arguments
+///
+/// * `transport` - The transport used to submit the calls.
+/// * `canister_name` - The name or principal of the asset canister.
+/// * `files` - The files to upload and the assets they become.
+/// * `chunk_size` - Size of each chunk in bytes.
+/// * `config` - Chunk call settings, used as in [`create_chunks`].
+///
+/// # Returns
+///
+/// A `Result` containing a summary of the committed batch or an error message.
+pub fn upload_assets<T: Transport + ?Sized>(
+    transport: &T,
+    canister_name: &str,
+    files: &[AssetFile],
+    chunk_size: usize,
+    config: &ParallelUploadConfig,
+) -> Result<AssetUploadSummary, UploadError> {
+    if files.is_empty() {
+        return Err(UploadError::Other(create_error_string("No assets to upload")));
+    }
+
+    let batch_id = create_batch(transport, canister_name)?;
+
+    let mut operations = Vec::new();
+    let mut chunks = 0;
+
+    for file in files {
+        let source = ReaderChunkSource::open(&file.path, chunk_size, 0)
+            .map_err(|e| UploadError::io(format!("Failed to open {}", file.path.display()), e))?;
+        let sha256 =
+            sha256_source(&source).map_err(|e| UploadError::io(format!("Failed to hash {}", file.path.display()), e))?;
+
+        let chunk_ids = create_chunks(transport, canister_name, &batch_id, &file.key, &source, config)?;
+        chunks += chunk_ids.len();
+
+        operations.push(BatchOperationKind::DeleteAsset(DeleteAssetArguments {
+            key: file.key.clone(),
+        }));
+        operations.push(BatchOperationKind::CreateAsset(CreateAssetArguments {
+            key: file.key.clone(),
+            content_type: file.content_type.clone(),
+            max_age: None,
+            headers: None,
+            enable_aliasing: None,
+            allow_raw_access: None,
+        }));
+        operations.push(BatchOperationKind::SetAssetContent(SetAssetContentArguments {
+            key: file.key.clone(),
+            content_encoding: "identity".to_string(),
+            chunk_ids,
+            sha256: Some(ByteBuf::from(sha256.to_vec())),
+        }));
+    }
+
+    let args = Encode!(&CommitBatchArguments {
+        batch_id: batch_id.clone(),
+        operations,
+    })
+    .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))?;
+
+    transport
+        .call(canister_name, COMMIT_BATCH_METHOD, &args)
+        .map_err(|e| UploadError::from(e).context(format!("{} failed", COMMIT_BATCH_METHOD)))?;
+
+    Ok(AssetUploadSummary {
+        batch_id,
+        assets: files.len(),
+        chunks,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{fast_config, CallError, MockTransport, EMPTY_CANDID_REPLY};
+    use candid::{IDLArgs, IDLValue};
+    use std::fs;
+    use std::time::Duration;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Answers the batch protocol like an asset canister, numbering chunks from 100
+    fn asset_canister() -> MockTransport {
+        let next_chunk = AtomicU64::new(100);
+        MockTransport::with_responder(move |call| {
+            let reply = match call.method.as_str() {
+                CREATE_BATCH_METHOD => Encode!(&CreateBatchResponse { batch_id: Nat::from(7u64) }),
+                CREATE_CHUNK_METHOD => Encode!(&CreateChunkResponse {
+                    chunk_id: Nat::from(next_chunk.fetch_add(1, Ordering::SeqCst)),
+                }),
+                COMMIT_BATCH_METHOD => return Ok(EMPTY_CANDID_REPLY.to_vec()),
+                _ => panic!("unexpected method {}", call.method),
+            };
+            Ok(reply.unwrap())
+        })
+    }
+
+    /// Chunks are created one at a time, so the canister numbers them in file order
+    fn one_at_a_time() -> ParallelUploadConfig {
+        ParallelUploadConfig {
+            max_concurrent: 1,
+            ..fast_config()
+        }
+    }
+
+    #[test]
+    fn test_content_type_for() {
+        assert_eq!(content_type_for(Path::new("dist/index.HTML")), "text/html");
+        assert_eq!(content_type_for(Path::new("app.js")), "text/javascript");
+        assert_eq!(content_type_for(Path::new("model.bin")), DEFAULT_CONTENT_TYPE);
+        assert_eq!(content_type_for(Path::new("LICENSE")), DEFAULT_CONTENT_TYPE);
+    }
+
+    #[test]
+    fn test_create_chunk_args_round_trip() {
+        #[derive(CandidType, Deserialize)]
+        struct Received {
+            batch_id: Nat,
+            content: ByteBuf,
+        }
+
+        let args = encode_create_chunk_args(&Nat::from(3u64), &[1, 2, 3]).unwrap();
+        let received = Decode!(&args, Received).unwrap();
+
+        assert_eq!(received.batch_id, Nat::from(3u64));
+        assert_eq!(received.content.into_vec(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_create_batch_keeps_call_error_kind() {
+        let transport = MockTransport::with_responder(|_| Err(CallError::Timeout(Duration::from_secs(5))));
+
+        let error = create_batch(&transport, "frontend").unwrap_err();
+        assert!(error.to_string().starts_with("create_batch failed"));
+        assert!(matches!(error.root_cause(), UploadError::Timeout(_)));
+    }
+
+    #[test]
+    fn test_upload_assets_leaves_upload_journal_alone() {
+        use crate::journal::UploadJournal;
+        use crate::transport::test_params;
+        use std::sync::{Arc, Mutex};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.js");
+        fs::write(&path, b"let x = 1;").unwrap();
+        let journal = Arc::new(Mutex::new(UploadJournal::open_or_create(dir.path(), &path, &test_params(), 5, 0).unwrap()));
+        let config = ParallelUploadConfig {
+            journal: Some(journal.clone()),
+            ..one_at_a_time()
+        };
+
+        upload_assets(&asset_canister(), "frontend", &[AssetFile::new(&path, "/app.js")], 5, &config).unwrap();
+        assert!(journal.lock().unwrap().acknowledged.is_empty());
+    }
+
+    #[test]
+    fn test_upload_assets_commits_chunks_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.html");
+        fs::write(&path, b"<html></html>").unwrap();
+        let transport = asset_canister();
+
+        let files = vec![AssetFile::new(&path, "/index.html")];
+        let summary = upload_assets(&transport, "frontend", &files, 5, &one_at_a_time()).unwrap();
+
+        assert_eq!(summary.batch_id, Nat::from(7u64));
+        assert_eq!((summary.assets, summary.chunks), (1, 3));
+
+        let calls = transport.calls();
+        assert_eq!(calls.first().unwrap().method, CREATE_BATCH_METHOD);
+        assert_eq!(calls.iter().filter(|call| call.method == CREATE_CHUNK_METHOD).count(), 3);
+
+        let commit = calls.last().unwrap();
+        assert_eq!(commit.method, COMMIT_BATCH_METHOD);
+        let text = IDLArgs::from_bytes(&commit.args).unwrap().to_string();
+        assert!(text.contains("\"/index.html\""));
+        assert!(text.contains("\"text/html\""));
+        assert!(text.contains("\"identity\""));
+
+        // The canister's chunk IDs are committed in file order
+        let chunk_ids = match &IDLArgs::from_bytes(&commit.args).unwrap().args[0] {
+            IDLValue::Record(fields) => fields
+                .iter()
+                .find_map(|field| match &field.val {
+                    IDLValue::Vec(operations) => operations.last().cloned(),
+                    _ => None,
+                })
+                .unwrap()
+                .to_string(),
+            other => panic!("unexpected commit argument {}", other),
+        };
+        assert!(chunk_ids.contains("vec { 100 : nat; 101 : nat; 102 : nat }"), "{}", chunk_ids);
+    }
+}
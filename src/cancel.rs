@@ -0,0 +1,89 @@
+//! Cooperative cancellation of uploads
+//!
+//! A [`CancellationToken`] is shared between whatever decides to stop an
+//! upload, such as a Ctrl-C handler, and the upload loops, which check it
+//! before starting each chunk. Chunks already in flight finish, or are killed
+//! by a transport watching a token of its own, and are recorded in the journal
+//! as usual, so an interrupted upload resumes exactly where it stopped.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A flag that stops an upload once set; clones share the flag
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Creates a token that is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels every upload holding a clone of this token.
+    ///
+    /// Only stores to an atomic, so it is safe to call from a signal handler.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the token was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Why an upload stopped before starting every chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The upload ran for its maximum duration
+    TimeLimit,
+    /// The upload's cancellation token was cancelled
+    Cancelled,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::TimeLimit => write!(f, "the upload reached its maximum duration"),
+            StopReason::Cancelled => write!(f, "the upload was cancelled"),
+        }
+    }
+}
+
+/// Whether an upload that began at `started` must stop starting chunks, and why
+pub(crate) fn stop_reason(
+    cancellation: Option<&CancellationToken>,
+    max_duration: Option<Duration>,
+    started: Instant,
+) -> Option<StopReason> {
+    if cancellation.is_some_and(CancellationToken::is_cancelled) {
+        Some(StopReason::Cancelled)
+    } else if max_duration.is_some_and(|max_duration| started.elapsed() >= max_duration) {
+        Some(StopReason::TimeLimit)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_cancellation() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        let started = Instant::now();
+
+        assert_eq!(stop_reason(Some(&clone), None, started), None);
+        assert_eq!(stop_reason(None, Some(Duration::ZERO), started), Some(StopReason::TimeLimit));
+
+        token.cancel();
+        assert!(clone.is_cancelled());
+        assert_eq!(stop_reason(Some(&clone), Some(Duration::ZERO), started), Some(StopReason::Cancelled));
+    }
+}
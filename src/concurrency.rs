@@ -0,0 +1,167 @@
+//! Adaptive concurrency for parallel uploads
+//!
+//! A fixed number of chunk calls in flight is either too cautious for an idle
+//! subnet or too aggressive for a congested one. [`AdaptiveConcurrency`]
+//! adjusts the limit AIMD-style, like TCP congestion control: every round of
+//! calls that completes at the usual latency adds a slot, while a failed
+//! attempt or a chunk that took much longer than the fastest ones seen halves
+//! it. The limit always stays within the configured bounds.
+
+use std::time::Duration;
+
+/// A chunk slower than the baseline latency times this factor signals congestion
+pub const LATENCY_TOLERANCE: f64 = 2.0;
+
+/// Factor the baseline latency grows by with every sample, so it follows a
+/// subnet that became slower for good instead of backing off forever
+const BASELINE_DRIFT: f64 = 1.02;
+
+/// AIMD controller for the number of chunk calls in flight
+#[derive(Debug, Clone)]
+pub struct AdaptiveConcurrency {
+    /// Lowest limit
+    min: usize,
+    /// Highest limit
+    max: usize,
+    /// Current limit
+    limit: usize,
+    /// Calls completed at the usual latency since the limit last changed
+    progress: usize,
+    /// Latency of an uncongested chunk call, from the fastest calls seen
+    baseline: Option<Duration>,
+    /// Outcomes to observe before the limit may be cut again, so that the
+    /// calls already in flight when congestion started cut it only once
+    cooldown: usize,
+}
+
+impl AdaptiveConcurrency {
+    /// Creates a controller starting at `min` that never leaves `min..=max`.
+    ///
+    /// A `min` of 0 is treated as 1, and a `max` below `min` as `min`, so
+    /// `min == max` gives a fixed concurrency.
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+
+        Self {
+            min,
+            max,
+            limit: min,
+            progress: 0,
+            baseline: None,
+            cooldown: 0,
+        }
+    }
+
+    /// The number of chunk calls that may be in flight
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Records a chunk call that succeeded after `latency`.
+    pub fn record_success(&mut self, latency: Duration) {
+        let baseline = match self.baseline {
+            Some(baseline) => baseline.mul_f64(BASELINE_DRIFT).min(latency),
+            None => latency,
+        };
+        self.baseline = Some(baseline);
+
+        if latency.as_secs_f64() > baseline.as_secs_f64() * LATENCY_TOLERANCE {
+            self.decrease();
+        } else if self.cooldown > 0 {
+            self.cooldown -= 1;
+        } else {
+            // One more slot once a full round of calls completed
+            self.progress += 1;
+            if self.progress >= self.limit {
+                self.limit = (self.limit + 1).min(self.max);
+                self.progress = 0;
+            }
+        }
+    }
+
+    /// Records a chunk call attempt that failed.
+    pub fn record_failure(&mut self) {
+        self.decrease();
+    }
+
+    /// Halves the limit unless it was cut within the current round of calls
+    fn decrease(&mut self) {
+        if self.cooldown > 0 {
+            self.cooldown -= 1;
+            return;
+        }
+
+        self.limit = (self.limit / 2).max(self.min);
+        self.progress = 0;
+        self.cooldown = self.limit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn test_increases_by_one_per_round() {
+        let mut concurrency = AdaptiveConcurrency::new(1, 8);
+        assert_eq!(concurrency.limit(), 1);
+
+        concurrency.record_success(FAST);
+        assert_eq!(concurrency.limit(), 2);
+
+        // Two more calls complete a round at a limit of 2
+        concurrency.record_success(FAST);
+        assert_eq!(concurrency.limit(), 2);
+        concurrency.record_success(FAST);
+        assert_eq!(concurrency.limit(), 3);
+
+        for _ in 0..100 {
+            concurrency.record_success(FAST);
+        }
+        assert_eq!(concurrency.limit(), 8);
+    }
+
+    #[test]
+    fn test_backs_off_once_per_round() {
+        let mut concurrency = AdaptiveConcurrency::new(2, 16);
+        for _ in 0..200 {
+            concurrency.record_success(FAST);
+        }
+        assert_eq!(concurrency.limit(), 16);
+
+        concurrency.record_failure();
+        assert_eq!(concurrency.limit(), 8);
+
+        // Calls that were already in flight do not cut the limit again
+        for _ in 0..8 {
+            concurrency.record_failure();
+        }
+        assert_eq!(concurrency.limit(), 8);
+
+        // A slow chunk is a congestion signal like a failure
+        concurrency.record_success(FAST * 10);
+        assert_eq!(concurrency.limit(), 4);
+
+        for _ in 0..20 {
+            concurrency.record_failure();
+        }
+        assert_eq!(concurrency.limit(), 2);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let mut fixed = AdaptiveConcurrency::new(3, 3);
+        fixed.record_failure();
+        assert_eq!(fixed.limit(), 3);
+        for _ in 0..10 {
+            fixed.record_success(FAST);
+        }
+        assert_eq!(fixed.limit(), 3);
+
+        assert_eq!(AdaptiveConcurrency::new(0, 0).limit(), 1);
+        assert_eq!(AdaptiveConcurrency::new(4, 2).limit(), 4);
+    }
+}
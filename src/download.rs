@@ -0,0 +1,561 @@
+//! Chunked downloads from a canister
+//!
+//! Replies are limited to roughly 2 MB, so a file stored in a canister cannot
+//! be fetched with a single call. The downloader asks for the file size, reads
+//! it back in pieces through a ranged read query, either by chunk index
+//! (`get_chunk(key, index)`) or by byte range (`read(key, offset, len)`), and
+//! reassembles the pieces in a local file with several reads in flight.
+
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use candid::{Encode, IDLValue};
+
+use crate::cancel::CancellationToken;
+use crate::error::UploadError;
+use crate::hooks::CanisterCall;
+use crate::journal::sha256_reader;
+use crate::progress::{notify, ProgressEvent, SharedObserver};
+use crate::reply::reply_value;
+use crate::resume::value_to_u64;
+use crate::retry::{AttemptError, RetryPolicy};
+use crate::source::ReaderChunkSource;
+use crate::transport::Transport;
+use crate::verify::verify_upload;
+use crate::{create_error_string, UploadParams, MAX_CANISTER_HTTP_PAYLOAD_SIZE};
+
+/// How the read method addresses the piece to return
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadMode {
+    /// `(key : text, index : nat32) -> (blob)`, pieces of `chunk_size` bytes
+    ChunkIndex,
+    /// `(key : text, offset : nat64, len : nat64) -> (blob)`
+    ByteRange,
+}
+
+/// Configuration for download operations
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// Query method returning one piece of the file
+    pub read_method: String,
+    /// How `read_method` addresses pieces
+    pub read_mode: ReadMode,
+    /// Query method returning the file size, `(key : text) -> (nat64)`
+    pub size_method: String,
+    /// Size of each piece in bytes; must match the canister's chunking in `ChunkIndex` mode
+    pub chunk_size: usize,
+    /// Maximum number of reads in flight
+    pub max_concurrent: usize,
+    /// Maximum attempts per piece
+    pub max_retries: usize,
+    /// Backoff between attempts of a piece
+    pub retry_policy: RetryPolicy,
+    /// Receives the start, chunk and verify events of the download, see [`ProgressEvent`]
+    pub observer: Option<SharedObserver>,
+    /// Optional hash query the downloaded file is verified against
+    pub verify: Option<CanisterCall>,
+    /// Stops starting pieces once cancelled; the download then fails with [`UploadError::Cancelled`]
+    pub cancellation: Option<CancellationToken>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            read_method: "get_chunk".to_string(),
+            read_mode: ReadMode::ChunkIndex,
+            size_method: "size".to_string(),
+            chunk_size: MAX_CANISTER_HTTP_PAYLOAD_SIZE,
+            max_concurrent: 4,
+            max_retries: 3,
+            retry_policy: RetryPolicy::default(),
+            observer: None,
+            verify: None,
+            cancellation: None,
+        }
+    }
+}
+
+/// Summary of a completed download
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadSummary {
+    /// Number of bytes written
+    pub bytes: u64,
+    /// Number of pieces read
+    pub chunks: usize,
+    /// SHA-256 of the downloaded file
+    pub sha256: [u8; 32],
+}
+
+/// Downloads a file from a canister.
+///
+/// The file is written to `<output>.part` and only renamed to `output` once
+/// every piece arrived with the expected size (and the hash matched, if a
+/// verify query is configured); it is removed when the download fails.
+///
+/// # Arguments
+///
+/// * `transport` - The transport used to submit the queries.
+/// * `canister_name` - The name or principal of the canister.
+/// * `key` - The key the file is stored under.
+/// * `output` - Where to write the file.
+/// * `config` - Download configuration.
+///
+/// # Returns
+///
+/// A `Result` containing a summary of the download or an error message.
+pub fn download_to_file<T: Transport + ?Sized>(
+    transport: &T,
+    canister_name: &str,
+    key: &str,
+    output: &Path,
+    config: &DownloadConfig,
+) -> Result<DownloadSummary, UploadError> {
+    if config.chunk_size == 0 {
+        return Err(UploadError::Other(create_error_string("Chunk size must be greater than zero")));
+    }
+
+    let total_size = query_size(transport, canister_name, key, &config.size_method)?;
+    let chunk_count = total_size.div_ceil(config.chunk_size as u64) as usize;
+    notify(&config.observer, || ProgressEvent::Started {
+        chunks: chunk_count,
+        workers: config.max_concurrent.max(1).min(chunk_count),
+    });
+
+    let part_path = part_path(output);
+    let result = download_part(transport, canister_name, key, &part_path, total_size, chunk_count, config).and_then(|sha256| {
+        fs::rename(&part_path, output)
+            .map(|()| sha256)
+            .map_err(|e| UploadError::io(format!("Failed to move download to {}", output.display()), e))
+    });
+    if result.is_err() {
+        // Best effort; a failed download is started over rather than resumed
+        let _ = fs::remove_file(&part_path);
+    }
+    let sha256 = result?;
+
+    Ok(DownloadSummary {
+        bytes: total_size,
+        chunks: chunk_count,
+        sha256,
+    })
+}
+
+/// Reads every piece into `part_path` and checks the result, returning its SHA-256
+fn download_part<T: Transport + ?Sized>(
+    transport: &T,
+    canister_name: &str,
+    key: &str,
+    part_path: &Path,
+    total_size: u64,
+    chunk_count: usize,
+    config: &DownloadConfig,
+) -> Result<[u8; 32], UploadError> {
+    let file = File::create(part_path)
+        .and_then(|file| file.set_len(total_size).map(|()| file))
+        .map_err(|e| UploadError::io(format!("Failed to create {}", part_path.display()), e))?;
+    let file = Mutex::new(file);
+
+    let next_chunk = AtomicUsize::new(0);
+    let failures = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..config.max_concurrent.max(1) {
+            scope.spawn(|| loop {
+                if is_cancelled(config) {
+                    break;
+                }
+                let index = next_chunk.fetch_add(1, Ordering::SeqCst);
+                if index >= chunk_count {
+                    break;
+                }
+
+                let offset = index as u64 * config.chunk_size as u64;
+                let len = u64::min(config.chunk_size as u64, total_size - offset) as usize;
+
+                let result = read_chunk_with_retry(transport, canister_name, key, index, offset, len, config)
+                    .and_then(|data| {
+                        let mut file = file.lock().unwrap();
+                        file.seek(SeekFrom::Start(offset))
+                            .and_then(|_| file.write_all(&data))
+                            .map_err(|e| AttemptError::fatal(UploadError::io(format!("Failed to write chunk {}", index), e)))
+                    });
+
+                if let Err(e) = result {
+                    failures.lock().unwrap().push((index, e.error));
+                }
+            });
+        }
+    });
+
+    // Pieces killed by a second Ctrl-C fail like any other, but the download was cancelled
+    let mut failures = failures.into_inner().unwrap();
+    let done = usize::min(next_chunk.into_inner(), chunk_count) - failures.len();
+    if is_cancelled(config) && done < chunk_count {
+        return Err(UploadError::Cancelled.context(format!("Download stopped after {} of {} chunks", done, chunk_count)));
+    }
+    if !failures.is_empty() {
+        failures.sort_by_key(|(index, _)| *index);
+        let indices: Vec<usize> = failures.iter().map(|(index, _)| *index).collect();
+        let (_, first) = failures.swap_remove(0);
+        return Err(first.context(format!("Failed to download chunks {:?}", indices)));
+    }
+
+    file.into_inner()
+        .unwrap()
+        .sync_all()
+        .map_err(|e| UploadError::io(format!("Failed to flush {}", part_path.display()), e))?;
+
+    if let Some(hash_query) = &config.verify {
+        let source = ReaderChunkSource::open(part_path, config.chunk_size, 0)
+            .map_err(|e| UploadError::io(format!("Failed to read {}", part_path.display()), e))?;
+        let params = UploadParams {
+            name: key,
+            canister_name,
+            canister_method: &config.read_method,
+            network: None,
+        };
+        let sha256 = verify_upload(transport, &params, &source, hash_query)?;
+        notify(&config.observer, || ProgressEvent::Verified {
+            sha256,
+            method: hash_query.method.clone(),
+        });
+    }
+
+    File::open(part_path)
+        .and_then(sha256_reader)
+        .map_err(|e| UploadError::io(format!("Failed to hash {}", part_path.display()), e))
+}
+
+/// Where a download is written until it completes
+fn part_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// Whether the download was cancelled
+fn is_cancelled(config: &DownloadConfig) -> bool {
+    config.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled)
+}
+
+/// Asks the canister for the size of the file stored under `key`
+fn query_size<T: Transport + ?Sized>(transport: &T, canister_name: &str, key: &str, size_method: &str) -> Result<u64, UploadError> {
+    let args = Encode!(&key)
+        .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))?;
+    let reply = transport
+        .query(canister_name, size_method, &args)
+        .map_err(|e| UploadError::from(e).context(format!("Query {} failed", size_method)))?;
+
+    let value = reply_value(&reply, size_method)?;
+    value_to_u64(&value)
+        .ok_or_else(|| UploadError::Candid(create_error_string(&format!("Unexpected {} reply: {}", size_method, value))))
+}
+
+/// Reads one piece, retrying reads that failed in a way another attempt could fix
+fn read_chunk_with_retry<T: Transport + ?Sized>(
+    transport: &T,
+    canister_name: &str,
+    key: &str,
+    index: usize,
+    offset: u64,
+    len: usize,
+    config: &DownloadConfig,
+) -> Result<Vec<u8>, AttemptError> {
+    let chunk_id = index as u32;
+    let mut attempts = 0;
+    let first_attempt = Instant::now();
+    notify(&config.observer, || ProgressEvent::ChunkStarted { chunk_id, size: len });
+
+    loop {
+        attempts += 1;
+        let attempt_start = Instant::now();
+
+        match read_chunk(transport, canister_name, key, index, offset, len, config) {
+            Ok(data) => {
+                notify(&config.observer, || ProgressEvent::ChunkSucceeded {
+                    chunk_id,
+                    size: data.len(),
+                    attempts,
+                    latency: attempt_start.elapsed(),
+                });
+                return Ok(data);
+            }
+            Err(e) => {
+                let delay = config.retry_policy.delay(attempts as u32);
+                if e.is_fatal()
+                    || is_cancelled(config)
+                    || attempts >= config.max_retries
+                    || !config.retry_policy.allows_retry(first_attempt.elapsed(), delay)
+                {
+                    let class = e.class;
+                    let error = e.into_chunk_error(chunk_id, attempts);
+                    notify(&config.observer, || ProgressEvent::ChunkFailed {
+                        chunk_id,
+                        error: error.clone(),
+                    });
+                    return Err(AttemptError { class, error });
+                }
+
+                notify(&config.observer, || ProgressEvent::ChunkRetry {
+                    chunk_id,
+                    attempt: attempts,
+                    max_attempts: config.max_retries,
+                    delay,
+                    error: e.error,
+                });
+
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+/// Reads one piece and checks its length.
+///
+/// Only the query itself can fail in a way another attempt could fix, as
+/// classified by [`crate::retry::classify`]; the same query gets the same reply.
+fn read_chunk<T: Transport + ?Sized>(
+    transport: &T,
+    canister_name: &str,
+    key: &str,
+    index: usize,
+    offset: u64,
+    len: usize,
+    config: &DownloadConfig,
+) -> Result<Vec<u8>, AttemptError> {
+    let args = match config.read_mode {
+        ReadMode::ChunkIndex => Encode!(&key, &(index as u32)),
+        ReadMode::ByteRange => Encode!(&key, &offset, &(len as u64)),
+    }
+    .map_err(|e| AttemptError::fatal(UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e)))))?;
+
+    let reply = transport
+        .query(canister_name, &config.read_method, &args)
+        .map_err(AttemptError::from_call)?;
+
+    let data = match reply_value(&reply, &config.read_method).map_err(AttemptError::fatal)? {
+        IDLValue::Blob(bytes) => bytes,
+        IDLValue::Vec(values) => values
+            .into_iter()
+            .map(|value| match value {
+                IDLValue::Nat8(byte) => Ok(byte),
+                other => Err(UploadError::Candid(create_error_string(&format!(
+                    "Unexpected value in chunk {}: {}",
+                    index, other
+                )))),
+            })
+            .collect::<Result<_, _>>()
+            .map_err(AttemptError::fatal)?,
+        other => {
+            return Err(AttemptError::fatal(UploadError::Candid(create_error_string(&format!(
+                "Unexpected {} reply: {}",
+                config.read_method, other
+            )))))
+        }
+    };
+
+    if data.len() != len {
+        return Err(AttemptError::fatal(UploadError::Verification(create_error_string(&format!(
+            "Chunk {} has {} bytes, expected {}",
+            index,
+            data.len(),
+            len
+        )))));
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{CallError, MockTransport};
+    use candid::Decode;
+    use serde_bytes::ByteBuf;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Serves `data` through `size`, `get_chunk` and `read` like the demo backend
+    fn serving(data: Vec<u8>, chunk_size: usize) -> MockTransport {
+        MockTransport::with_responder(move |call| {
+            let reply = match call.method.as_str() {
+                "size" => Encode!(&Some(data.len() as u64)),
+                "get_chunk" => {
+                    let (_, index) = Decode!(&call.args, String, u32).unwrap();
+                    let chunk = data.chunks(chunk_size).nth(index as usize).map(|c| ByteBuf::from(c.to_vec()));
+                    Encode!(&chunk)
+                }
+                "read" => {
+                    let (_, offset, len) = Decode!(&call.args, String, u64, u64).unwrap();
+                    let end = usize::min((offset + len) as usize, data.len());
+                    Encode!(&Some(ByteBuf::from(data[offset as usize..end].to_vec())))
+                }
+                _ => panic!("unexpected method {}", call.method),
+            };
+            Ok(reply.unwrap())
+        })
+    }
+
+    #[test]
+    fn test_download_by_chunk_index() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let transport = serving(data.clone(), 300);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("model.bin");
+        let downloaded = Arc::new(Mutex::new(Vec::new()));
+        let config = DownloadConfig {
+            chunk_size: 300,
+            observer: Some(SharedObserver::new({
+                let downloaded = downloaded.clone();
+                move |event: &ProgressEvent| {
+                    if let ProgressEvent::ChunkSucceeded { chunk_id, size, .. } = event {
+                        downloaded.lock().unwrap().push((*chunk_id, *size));
+                    }
+                }
+            })),
+            ..Default::default()
+        };
+
+        let summary = download_to_file(&transport, "backend", "model", &output, &config).unwrap();
+
+        assert_eq!(summary.bytes, 1000);
+        assert_eq!(summary.chunks, 4);
+        let mut downloaded = downloaded.lock().unwrap().clone();
+        downloaded.sort();
+        assert_eq!(downloaded, vec![(0, 300), (1, 300), (2, 300), (3, 100)]);
+        assert_eq!(fs::read(&output).unwrap(), data);
+        assert!(!part_path(&output).exists());
+        assert!(transport.calls().iter().all(|call| call.query));
+    }
+
+    #[test]
+    fn test_download_by_byte_range() {
+        let data: Vec<u8> = (0..=255).collect();
+        let transport = serving(data.clone(), 100);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("model.bin");
+        let config = DownloadConfig {
+            read_method: "read".to_string(),
+            read_mode: ReadMode::ByteRange,
+            chunk_size: 100,
+            max_concurrent: 2,
+            ..Default::default()
+        };
+
+        download_to_file(&transport, "backend", "model", &output, &config).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), data);
+    }
+
+    #[test]
+    fn test_download_retries_only_transient_failures() {
+        let data = vec![3u8; 200];
+        let serve = serving(data.clone(), 100);
+        let failed_once = AtomicBool::new(false);
+        let transport = MockTransport::with_responder(move |call| {
+            if call.method == "get_chunk" && !failed_once.swap(true, Ordering::SeqCst) {
+                return Err(CallError::Transport("connection reset".to_string()));
+            }
+            serve.query(&call.canister_name, &call.method, &call.args)
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("model.bin");
+        let config = DownloadConfig {
+            chunk_size: 100,
+            max_concurrent: 1,
+            retry_policy: RetryPolicy::fixed(Duration::ZERO),
+            ..Default::default()
+        };
+
+        download_to_file(&transport, "backend", "model", &output, &config).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), data);
+
+        // An error reply is the same on every attempt
+        let transport = MockTransport::with_responder(|call| match call.method.as_str() {
+            "size" => Ok(Encode!(&200u64).unwrap()),
+            _ => Ok(Encode!(&Err::<ByteBuf, String>("no such key".to_string())).unwrap()),
+        });
+        let error = download_to_file(&transport, "backend", "model", &output, &config).unwrap_err();
+        assert!(matches!(error.root_cause(), UploadError::Reply(_)));
+        // The size query and a single read of each of the two pieces
+        assert_eq!(transport.calls().len(), 3);
+    }
+
+    #[test]
+    fn test_download_rejects_short_chunks() {
+        // The canister chunks by 50 bytes while the client expects 100
+        let transport = serving(vec![1u8; 120], 50);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("model.bin");
+        let config = DownloadConfig {
+            chunk_size: 100,
+            max_retries: 1,
+            ..Default::default()
+        };
+
+        let error = download_to_file(&transport, "backend", "model", &output, &config).unwrap_err();
+        assert!(error.to_string().contains("Failed to download chunks [0, 1]"));
+        assert!(matches!(error.root_cause(), UploadError::Verification(_)));
+        assert!(!output.exists());
+        assert!(!part_path(&output).exists());
+    }
+
+    #[test]
+    fn test_download_stops_when_cancelled() {
+        let transport = serving(vec![5u8; 400], 100);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("model.bin");
+        let token = CancellationToken::new();
+        let config = DownloadConfig {
+            chunk_size: 100,
+            max_concurrent: 1,
+            observer: Some(SharedObserver::new({
+                let token = token.clone();
+                move |event: &ProgressEvent| {
+                    if let ProgressEvent::ChunkSucceeded { chunk_id: 1, .. } = event {
+                        token.cancel();
+                    }
+                }
+            })),
+            cancellation: Some(token),
+            ..Default::default()
+        };
+
+        let error = download_to_file(&transport, "backend", "model", &output, &config).unwrap_err();
+        assert!(error.to_string().contains("Download stopped after 2 of 4 chunks"));
+        assert!(matches!(error.root_cause(), UploadError::Cancelled));
+        assert!(!output.exists());
+        assert!(!part_path(&output).exists());
+        // The size query and the two pieces read before the stop
+        assert_eq!(transport.calls().len(), 3);
+
+        // Reads killed after every piece was handed out still count as a cancelled download
+        let token = CancellationToken::new();
+        let transport = MockTransport::with_responder({
+            let serve = serving(vec![5u8; 200], 100);
+            let token = token.clone();
+            move |call| {
+                if call.method == "get_chunk" && call.args.ends_with(&1u32.to_le_bytes()) {
+                    token.cancel();
+                    return Err(CallError::Transport("Call cancelled".to_string()));
+                }
+                serve.query(&call.canister_name, &call.method, &call.args)
+            }
+        });
+        let config = DownloadConfig {
+            chunk_size: 100,
+            max_concurrent: 1,
+            cancellation: Some(token),
+            ..Default::default()
+        };
+
+        let error = download_to_file(&transport, "backend", "model", &output, &config).unwrap_err();
+        assert_eq!(error.to_string(), "Download stopped after 1 of 2 chunks: The upload was cancelled");
+        assert!(!part_path(&output).exists());
+    }
+}
@@ -0,0 +1,197 @@
+//! Errors of the upload pipelines
+//!
+//! [`UploadError`] tells apart where an upload failed: on the local machine,
+//! when starting `dfx`, at the replica or canister, in the Candid encoding, or
+//! because the canister's reply or the stored data was not what was expected.
+//! The failure of a single chunk is wrapped in [`UploadError::Chunk`] together
+//! with the chunk's index and the number of attempts made, and the failure of
+//! a step such as a `create_batch` call in [`UploadError::Context`] with a
+//! description of the step, so the kind of the underlying failure is kept.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cancel::StopReason;
+use crate::transport::CallError;
+
+/// Why an upload, or a call or chunk within it, failed
+#[derive(Debug, Clone)]
+pub enum UploadError {
+    /// Reading the file or another local I/O operation failed
+    Io {
+        /// What was being done
+        context: String,
+        /// The underlying I/O error
+        source: Arc<io::Error>,
+    },
+    /// The `dfx` process could not be started
+    Spawn(String),
+    /// The replica or the canister rejected a call
+    Rejected {
+        /// Numeric reject code from the interface spec, when known
+        reject_code: Option<u8>,
+        /// Replica error code such as `IC0503`, when known
+        error_code: Option<String>,
+        /// The rejection message
+        message: String,
+    },
+    /// A call got no reply within the call timeout
+    Timeout(Duration),
+    /// A call could not be delivered, e.g. because the connection failed
+    Transport(String),
+    /// A Candid argument could not be encoded or a reply could not be decoded
+    Candid(String),
+    /// The canister replied, but the reply reports a failure, see [`crate::reply::ReplyPolicy`]
+    Reply(String),
+    /// A chunk failed
+    Chunk {
+        /// Index of the chunk in the file (0-based)
+        index: u32,
+        /// Attempts made before giving up
+        attempts: usize,
+        /// Whether the failure was retryable, i.e. the chunk ran out of attempts
+        retryable: bool,
+        /// The failure of the last attempt
+        source: Box<UploadError>,
+    },
+    /// A step of an operation failed, e.g. a call made before or after the chunks
+    Context {
+        /// What was being done
+        context: String,
+        /// The failure of the step
+        source: Box<UploadError>,
+    },
+    /// A chunk was not sent because the upload stopped before it
+    NotAttempted(String),
+    /// The stored data does not match the local file, or could not be checked
+    Verification(String),
+    /// The upload reached its maximum duration before every chunk was sent
+    TimeLimit,
+    /// The upload was cancelled
+    Cancelled,
+    /// Any other failure, e.g. invalid input or a failed prepare or finalize call
+    Other(String),
+}
+
+impl UploadError {
+    /// An I/O error with a description of what was being done
+    pub fn io(context: impl Into<String>, source: io::Error) -> Self {
+        UploadError::Io {
+            context: context.into(),
+            source: Arc::new(source),
+        }
+    }
+
+    /// Wraps this error in an [`UploadError::Context`] describing what was being done
+    pub fn context(self, context: impl Into<String>) -> Self {
+        UploadError::Context {
+            context: context.into(),
+            source: Box::new(self),
+        }
+    }
+
+    /// The failure behind any [`UploadError::Chunk`] or [`UploadError::Context`] wrapping
+    pub fn root_cause(&self) -> &UploadError {
+        match self {
+            UploadError::Chunk { source, .. } | UploadError::Context { source, .. } => source.root_cause(),
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::Io { context, source } => write!(f, "{}: {}", context, source),
+            UploadError::Rejected { error_code: Some(code), message, .. } => write!(f, "{}: {}", code, message),
+            UploadError::Rejected { message, .. } => write!(f, "{}", message),
+            UploadError::Timeout(timeout) => write!(f, "No reply within {:.1}s", timeout.as_secs_f64()),
+            UploadError::Chunk { index, attempts, retryable: true, source } => {
+                let plural = if *attempts == 1 { "" } else { "s" };
+                write!(f, "Chunk {} failed after {} attempt{}: {}", index, attempts, plural, source)
+            }
+            UploadError::Chunk { index, source, .. } => {
+                write!(f, "Chunk {} cannot be uploaded, not retrying: {}", index, source)
+            }
+            UploadError::Context { context, source } => write!(f, "{}: {}", context, source),
+            UploadError::NotAttempted(reason) => write!(f, "Not attempted: {}", reason),
+            UploadError::TimeLimit => write!(f, "The upload reached its maximum duration"),
+            UploadError::Cancelled => write!(f, "The upload was cancelled"),
+            UploadError::Spawn(message)
+            | UploadError::Transport(message)
+            | UploadError::Candid(message)
+            | UploadError::Reply(message)
+            | UploadError::Verification(message)
+            | UploadError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for UploadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UploadError::Io { source, .. } => Some(source.as_ref()),
+            UploadError::Chunk { source, .. } | UploadError::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<CallError> for UploadError {
+    fn from(error: CallError) -> Self {
+        match error {
+            CallError::Spawn(message) => UploadError::Spawn(message),
+            CallError::Transport(message) => UploadError::Transport(message),
+            CallError::Rejected { reject_code, error_code, message } => UploadError::Rejected {
+                reject_code,
+                error_code,
+                message,
+            },
+            CallError::Timeout(timeout) => UploadError::Timeout(timeout),
+        }
+    }
+}
+
+impl From<StopReason> for UploadError {
+    fn from(reason: StopReason) -> Self {
+        match reason {
+            StopReason::TimeLimit => UploadError::TimeLimit,
+            StopReason::Cancelled => UploadError::Cancelled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_errors_keep_their_cause() {
+        let rejected = UploadError::from(CallError::Rejected {
+            reject_code: Some(5),
+            error_code: Some("IC0503".to_string()),
+            message: "Canister trapped".to_string(),
+        });
+        let error = UploadError::Chunk {
+            index: 3,
+            attempts: 2,
+            retryable: true,
+            source: Box::new(rejected),
+        };
+
+        assert_eq!(error.to_string(), "Chunk 3 failed after 2 attempts: IC0503: Canister trapped");
+        assert!(matches!(error.root_cause(), UploadError::Rejected { reject_code: Some(5), .. }));
+        assert!(error.source().is_some());
+
+        let call = UploadError::from(CallError::Timeout(Duration::from_secs(5))).context("create_batch failed");
+        assert_eq!(call.to_string(), "create_batch failed: No reply within 5.0s");
+        assert!(matches!(call.root_cause(), UploadError::Timeout(_)));
+
+        let io = UploadError::io("Failed to read chunk 0", io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert!(io.to_string().starts_with("Failed to read chunk 0: "));
+        assert_eq!(io.source().unwrap().to_string(), io::Error::from(io::ErrorKind::UnexpectedEof).to_string());
+    }
+}
@@ -0,0 +1,126 @@
+//! Prepare and finalize calls around an upload
+//!
+//! A [`CanisterCall`] is made once per upload, either before the first chunk
+//! (e.g. `clear_parallel_chunks`) or after every chunk succeeded (e.g.
+//! `save_parallel_to_stable`), so uploads no longer need a manual
+//! `dfx canister call` afterwards.
+
+use candid::IDLArgs;
+
+use crate::error::UploadError;
+use crate::progress::{notify, ProgressEvent, SharedObserver};
+use crate::reply::reply_error;
+use crate::transport::{Transport, EMPTY_CANDID_REPLY};
+use crate::{create_error_string, UploadParams};
+
+/// An update call made around an upload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanisterCall {
+    /// The canister method to call
+    pub method: String,
+    /// The call argument in binary Candid (DIDL) format
+    pub args: Vec<u8>,
+}
+
+impl CanisterCall {
+    /// Creates a call with an already-encoded Candid argument.
+    pub fn new(method: &str, args: Vec<u8>) -> Self {
+        Self {
+            method: method.to_string(),
+            args,
+        }
+    }
+
+    /// Creates a call without arguments, `()`.
+    pub fn without_args(method: &str) -> Self {
+        Self::new(method, EMPTY_CANDID_REPLY.to_vec())
+    }
+
+    /// Creates a call from a textual Candid argument such as `("key")`.
+    pub fn from_text(method: &str, candid_args: &str) -> Result<Self, UploadError> {
+        let args = candid_parser::parse_idl_args(candid_args)
+            .map_err(|e| UploadError::Candid(create_error_string(&format!("Invalid Candid argument for {}: {}", method, e))))?
+            .to_bytes()
+            .map_err(|e| {
+                UploadError::Candid(create_error_string(&format!("Failed to encode Candid argument for {}: {}", method, e)))
+            })?;
+
+        Ok(Self::new(method, args))
+    }
+
+    /// Makes the call and returns its reply rendered as Candid text.
+    ///
+    /// A reply of the form `variant { Err = ... }` or `variant { Error = ... }` is reported as an error.
+    pub fn invoke<T: Transport + ?Sized>(&self, transport: &T, canister_name: &str) -> Result<String, UploadError> {
+        let reply = transport
+            .call(canister_name, &self.method, &self.args)
+            .map_err(|e| UploadError::from(e).context(format!("{} failed", self.method)))?;
+
+        if let Some(error) = reply_error(&reply) {
+            return Err(UploadError::Reply(create_error_string(&format!("{} returned an error: {}", self.method, error))));
+        }
+
+        Ok(render_reply(&reply))
+    }
+}
+
+/// Renders a binary Candid reply as text, falling back to hex for undecodable replies
+pub fn render_reply(reply: &[u8]) -> String {
+    match IDLArgs::from_bytes(reply) {
+        Ok(args) => args.to_string(),
+        Err(_) => hex::encode(reply),
+    }
+}
+
+/// Runs a prepare or finalize call and reports its reply to `observer`.
+pub(crate) fn run_hook<T: Transport + ?Sized>(
+    transport: &T,
+    params: &UploadParams,
+    call: &CanisterCall,
+    stage: &'static str,
+    observer: &Option<SharedObserver>,
+) -> Result<(), UploadError> {
+    let reply = call
+        .invoke(transport, params.canister_name)
+        .map_err(|e| e.context(format!("{} call failed", stage)))?;
+
+    notify(observer, || ProgressEvent::HookSucceeded {
+        stage,
+        method: call.method.clone(),
+        reply,
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+    use candid::Encode;
+
+    #[test]
+    fn test_call_from_text() {
+        let call = CanisterCall::from_text("save_parallel_to_stable", "(\"model\")").unwrap();
+        assert_eq!(call.args, Encode!(&"model").unwrap());
+
+        assert!(CanisterCall::from_text("save_parallel_to_stable", "(\"model\"").is_err());
+    }
+
+    #[test]
+    fn test_invoke_renders_reply() {
+        let ok: Result<u64, String> = Ok(42);
+        let transport = MockTransport::with_responder(move |_| Ok(Encode!(&ok).unwrap()));
+
+        let reply = CanisterCall::without_args("save").invoke(&transport, "backend").unwrap();
+        assert!(reply.contains("42"));
+    }
+
+    #[test]
+    fn test_invoke_reports_err_variant() {
+        let err: Result<u64, String> = Err("No parallel chunks to save".to_string());
+        let transport = MockTransport::with_responder(move |_| Ok(Encode!(&err).unwrap()));
+
+        let error = CanisterCall::without_args("save").invoke(&transport, "backend").unwrap_err();
+        assert!(matches!(&error, UploadError::Reply(message) if message.contains("No parallel chunks to save")));
+    }
+}
@@ -0,0 +1,385 @@
+//! Chunked Wasm installation through the management canister
+//!
+//! Wasm modules larger than the ingress limit cannot be installed with a
+//! single `install_code` call. Instead, the module is stored in the target
+//! canister's chunk store piece by piece and installed from there:
+//!
+//! ```text
+//! upload_chunk : (record { canister_id : principal; chunk : blob }) -> (record { hash : blob });
+//! stored_chunks : (record { canister_id : principal }) -> (vec record { hash : blob });
+//! install_chunked_code : (record { mode; target_canister; chunk_hashes_list; wasm_module_hash; arg; ... }) -> ();
+//! clear_chunk_store : (record { canister_id : principal }) -> ();
+//! ```
+//!
+//! The hash returned for every chunk is checked against the local chunk, and
+//! chunks the store already holds are not uploaded again, so an interrupted
+//! install resumes where it stopped.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use serde_bytes::ByteBuf;
+
+use crate::error::UploadError;
+use crate::parallel::{run_parallel, ParallelUploadConfig};
+use crate::source::ReaderChunkSource;
+use crate::transport::{Transport, EMPTY_CANDID_REPLY};
+use crate::verify::LocalHashes;
+use crate::{create_error_string, UploadParams};
+
+/// Principal of the management canister
+pub const MANAGEMENT_CANISTER: &str = "aaaaa-aa";
+
+/// Largest chunk the chunk store accepts, 1 MiB
+pub const WASM_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How the module is installed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallMode {
+    /// Install into an empty canister
+    Install,
+    /// Replace the code and discard the canister state
+    Reinstall,
+    /// Replace the code and keep the canister state
+    Upgrade,
+}
+
+/// Configuration for chunked installs
+#[derive(Debug, Clone)]
+pub struct InstallConfig {
+    /// How the module is installed
+    pub mode: InstallMode,
+    /// Init or upgrade argument in binary Candid (DIDL) format
+    pub arg: Vec<u8>,
+    /// Size of each chunk in bytes, at most [`WASM_CHUNK_SIZE`]
+    pub chunk_size: usize,
+    /// Clear the chunk store before uploading instead of reusing the chunks it holds
+    pub fresh: bool,
+    /// Leave the chunks in the store after installing
+    pub keep_chunks: bool,
+}
+
+impl Default for InstallConfig {
+    fn default() -> Self {
+        Self {
+            mode: InstallMode::Install,
+            arg: EMPTY_CANDID_REPLY.to_vec(),
+            chunk_size: WASM_CHUNK_SIZE,
+            fresh: false,
+            keep_chunks: false,
+        }
+    }
+}
+
+/// Summary of a completed install
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallSummary {
+    /// Number of chunks the module consists of
+    pub chunks: usize,
+    /// Number of chunks uploaded by this run; the rest were already stored
+    pub uploaded: usize,
+    /// SHA-256 of the installed module
+    pub module_hash: [u8; 32],
+}
+
+#[derive(CandidType, Deserialize)]
+struct ChunkStoreArgs {
+    canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+struct UploadChunkArgs {
+    canister_id: Principal,
+    chunk: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ChunkHash {
+    hash: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize)]
+enum CanisterInstallMode {
+    #[serde(rename = "install")]
+    Install,
+    #[serde(rename = "reinstall")]
+    Reinstall,
+    #[serde(rename = "upgrade")]
+    Upgrade(Option<UpgradeFlags>),
+}
+
+#[derive(CandidType, Deserialize)]
+struct UpgradeFlags {
+    skip_pre_upgrade: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct InstallChunkedCodeArgs {
+    mode: CanisterInstallMode,
+    target_canister: Principal,
+    store_canister: Option<Principal>,
+    chunk_hashes_list: Vec<ChunkHash>,
+    wasm_module_hash: ByteBuf,
+    arg: ByteBuf,
+    sender_canister_version: Option<u64>,
+}
+
+/// Calls a management canister method with a `record { canister_id }` argument
+fn chunk_store_call<T: Transport + ?Sized>(transport: &T, method: &str, canister_id: Principal) -> Result<Vec<u8>, UploadError> {
+    let args = Encode!(&ChunkStoreArgs { canister_id })
+        .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))?;
+
+    transport
+        .call(MANAGEMENT_CANISTER, method, &args)
+        .map_err(|e| UploadError::from(e).context(format!("{} failed", method)))
+}
+
+/// Lists the hashes of the chunks in a canister's chunk store.
+pub fn stored_chunks<T: Transport + ?Sized>(transport: &T, canister_id: Principal) -> Result<Vec<Vec<u8>>, UploadError> {
+    let reply = chunk_store_call(transport, "stored_chunks", canister_id)?;
+
+    Decode!(&reply, Vec<ChunkHash>)
+        .map(|hashes| hashes.into_iter().map(|hash| hash.hash.into_vec()).collect())
+        .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to decode stored_chunks reply: {}", e))))
+}
+
+/// Removes every chunk from a canister's chunk store.
+pub fn clear_chunk_store<T: Transport + ?Sized>(transport: &T, canister_id: Principal) -> Result<(), UploadError> {
+    chunk_store_call(transport, "clear_chunk_store", canister_id).map(|_| ())
+}
+
+/// Encodes the `upload_chunk` argument for one chunk of a module
+pub fn encode_upload_chunk_args(canister_id: Principal, chunk: &[u8]) -> Result<Vec<u8>, UploadError> {
+    Encode!(&UploadChunkArgs {
+        canister_id,
+        chunk: ByteBuf::from(chunk.to_vec()),
+    })
+    .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))
+}
+
+/// Installs a Wasm module through the target canister's chunk store.
+///
+/// # Arguments
+///
+/// * `transport` - The transport used to submit the management canister calls.
+/// * `canister_id` - The canister the module is installed in.
+/// * `wasm_path` - Path to the Wasm module (optionally gzipped).
+/// * `install` - Install mode, argument and chunking.
+/// * `config` - Settings of the chunk uploads, used as in [`crate::assets::create_chunks`].
+///
+/// # Returns
+///
+/// A `Result` containing a summary of the install or an error message.
+pub fn install_chunked_wasm<T: Transport + ?Sized>(
+    transport: &T,
+    canister_id: Principal,
+    wasm_path: &Path,
+    install: &InstallConfig,
+    config: &ParallelUploadConfig,
+) -> Result<InstallSummary, UploadError> {
+    if install.chunk_size == 0 || install.chunk_size > WASM_CHUNK_SIZE {
+        return Err(UploadError::Other(create_error_string(&format!(
+            "Chunk size must be between 1 and {} bytes",
+            WASM_CHUNK_SIZE
+        ))));
+    }
+
+    let source = ReaderChunkSource::open(wasm_path, install.chunk_size, 0)
+        .map_err(|e| UploadError::io(format!("Failed to open {}", wasm_path.display()), e))?;
+    let hashes =
+        LocalHashes::compute(&source).map_err(|e| UploadError::io(format!("Failed to hash {}", wasm_path.display()), e))?;
+
+    if install.fresh {
+        clear_chunk_store(transport, canister_id)?;
+    }
+
+    // Chunks already in the store are identified by their hash
+    let stored: HashSet<Vec<u8>> = if install.fresh {
+        HashSet::new()
+    } else {
+        stored_chunks(transport, canister_id)?.into_iter().collect()
+    };
+    let missing: Vec<u32> = (0..hashes.chunks.len() as u32)
+        .filter(|&index| !stored.contains(hashes.chunks[index as usize].as_slice()))
+        .collect();
+
+    let uploaded = missing.len();
+    if !missing.is_empty() {
+        let name = wasm_path.display().to_string();
+        let params = UploadParams {
+            name: &name,
+            canister_name: MANAGEMENT_CANISTER,
+            canister_method: "upload_chunk",
+            network: None,
+        };
+        let encode = |_: u32, chunk: &[u8]| encode_upload_chunk_args(canister_id, chunk);
+
+        let mut run = run_parallel(transport, &params, &source, missing, &config.for_chunk_calls(), &encode);
+
+        if let Some(error) = run.take_failure(|failed| format!("Failed to upload chunks {:?}; run again to resume", failed)) {
+            return Err(error);
+        }
+
+        for (index, reply) in &run.replies {
+            let hash = Decode!(reply, ChunkHash)
+                .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to decode upload_chunk reply: {}", e))))?;
+            if hash.hash.as_slice() != hashes.chunks[*index as usize].as_slice() {
+                return Err(UploadError::Verification(create_error_string(&format!(
+                    "Chunk {} was stored with hash {}, expected {}",
+                    index,
+                    hex::encode(&hash.hash),
+                    hex::encode(hashes.chunks[*index as usize])
+                ))));
+            }
+        }
+    }
+
+    let args = Encode!(&InstallChunkedCodeArgs {
+        mode: match install.mode {
+            InstallMode::Install => CanisterInstallMode::Install,
+            InstallMode::Reinstall => CanisterInstallMode::Reinstall,
+            InstallMode::Upgrade => CanisterInstallMode::Upgrade(None),
+        },
+        target_canister: canister_id,
+        store_canister: None,
+        chunk_hashes_list: hashes
+            .chunks
+            .iter()
+            .map(|hash| ChunkHash { hash: ByteBuf::from(hash.to_vec()) })
+            .collect(),
+        wasm_module_hash: ByteBuf::from(hashes.file.to_vec()),
+        arg: ByteBuf::from(install.arg.clone()),
+        sender_canister_version: None,
+    })
+    .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))?;
+
+    transport
+        .call(MANAGEMENT_CANISTER, "install_chunked_code", &args)
+        .map_err(|e| UploadError::from(e).context("install_chunked_code failed"))?;
+
+    if !install.keep_chunks {
+        clear_chunk_store(transport, canister_id)?;
+    }
+
+    Ok(InstallSummary {
+        chunks: hashes.chunks.len(),
+        uploaded,
+        module_hash: hashes.file,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{fast_config, MockTransport};
+    use sha2::{Digest, Sha256};
+    use std::fs;
+    use std::sync::Mutex;
+
+    fn target() -> Principal {
+        Principal::from_text("bkyz2-fmaaa-aaaaa-qaaaq-cai").unwrap()
+    }
+
+    /// Acts as the management canister with a chunk store holding `stored`
+    fn management_canister(stored: Vec<Vec<u8>>) -> MockTransport {
+        let store = Mutex::new(stored);
+        MockTransport::with_responder(move |call| {
+            let reply = match call.method.as_str() {
+                "stored_chunks" => {
+                    let hashes: Vec<ChunkHash> = store
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .map(|chunk| ChunkHash { hash: ByteBuf::from(Sha256::digest(chunk).to_vec()) })
+                        .collect();
+                    Encode!(&hashes)
+                }
+                "upload_chunk" => {
+                    let args = Decode!(&call.args, UploadChunkArgs).unwrap();
+                    store.lock().unwrap().push(args.chunk.to_vec());
+                    Encode!(&ChunkHash { hash: ByteBuf::from(Sha256::digest(&args.chunk).to_vec()) })
+                }
+                "clear_chunk_store" => {
+                    store.lock().unwrap().clear();
+                    Ok(EMPTY_CANDID_REPLY.to_vec())
+                }
+                "install_chunked_code" => Ok(EMPTY_CANDID_REPLY.to_vec()),
+                _ => panic!("unexpected method {}", call.method),
+            };
+            Ok(reply.unwrap())
+        })
+    }
+
+    fn write_wasm(dir: &tempfile::TempDir) -> std::path::PathBuf {
+        let path = dir.path().join("backend.wasm");
+        fs::write(&path, (0..250u32).map(|i| i as u8).collect::<Vec<u8>>()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_install_uploads_chunks_and_installs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_wasm(&dir);
+        let transport = management_canister(Vec::new());
+        let install = InstallConfig {
+            mode: InstallMode::Upgrade,
+            chunk_size: 100,
+            ..Default::default()
+        };
+
+        let summary = install_chunked_wasm(&transport, target(), &path, &install, &fast_config()).unwrap();
+        assert_eq!((summary.chunks, summary.uploaded), (3, 3));
+
+        let calls = transport.calls();
+        assert!(calls.iter().all(|call| call.canister_name == MANAGEMENT_CANISTER));
+        assert_eq!(calls.last().unwrap().method, "clear_chunk_store");
+
+        let install_call = calls.iter().find(|call| call.method == "install_chunked_code").unwrap();
+        let args = Decode!(&install_call.args, InstallChunkedCodeArgs).unwrap();
+        assert!(matches!(args.mode, CanisterInstallMode::Upgrade(None)));
+        assert_eq!(args.target_canister, target());
+        assert_eq!(args.wasm_module_hash.as_slice(), summary.module_hash.as_slice());
+
+        // The hash list follows the module order, whatever order the chunks were uploaded in
+        let data = fs::read(&path).unwrap();
+        let expected: Vec<Vec<u8>> = data.chunks(100).map(|chunk| Sha256::digest(chunk).to_vec()).collect();
+        let listed: Vec<Vec<u8>> = args.chunk_hashes_list.into_iter().map(|hash| hash.hash.into_vec()).collect();
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn test_install_resumes_from_stored_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_wasm(&dir);
+        let data = fs::read(&path).unwrap();
+        let transport = management_canister(vec![data[..100].to_vec(), data[200..].to_vec()]);
+        let install = InstallConfig {
+            chunk_size: 100,
+            keep_chunks: true,
+            ..Default::default()
+        };
+
+        let summary = install_chunked_wasm(&transport, target(), &path, &install, &fast_config()).unwrap();
+        assert_eq!((summary.chunks, summary.uploaded), (3, 1));
+
+        let uploads: Vec<_> = transport.calls().into_iter().filter(|call| call.method == "upload_chunk").collect();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(Decode!(&uploads[0].args, UploadChunkArgs).unwrap().chunk.as_slice(), &data[100..200]);
+        assert!(transport.calls().iter().all(|call| call.method != "clear_chunk_store"));
+    }
+
+    #[test]
+    fn test_install_rejects_oversized_chunks() {
+        let transport = MockTransport::new();
+        let install = InstallConfig {
+            chunk_size: WASM_CHUNK_SIZE + 1,
+            ..Default::default()
+        };
+
+        let error = install_chunked_wasm(&transport, target(), Path::new("backend.wasm"), &install, &fast_config())
+            .unwrap_err();
+        assert!(matches!(&error, UploadError::Other(message) if message.contains("Chunk size")));
+        assert!(transport.calls().is_empty());
+    }
+}
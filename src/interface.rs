@@ -0,0 +1,213 @@
+//! Pre-flight checks against a canister's Candid interface
+//!
+//! When the chunk method's signature does not match what the uploader sends,
+//! every chunk fails and is retried the same way. Checking the interface
+//! first turns that into one precise error: the interface is read from the
+//! canister's `candid:service` metadata or from a local `.did` file, and a
+//! sample argument is decoded against the method's parameter types, exactly
+//! as the canister would.
+
+use std::fs;
+use std::path::Path;
+
+use candid::types::{FuncMode, Type};
+use candid::{IDLArgs, TypeEnv};
+use candid_parser::utils::CandidSource;
+
+use crate::create_error_string;
+use crate::error::UploadError;
+use crate::transport::Transport;
+
+/// Name of the canister metadata section holding the service's Candid interface
+pub const CANDID_SERVICE_METADATA: &str = "candid:service";
+
+/// How a method is expected to be called
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MethodKind {
+    /// Called as an update, so its state changes are kept
+    Update,
+    /// Called as a query
+    Query,
+}
+
+/// A canister's Candid service interface
+#[derive(Debug, Clone)]
+pub struct ServiceInterface {
+    /// Types referenced by the service
+    env: TypeEnv,
+    /// The service type
+    actor: Type,
+}
+
+impl ServiceInterface {
+    /// Parses a service description in Candid text, such as a `.did` file.
+    pub fn parse(did: &str) -> Result<Self, UploadError> {
+        let (env, actor) = CandidSource::Text(did)
+            .load()
+            .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to parse Candid interface: {}", e))))?;
+        let actor = actor.ok_or_else(|| UploadError::Candid(create_error_string("Candid interface has no service")))?;
+
+        Ok(Self { env, actor })
+    }
+
+    /// Reads a service description from a `.did` file.
+    pub fn from_file(path: &Path) -> Result<Self, UploadError> {
+        let did = fs::read_to_string(path).map_err(|e| UploadError::io(format!("Failed to read {}", path.display()), e))?;
+        Self::parse(&did)
+    }
+
+    /// Reads the service description a canister publishes in its `candid:service` metadata.
+    pub fn fetch<T: Transport + ?Sized>(transport: &T, canister_name: &str) -> Result<Self, UploadError> {
+        let did = transport.metadata(canister_name, CANDID_SERVICE_METADATA)?;
+        let did = String::from_utf8(did).map_err(|e| {
+            UploadError::Candid(create_error_string(&format!("{} metadata is not text: {}", CANDID_SERVICE_METADATA, e)))
+        })?;
+        Self::parse(&did)
+    }
+
+    /// Checks that a method exists, can be called as `kind`, and accepts `args`.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The method name.
+    /// * `kind` - How the uploader calls the method.
+    /// * `args` - A sample of the binary Candid argument the uploader sends.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the call would be accepted, or an error naming the mismatch.
+    pub fn check_method(&self, method: &str, kind: MethodKind, args: &[u8]) -> Result<(), UploadError> {
+        let func = self.env.get_method(&self.actor, method).map_err(|_| {
+            let methods = self.method_names();
+            UploadError::Other(if methods.is_empty() {
+                create_error_string(&format!("Canister has no method {}; its interface declares no methods", method))
+            } else {
+                create_error_string(&format!("Canister has no method {}; it provides {}", method, methods.join(", ")))
+            })
+        })?;
+
+        let is_query = func.modes.iter().any(|mode| matches!(mode, FuncMode::Query | FuncMode::CompositeQuery));
+        match kind {
+            MethodKind::Update if is_query => {
+                return Err(UploadError::Other(create_error_string(&format!(
+                    "{} is a query: its state changes would be discarded, use an update method",
+                    method
+                ))));
+            }
+            MethodKind::Query if !is_query => {
+                return Err(UploadError::Other(create_error_string(&format!("{} is not a query method", method))));
+            }
+            _ => {}
+        }
+
+        IDLArgs::from_bytes_with_types(args, &self.env, &func.args)
+            .map(|_| ())
+            .map_err(|e| {
+                UploadError::Candid(create_error_string(&format!(
+                    "{} expects {} but would be sent {}: {}",
+                    method,
+                    format_types(&func.args),
+                    describe_args(args),
+                    e
+                )))
+            })
+    }
+
+    /// Names of the methods the service provides
+    fn method_names(&self) -> Vec<String> {
+        self.env
+            .as_service(&self.actor)
+            .map(|methods| methods.iter().map(|(name, _)| name.clone()).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Renders argument types as a Candid tuple, e.g. `(nat32, blob)`
+fn format_types(types: &[Type]) -> String {
+    let types: Vec<String> = types.iter().map(|ty| ty.to_string()).collect();
+    format!("({})", types.join(", "))
+}
+
+/// Renders the types of an encoded argument
+fn describe_args(args: &[u8]) -> String {
+    match IDLArgs::from_bytes(args) {
+        Ok(args) => format_types(&args.args.iter().map(|value| value.value_ty()).collect::<Vec<_>>()),
+        Err(_) => "an undecodable argument".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode_blob_args;
+    use crate::parallel::encode_chunk_with_id_args;
+
+    const DID: &str = r#"
+        type ChunkRecord = record { key : text; data : blob };
+        service : {
+            append_chunk : (blob) -> ();
+            append_parallel_chunk : (nat32, blob) -> ();
+            store : (ChunkRecord) -> ();
+            buffer_size : () -> (nat64) query;
+        }
+    "#;
+
+    #[test]
+    fn test_check_matching_methods() {
+        let service = ServiceInterface::parse(DID).unwrap();
+
+        service.check_method("append_chunk", MethodKind::Update, &encode_blob_args(&[1]).unwrap()).unwrap();
+        service
+            .check_method("append_parallel_chunk", MethodKind::Update, &encode_chunk_with_id_args(0, &[1]).unwrap())
+            .unwrap();
+        service.check_method("buffer_size", MethodKind::Query, crate::transport::EMPTY_CANDID_REPLY).unwrap();
+    }
+
+    #[test]
+    fn test_check_reports_argument_mismatch() {
+        let service = ServiceInterface::parse(DID).unwrap();
+
+        // The parallel mode's (nat32, blob) sent to a (blob) method
+        let error = service
+            .check_method("append_chunk", MethodKind::Update, &encode_chunk_with_id_args(0, &[1]).unwrap())
+            .unwrap_err();
+        assert!(error.to_string().contains("append_chunk expects (blob) but would be sent (nat32, blob)"), "{}", error);
+
+        let error = service.check_method("store", MethodKind::Update, &encode_blob_args(&[1]).unwrap()).unwrap_err();
+        assert!(error.to_string().contains("store expects (ChunkRecord)"), "{}", error);
+    }
+
+    #[test]
+    fn test_check_reports_missing_method_and_wrong_kind() {
+        let service = ServiceInterface::parse(DID).unwrap();
+        let args = encode_blob_args(&[1]).unwrap();
+
+        let error = service.check_method("upload", MethodKind::Update, &args).unwrap_err();
+        assert!(error.to_string().contains("no method upload"));
+        assert!(error.to_string().contains("append_chunk"));
+
+        let error = service.check_method("buffer_size", MethodKind::Update, &args).unwrap_err();
+        assert!(error.to_string().contains("buffer_size is a query"));
+    }
+
+    #[test]
+    fn test_demo_canister_interface_accepts_the_uploads() {
+        use crate::session::{encode_begin_upload_args, encode_put_chunk_args, BEGIN_UPLOAD_METHOD, COMMIT_UPLOAD_METHOD};
+        use candid::Encode;
+
+        let did = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("demo/ic-uploader-demo/src/ic-uploader-demo-backend/ic-uploader-demo-backend.did");
+        let service = ServiceInterface::from_file(&did).unwrap();
+
+        service.check_method("append_chunk", MethodKind::Update, &encode_blob_args(&[1]).unwrap()).unwrap();
+        service
+            .check_method("append_parallel_chunk", MethodKind::Update, &encode_chunk_with_id_args(0, &[1]).unwrap())
+            .unwrap();
+        service
+            .check_method(BEGIN_UPLOAD_METHOD, MethodKind::Update, &encode_begin_upload_args("sample", 1, 1, &[0; 32]).unwrap())
+            .unwrap();
+        service.check_method("put_chunk", MethodKind::Update, &encode_put_chunk_args(0, 0, &[1]).unwrap()).unwrap();
+        service.check_method(COMMIT_UPLOAD_METHOD, MethodKind::Update, &Encode!(&0u64).unwrap()).unwrap();
+        service.check_method("parallel_chunk_ids", MethodKind::Query, crate::transport::EMPTY_CANDID_REPLY).unwrap();
+    }
+}
@@ -0,0 +1,425 @@
+//! Persistent upload journal for automatic resume
+//!
+//! The journal records which chunks of a file the canister has acknowledged,
+//! together with the identity of the file and the upload target, so re-running
+//! the same upload picks up where the previous run stopped. The file identity,
+//! target and session are kept in a JSON snapshot; every acknowledged chunk is
+//! appended as one line to a log next to it, so recording a chunk costs the
+//! same however many chunks came before. Opening the journal folds the log
+//! into the snapshot.
+
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::UploadError;
+use crate::session::SessionId;
+use crate::{create_error_string, UploadParams};
+
+/// Default directory journals are stored in, relative to the working directory
+pub const DEFAULT_JOURNAL_DIR: &str = ".ic-upload";
+
+/// Identity of a local file, used to detect changes between runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileIdentity {
+    /// File size in bytes
+    pub size: u64,
+    /// Modification time in milliseconds since the Unix epoch
+    pub modified_ms: u64,
+    /// Hex-encoded SHA-256 of the file contents
+    pub sha256: String,
+}
+
+impl FileIdentity {
+    /// Reads the size and modification time of a file and hashes its contents.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let metadata = fs::metadata(&path)?;
+        let modified_ms = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        Ok(Self {
+            size: metadata.len(),
+            modified_ms,
+            sha256: hex::encode(sha256_reader(File::open(&path)?)?),
+        })
+    }
+
+    /// Whether the file contents are the same, ignoring the modification time
+    pub fn same_contents(&self, other: &FileIdentity) -> bool {
+        self.size == other.size && self.sha256 == other.sha256
+    }
+}
+
+/// Computes the SHA-256 of everything a reader yields, without loading it into memory.
+pub fn sha256_reader<R: Read>(mut reader: R) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Journal of an upload of one file to one canister method
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadJournal {
+    /// Identity of the file being uploaded
+    pub file: FileIdentity,
+    /// Size of each chunk in bytes
+    pub chunk_size: usize,
+    /// Byte offset in the file where chunking starts
+    pub start_offset: u64,
+    /// Target canister name
+    pub canister_name: String,
+    /// Target canister method
+    pub canister_method: String,
+    /// Optional network specification
+    pub network: Option<String>,
+    /// IDs of the chunks the canister has acknowledged
+    pub acknowledged: BTreeSet<u32>,
+    /// Upload session the acknowledged chunks were sent to, if any
+    #[serde(default)]
+    pub session_id: Option<SessionId>,
+    /// Whether the session was committed, so a resumed upload only finalizes and verifies
+    #[serde(default)]
+    pub session_committed: bool,
+    /// Where the journal is saved
+    #[serde(skip)]
+    path: PathBuf,
+    /// Log of the chunks acknowledged since the last snapshot, opened on the first one
+    #[serde(skip)]
+    log: Option<File>,
+}
+
+impl UploadJournal {
+    /// Opens the journal for an upload, creating a fresh one if none exists.
+    ///
+    /// # Arguments
+    ///
+    /// * `journal_dir` - Directory journals are stored in.
+    /// * `file_path` - Path to the file being uploaded.
+    /// * `params` - Upload parameters including canister info.
+    /// * `chunk_size` - The size of each chunk.
+    /// * `start_offset` - Byte offset where chunking starts.
+    ///
+    /// # Returns
+    ///
+    /// The journal, or an error if the file changed since the journal was written.
+    pub fn open_or_create(
+        journal_dir: &Path,
+        file_path: &Path,
+        params: &UploadParams,
+        chunk_size: usize,
+        start_offset: u64,
+    ) -> Result<Self, UploadError> {
+        let path = journal_path(journal_dir, file_path, params, chunk_size, start_offset);
+        let identity = FileIdentity::from_path(file_path)
+            .map_err(|e| UploadError::io(format!("Failed to read {}", file_path.display()), e))?;
+
+        if path.exists() {
+            let mut journal = Self::load(&path)?;
+
+            if !journal.file.same_contents(&identity) {
+                return Err(UploadError::Other(create_error_string(&format!(
+                    "{} changed since the journal {} was written (size {} -> {}, sha256 {} -> {}). \
+                     Delete the journal to start a fresh upload.",
+                    file_path.display(),
+                    path.display(),
+                    journal.file.size,
+                    identity.size,
+                    journal.file.sha256,
+                    identity.sha256,
+                ))));
+            }
+
+            journal.file = identity;
+            journal.save()?;
+            return Ok(journal);
+        }
+
+        let mut journal = Self {
+            file: identity,
+            chunk_size,
+            start_offset,
+            canister_name: params.canister_name.to_string(),
+            canister_method: params.canister_method.to_string(),
+            network: params.network.map(|n| n.to_string()),
+            acknowledged: BTreeSet::new(),
+            session_id: None,
+            session_committed: false,
+            path,
+            log: None,
+        };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// Loads a journal from disk, including the chunks in its log.
+    pub fn load(path: &Path) -> Result<Self, UploadError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| UploadError::io(format!("Failed to read journal {}", path.display()), e))?;
+        let mut journal: Self = serde_json::from_str(&content)
+            .map_err(|e| UploadError::io(format!("Failed to parse journal {}", path.display()), e.into()))?;
+        journal.path = path.to_path_buf();
+
+        let log_path = journal.log_path();
+        match fs::read_to_string(&log_path) {
+            // Only complete lines count; a line cut short by a crash could hold a different chunk ID
+            Ok(log) => journal.acknowledged.extend(
+                log.split_inclusive('\n')
+                    .filter_map(|line| line.strip_suffix('\n'))
+                    .filter_map(|line| line.trim().parse::<u32>().ok()),
+            ),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(UploadError::io(format!("Failed to read journal log {}", log_path.display()), e)),
+        }
+        Ok(journal)
+    }
+
+    /// Writes a snapshot of the journal to disk, replacing the previous version
+    /// atomically, and empties the log it now contains.
+    pub fn save(&mut self) -> Result<(), UploadError> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| UploadError::io(format!("Failed to create {}", dir.display()), e))?;
+        }
+
+        let content =
+            serde_json::to_string_pretty(self).map_err(|e| UploadError::io("Failed to serialize journal", e.into()))?;
+
+        let temp_path = self.path.with_extension("json.tmp");
+        fs::write(&temp_path, content)
+            .and_then(|()| fs::rename(&temp_path, &self.path))
+            .map_err(|e| UploadError::io(format!("Failed to write journal {}", self.path.display()), e))?;
+
+        self.truncate_log()
+    }
+
+    /// Records that the canister acknowledged a chunk by appending it to the log
+    /// and syncing the log to disk, so the record survives a crash.
+    pub fn record(&mut self, chunk_id: u32) -> Result<(), UploadError> {
+        if !self.acknowledged.insert(chunk_id) {
+            return Ok(());
+        }
+
+        let log_path = self.log_path();
+        let log = match &mut self.log {
+            Some(log) => log,
+            log => log.insert(
+                File::options()
+                    .create(true)
+                    .append(true)
+                    .open(&log_path)
+                    .map_err(|e| UploadError::io(format!("Failed to open journal log {}", log_path.display()), e))?,
+            ),
+        };
+        writeln!(log, "{}", chunk_id)
+            .and_then(|()| log.sync_data())
+            .map_err(|e| UploadError::io(format!("Failed to write journal log {}", log_path.display()), e))
+    }
+
+    /// Records the upload session chunks are sent to and saves the journal.
+    ///
+    /// Chunks acknowledged for a different session are forgotten, since the new
+    /// session does not hold them.
+    pub fn record_session(&mut self, session_id: SessionId) -> Result<(), UploadError> {
+        if self.session_id != Some(session_id) {
+            // Emptied first, so a crash before the snapshot cannot carry them into the new session
+            self.truncate_log()?;
+            self.acknowledged.clear();
+            self.session_committed = false;
+        }
+        self.session_id = Some(session_id);
+        self.save()
+    }
+
+    /// Records that the upload session was committed and saves the journal.
+    pub fn record_commit(&mut self) -> Result<(), UploadError> {
+        self.session_committed = true;
+        self.save()
+    }
+
+    /// Whether the canister already acknowledged a chunk
+    pub fn is_acknowledged(&self, chunk_id: u32) -> bool {
+        self.acknowledged.contains(&chunk_id)
+    }
+
+    /// The first chunk that has not been acknowledged yet
+    pub fn first_pending(&self) -> usize {
+        (0..).find(|id| !self.acknowledged.contains(id)).unwrap_or(0) as usize
+    }
+
+    /// Where the journal is saved
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Deletes the journal and its log from disk, e.g. once the upload completed.
+    pub fn remove(&mut self) -> Result<(), UploadError> {
+        self.log = None;
+        for path in [self.log_path(), self.path.clone()] {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(UploadError::io(format!("Failed to remove journal {}", path.display()), e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the chunks acknowledged since the last snapshot are appended
+    fn log_path(&self) -> PathBuf {
+        self.path.with_extension("log")
+    }
+
+    /// Empties the log, once its chunks are in the snapshot or no longer wanted
+    fn truncate_log(&mut self) -> Result<(), UploadError> {
+        self.log = None;
+        let log_path = self.log_path();
+        match File::options().write(true).truncate(true).open(&log_path) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(UploadError::io(format!("Failed to truncate journal log {}", log_path.display()), e)),
+        }
+    }
+}
+
+/// Location of the journal for a given file and upload target.
+///
+/// The file name is a hash of the absolute file path, the target and the
+/// chunking parameters, so each distinct upload gets its own journal.
+pub fn journal_path(
+    journal_dir: &Path,
+    file_path: &Path,
+    params: &UploadParams,
+    chunk_size: usize,
+    start_offset: u64,
+) -> PathBuf {
+    let absolute = fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf());
+
+    let mut hasher = Sha256::new();
+    for part in [
+        absolute.to_string_lossy().as_ref(),
+        params.canister_name,
+        params.canister_method,
+        params.network.unwrap_or(""),
+        &chunk_size.to_string(),
+        &start_offset.to_string(),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update([0u8]);
+    }
+
+    let digest = hex::encode(hasher.finalize());
+    journal_dir.join(format!("{}.json", &digest[..16]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::test_params;
+    use std::io::Write;
+
+    #[test]
+    fn test_sha256_reader() {
+        let digest = sha256_reader(&b"abc"[..]).unwrap();
+        assert_eq!(
+            hex::encode(digest),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_journal_records_and_resumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("data.bin");
+        fs::write(&file_path, vec![7u8; 100]).unwrap();
+        let journal_dir = dir.path().join(DEFAULT_JOURNAL_DIR);
+
+        let mut journal = UploadJournal::open_or_create(&journal_dir, &file_path, &test_params(), 10, 0).unwrap();
+        assert!(journal.acknowledged.is_empty());
+        journal.record(0).unwrap();
+        journal.record(1).unwrap();
+        journal.record(3).unwrap();
+        journal.record(3).unwrap();
+
+        // Chunks are appended to the log, the snapshot is left alone
+        let log_path = journal.path().with_extension("log");
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), "0\n1\n3\n");
+        let snapshot: serde_json::Value = serde_json::from_str(&fs::read_to_string(journal.path()).unwrap()).unwrap();
+        assert_eq!(snapshot["acknowledged"], serde_json::json!([]));
+        File::options().append(true).open(&log_path).unwrap().write_all(b"4").unwrap();
+
+        // Reopening folds the log into the snapshot, without the line cut short
+        let mut reopened = UploadJournal::open_or_create(&journal_dir, &file_path, &test_params(), 10, 0).unwrap();
+        assert_eq!(reopened.acknowledged, BTreeSet::from([0, 1, 3]));
+        assert_eq!(reopened.first_pending(), 2);
+        assert!(reopened.is_acknowledged(3));
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), "");
+
+        reopened.remove().unwrap();
+        assert!(!reopened.path().exists());
+        assert!(!log_path.exists());
+    }
+
+    #[test]
+    fn test_journal_new_session_forgets_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("data.bin");
+        fs::write(&file_path, vec![7u8; 100]).unwrap();
+        let journal_dir = dir.path().join(DEFAULT_JOURNAL_DIR);
+
+        let mut journal = UploadJournal::open_or_create(&journal_dir, &file_path, &test_params(), 10, 0).unwrap();
+        journal.record_session(1).unwrap();
+        journal.record(0).unwrap();
+        journal.record_session(1).unwrap();
+        assert!(journal.is_acknowledged(0));
+
+        journal.record_session(2).unwrap();
+        assert!(journal.acknowledged.is_empty());
+
+        let reopened = UploadJournal::open_or_create(&journal_dir, &file_path, &test_params(), 10, 0).unwrap();
+        assert_eq!(reopened.session_id, Some(2));
+    }
+
+    #[test]
+    fn test_journal_refuses_changed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("data.bin");
+        fs::write(&file_path, vec![7u8; 100]).unwrap();
+        let journal_dir = dir.path().join(DEFAULT_JOURNAL_DIR);
+
+        let mut journal = UploadJournal::open_or_create(&journal_dir, &file_path, &test_params(), 10, 0).unwrap();
+        journal.record(0).unwrap();
+
+        File::options().append(true).open(&file_path).unwrap().write_all(b"more").unwrap();
+
+        let error = UploadJournal::open_or_create(&journal_dir, &file_path, &test_params(), 10, 0).unwrap_err();
+        assert!(matches!(&error, UploadError::Other(message) if message.contains("changed since the journal")));
+    }
+
+    #[test]
+    fn test_journal_path_depends_on_target() {
+        let dir = Path::new(DEFAULT_JOURNAL_DIR);
+        let file = Path::new("model.bin");
+        let mut other = test_params();
+        other.canister_method = "append_parallel_chunk";
+
+        assert_eq!(journal_path(dir, file, &test_params(), 10, 0), journal_path(dir, file, &test_params(), 10, 0));
+        assert_ne!(journal_path(dir, file, &test_params(), 10, 0), journal_path(dir, file, &other, 10, 0));
+        assert_ne!(journal_path(dir, file, &test_params(), 10, 0), journal_path(dir, file, &test_params(), 20, 0));
+    }
+}
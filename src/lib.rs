@@ -1,16 +1,51 @@
 //! This crate provides functionality for uploading files to Internet Computer canisters.
 //!
 //! It includes utilities for splitting files into chunks, converting data to blob strings,
-//! and interfacing with the `dfx` command-line tool to upload data to canisters.
+//! and interfacing with the `dfx` command-line tool (or a native `ic-agent` client)
+//! to upload data to canisters.
 #![warn(missing_docs)]
 
+pub mod agent;
+pub mod assets;
+pub mod cancel;
+pub mod concurrency;
+pub mod download;
+pub mod error;
+pub mod hooks;
+pub mod install;
+pub mod interface;
+pub mod journal;
 pub mod parallel;
+pub mod progress;
+pub mod ratelimit;
+pub mod reply;
+pub mod retry;
+pub mod resume;
+pub mod session;
+pub mod source;
+pub mod template;
+pub mod transport;
+pub mod verify;
 
 use std::process::Command;
-use std::io::Write;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
-use tempfile::NamedTempFile;
+use std::time::{Duration, Instant};
+
+use candid::Encode;
+
+use crate::cancel::{stop_reason, CancellationToken, StopReason};
+use crate::error::UploadError;
+use crate::hooks::{run_hook, CanisterCall};
+use crate::journal::UploadJournal;
+use crate::progress::{notify, ProgressEvent, ProgressObserver, SharedObserver, UploadReport};
+use crate::ratelimit::{TokenBucket, DEFAULT_BURST_MIB};
+use crate::reply::ReplyPolicy;
+use crate::retry::{AttemptError, RetryPolicy};
+use crate::session::{commit_session, encode_put_chunk_args, SessionId};
+use crate::source::ChunkSource;
+use crate::template::{ArgTemplate, ChunkContext};
+use crate::transport::{DfxTransport, Transport};
 
 /// The maximum size of the HTTP payload for canister updates, set to 2 MiB.
 pub const MAX_CANISTER_HTTP_PAYLOAD_SIZE: usize = 2 * 1000 * 1000; // 2 MiB
@@ -20,21 +55,55 @@ pub const MAX_CANISTER_HTTP_PAYLOAD_SIZE: usize = 2 * 1000 * 1000; // 2 MiB
 pub struct UploadConfig {
     /// Maximum number of retry attempts per chunk
     pub max_retries: usize,
-    /// Delay between retry attempts in milliseconds
-    pub retry_delay_ms: u64,
+    /// Backoff between retry attempts
+    pub retry_policy: RetryPolicy,
     /// Whether to enable auto-resume functionality
     pub auto_resume: bool,
-    /// Optional callback for progress reporting
-    pub progress_callback: Option<fn(usize, usize, &str)>,
+    /// Receives the progress events of the upload
+    pub observer: Option<SharedObserver>,
+    /// Optional journal updated after every acknowledged chunk
+    pub journal: Option<Arc<Mutex<UploadJournal>>>,
+    /// Upload session the chunks belong to; chunks are sent as `put_chunk` arguments when set,
+    /// and the session is committed once every chunk succeeded, before finalize and verify
+    pub session_id: Option<SessionId>,
+    /// Optional call made before the first chunk
+    pub prepare: Option<CanisterCall>,
+    /// Optional call made once every chunk succeeded
+    pub finalize: Option<CanisterCall>,
+    /// Optional hash query compared against the local chunks after finalizing
+    pub verify: Option<CanisterCall>,
+    /// Argument shape of the chunk calls; takes precedence over the session and `(blob)` shapes
+    pub arg_template: Option<ArgTemplate>,
+    /// Which chunk call replies count as success
+    pub reply_policy: ReplyPolicy,
+    /// Target upload rate in MiB per second, measured as in parallel mode; `None` for no limit
+    pub target_rate_mibs: Option<f64>,
+    /// Largest burst above the target rate in MiB
+    pub burst_mib: f64,
+    /// Time after which no further chunk is started; `None` for no limit
+    pub max_duration: Option<Duration>,
+    /// Token that stops the upload before the next chunk once cancelled
+    pub cancellation: Option<CancellationToken>,
 }
 
 impl Default for UploadConfig {
     fn default() -> Self {
         Self {
             max_retries: 3,
-            retry_delay_ms: 1000,
+            retry_policy: RetryPolicy::default(),
             auto_resume: false,
-            progress_callback: None,
+            observer: None,
+            journal: None,
+            session_id: None,
+            prepare: None,
+            finalize: None,
+            verify: None,
+            arg_template: None,
+            reply_policy: ReplyPolicy::default(),
+            target_rate_mibs: None,
+            burst_mib: DEFAULT_BURST_MIB,
+            max_duration: None,
+            cancellation: None,
         }
     }
 }
@@ -54,15 +123,82 @@ impl UploadConfig {
         self
     }
 
-    /// Sets the retry delay in milliseconds
+    /// Sets the delay before the first retry in milliseconds; later retries back off from it
     pub fn with_retry_delay(mut self, delay_ms: u64) -> Self {
-        self.retry_delay_ms = delay_ms;
+        self.retry_policy.base_delay = Duration::from_millis(delay_ms);
+        self
+    }
+
+    /// Sets how failed attempts are retried
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// Sets the observer that receives the progress events, see [`progress`]
+    pub fn with_observer(mut self, observer: impl ProgressObserver + 'static) -> Self {
+        self.observer = Some(SharedObserver::new(observer));
+        self
+    }
+
+    /// Sets the journal that records acknowledged chunks
+    pub fn with_journal(mut self, journal: Arc<Mutex<UploadJournal>>) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Sends chunks into an upload session opened with [`session::begin_upload`] and commits it after the last chunk
+    pub fn with_session(mut self, session_id: SessionId) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    /// Sets a call made before the first chunk, e.g. to clear a buffer
+    pub fn with_prepare(mut self, call: CanisterCall) -> Self {
+        self.prepare = Some(call);
+        self
+    }
+
+    /// Sets a call made once every chunk succeeded, e.g. to persist the upload
+    pub fn with_finalize(mut self, call: CanisterCall) -> Self {
+        self.finalize = Some(call);
+        self
+    }
+
+    /// Sets a hash query used to verify the upload, see [`verify::verify_upload`]
+    pub fn with_verify(mut self, hash_query: CanisterCall) -> Self {
+        self.verify = Some(hash_query);
+        self
+    }
+
+    /// Sends chunks in the argument shape of a template, see [`template`]
+    pub fn with_arg_template(mut self, template: ArgTemplate) -> Self {
+        self.arg_template = Some(template);
         self
     }
 
-    /// Sets a progress callback function
-    pub fn with_progress_callback(mut self, callback: fn(usize, usize, &str)) -> Self {
-        self.progress_callback = Some(callback);
+    /// Sets which chunk call replies count as success
+    pub fn with_reply_policy(mut self, policy: ReplyPolicy) -> Self {
+        self.reply_policy = policy;
+        self
+    }
+
+    /// Limits the upload rate with a token bucket, see [`ratelimit`]
+    pub fn with_rate_limit(mut self, rate_mibs: f64, burst_mib: f64) -> Self {
+        self.target_rate_mibs = Some(rate_mibs);
+        self.burst_mib = burst_mib;
+        self
+    }
+
+    /// Stops starting chunks once the upload ran for `max_duration`
+    pub fn with_max_duration(mut self, max_duration: Duration) -> Self {
+        self.max_duration = Some(max_duration);
+        self
+    }
+
+    /// Stops the upload before the next chunk once `token` is cancelled
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
         self
     }
 }
@@ -73,13 +209,20 @@ pub enum ChunkUploadResult {
     /// Chunk uploaded successfully
     Success,
     /// Chunk failed after all retry attempts
-    Failed(String),
+    Failed(UploadError),
     /// Upload was interrupted and can be resumed
     Interrupted {
         /// The index of the chunk that failed (0-based)
         failed_at_chunk: usize,
-        /// The error message describing the failure
-        error: String
+        /// The failure that interrupted the upload
+        error: UploadError,
+    },
+    /// The upload stopped early; the chunks before `next_chunk` were uploaded
+    Stopped {
+        /// The index of the first chunk that was not uploaded (0-based)
+        next_chunk: usize,
+        /// Why the upload stopped
+        reason: StopReason,
     },
 }
 
@@ -133,6 +276,23 @@ pub fn vec_u8_to_blob_string(data: &[u8]) -> String {
     format!("(blob \"{}\")", blob_content)
 }
 
+/// Encodes a vector of bytes as a binary Candid `(blob)` argument.
+///
+/// This is the binary (DIDL) equivalent of [`vec_u8_to_blob_string`], without the
+/// threefold size increase of escaping every byte as text.
+///
+/// # Arguments
+///
+/// * `data` - A slice of bytes to be encoded.
+///
+/// # Returns
+///
+/// A `Result` containing the Candid-encoded argument or an error message.
+pub fn encode_blob_args(data: &[u8]) -> Result<Vec<u8>, UploadError> {
+    Encode!(&serde_bytes::Bytes::new(data))
+        .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))
+}
+
 /// Uploads a chunk of data to the specified canister method.
 ///
 /// # Arguments
@@ -147,116 +307,178 @@ pub fn vec_u8_to_blob_string(data: &[u8]) -> String {
 ///
 /// # Returns
 ///
-/// A `Result` indicating success (`Ok(())`) or an error message (`Err(String)`).
+/// A `Result` indicating success (`Ok(())`) or why the chunk failed.
 pub fn upload_chunk(name: &str,
     canister_name: &str,
     bytecode_chunk: &[u8],
     canister_method_name: &str,
     chunk_number: usize,
     chunk_total: usize,
-    network: Option<&str>) -> Result<(), String> {
-
-    let blob_string = vec_u8_to_blob_string(bytecode_chunk);
-
-    let mut temp_file = NamedTempFile::new()
-        .map_err(|_| create_error_string("Failed to create temporary file"))?;
-
-    temp_file
-        .as_file_mut()
-        .write_all(blob_string.as_bytes())
-        .map_err(|_| create_error_string("Failed to write data to temporary file"))?;
-
-    let output = dfx(
-        "canister",
-        "call",
-        &vec![
-            canister_name,
-            canister_method_name,
-            "--argument-file",
-            temp_file.path().to_str().ok_or(create_error_string(
-                "temp_file path could not be converted to &str",
-            ))?,
-        ],
-        network, // Pass the optional network argument
-    )?;
-
-    // 0-indexing to 1-indexing
-    let chunk_number_display = chunk_number + 1;
-
-    if output.status.success() {
-        println!("Uploading {name} chunk {chunk_number_display}/{chunk_total}");
-    } else {
-        let error_message = String::from_utf8_lossy(&output.stderr).to_string();
-        eprintln!("Failed to upload chunk {chunk_number_display}: {error_message}");
-        return Err(create_error_string(&format!("Chunk {chunk_number_display} failed: {error_message}")));
-    }
-
-    Ok(())
+    network: Option<&str>) -> Result<(), UploadError> {
+
+    upload_chunk_with_transport(
+        &DfxTransport::new(network),
+        name,
+        canister_name,
+        bytecode_chunk,
+        canister_method_name,
+        chunk_number,
+        chunk_total,
+    )
+}
+
+/// Uploads a chunk of data to the specified canister method through the given transport.
+///
+/// A reply carrying an `Err` variant fails the chunk, see [`ReplyPolicy::default`].
+///
+/// # Arguments
+///
+/// * `transport` - The transport used to submit the call.
+/// * `name` - The name of the chunk being uploaded, used in the error.
+/// * `canister_name` - The name of the canister.
+/// * `bytecode_chunk` - A reference to the vector of bytes representing the chunk.
+/// * `canister_method_name` - The name of the canister method to call.
+/// * `chunk_number` - The number of the current chunk.
+/// * `chunk_total` - The total number of chunks, used in the error.
+///
+/// # Returns
+///
+/// A `Result` indicating success (`Ok(())`) or why the chunk failed.
+pub fn upload_chunk_with_transport<T: Transport + ?Sized>(transport: &T,
+    name: &str,
+    canister_name: &str,
+    bytecode_chunk: &[u8],
+    canister_method_name: &str,
+    chunk_number: usize,
+    chunk_total: usize) -> Result<(), UploadError> {
+
+    let blob_args = encode_blob_args(bytecode_chunk)?;
+
+    submit_chunk(transport, canister_name, &blob_args, canister_method_name, &ReplyPolicy::default())
+        .map_err(|e| e.into_chunk_error(chunk_number as u32, 1).context(upload_context(name, chunk_total)))
+}
+
+/// Describes the upload a failed chunk belonged to
+fn upload_context(name: &str, chunk_total: usize) -> String {
+    format!("Failed to upload {} ({} chunks)", name, chunk_total)
+}
+
+/// Submits an already-encoded chunk argument; progress is reported by the
+/// callers through [`ProgressEvent`]s.
+fn submit_chunk<T: Transport + ?Sized>(transport: &T,
+    canister_name: &str,
+    candid_args: &[u8],
+    canister_method_name: &str,
+    reply_policy: &ReplyPolicy) -> Result<(), AttemptError> {
+
+    // A delivered reply can still report a failure
+    transport
+        .call(canister_name, canister_method_name, candid_args)
+        .map_err(AttemptError::from_call)
+        .and_then(|reply| reply_policy.check(&reply).map_err(AttemptError::from_reply))
 }
 
 /// Uploads a single chunk with retry logic based on the provided configuration.
 ///
+/// The chunk is sent as `put_chunk` arguments when the configuration has a
+/// session and as `(blob)` otherwise. Argument templates need the chunk's byte
+/// offset and are applied by [`upload_chunks_with_resume`]. Failures that no
+/// retry can fix, see [`retry::classify`], are not retried.
+///
 /// # Arguments
 ///
+/// * `transport` - The transport used to submit chunk calls
 /// * `params` - Upload parameters including canister info
 /// * `chunk` - The chunk data to upload
 /// * `chunk_index` - The index of the current chunk (0-based)
-/// * `total_chunks` - The total number of chunks
+/// * `total_chunks` - The total number of chunks, used in the error
 /// * `config` - Upload configuration with retry settings
 ///
 /// # Returns
 ///
-/// A `Result` indicating success or failure after all attempts
-pub fn upload_chunk_with_config(
+/// A `Result` indicating success or the failure after all attempts
+pub fn upload_chunk_with_config<T: Transport + ?Sized>(
+    transport: &T,
     params: &UploadParams,
     chunk: &[u8],
     chunk_index: usize,
     total_chunks: usize,
     config: &UploadConfig,
-) -> Result<(), String> {
+) -> Result<(), UploadError> {
+    encode_and_submit_chunk(transport, params, chunk, chunk_index, config, &mut 0)
+        .map_err(|e| e.error.context(upload_context(params.name, total_chunks)))
+}
+
+/// Encodes a chunk in the session or `(blob)` shape and submits it with retries
+fn encode_and_submit_chunk<T: Transport + ?Sized>(
+    transport: &T,
+    params: &UploadParams,
+    chunk: &[u8],
+    chunk_index: usize,
+    config: &UploadConfig,
+    retries: &mut usize,
+) -> Result<(), AttemptError> {
+    let candid_args = match config.session_id {
+        Some(session_id) => encode_put_chunk_args(session_id, chunk_index as u32, chunk),
+        None => encode_blob_args(chunk),
+    }
+    .map_err(AttemptError::fatal)?;
+
+    submit_chunk_with_retry(transport, params, &candid_args, chunk.len(), chunk_index, config, retries)
+}
+
+/// Submits an encoded chunk of `size` bytes, retrying retryable failures as
+/// configured and counting the retries in `retries`
+fn submit_chunk_with_retry<T: Transport + ?Sized>(
+    transport: &T,
+    params: &UploadParams,
+    candid_args: &[u8],
+    size: usize,
+    chunk_index: usize,
+    config: &UploadConfig,
+    retries: &mut usize,
+) -> Result<(), AttemptError> {
     let mut attempts = 0;
     let max_attempts = config.max_retries;
+    let started = Instant::now();
+    let chunk_id = chunk_index as u32;
+    notify(&config.observer, || ProgressEvent::ChunkStarted { chunk_id, size });
 
     loop {
         attempts += 1;
 
-        match upload_chunk(
-            params.name,
-            params.canister_name,
-            chunk,
-            params.canister_method,
-            chunk_index,
-            total_chunks,
-            params.network,
-        ) {
+        let attempt_started = Instant::now();
+        match submit_chunk(transport, params.canister_name, candid_args, params.canister_method, &config.reply_policy) {
             Ok(()) => {
-                if let Some(callback) = config.progress_callback {
-                    let status = if attempts > 1 {
-                        format!("✓ Uploaded after {} attempts", attempts)
-                    } else {
-                        "✓ Uploaded".to_string()
-                    };
-                    callback(chunk_index + 1, total_chunks, &status);
-                }
+                notify(&config.observer, || ProgressEvent::ChunkSucceeded {
+                    chunk_id,
+                    size,
+                    attempts,
+                    latency: attempt_started.elapsed(),
+                });
                 return Ok(());
             }
             Err(e) => {
-                if attempts >= max_attempts {
-                    return Err(format!(
-                        "Failed to upload chunk {}/{} after {} attempts. Last error: {}",
-                        chunk_index + 1, total_chunks, attempts, e
-                    ));
+                if e.is_fatal() {
+                    return Err(AttemptError::fatal(e.into_chunk_error(chunk_id, attempts)));
                 }
 
-                if let Some(callback) = config.progress_callback {
-                    callback(
-                        chunk_index + 1,
-                        total_chunks,
-                        &format!("⚠ Attempt {}/{} failed, retrying...", attempts, max_attempts)
-                    );
+                let delay = config.retry_policy.delay(attempts as u32);
+                let cancelled = config.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled);
+                if cancelled || attempts >= max_attempts || !config.retry_policy.allows_retry(started.elapsed(), delay) {
+                    return Err(AttemptError::retryable(e.into_chunk_error(chunk_id, attempts)));
                 }
+                *retries += 1;
+
+                notify(&config.observer, || ProgressEvent::ChunkRetry {
+                    chunk_id,
+                    attempt: attempts,
+                    max_attempts,
+                    delay,
+                    error: e.error.clone(),
+                });
 
-                thread::sleep(Duration::from_millis(config.retry_delay_ms));
+                thread::sleep(delay);
             }
         }
     }
@@ -269,47 +491,188 @@ pub fn upload_chunk_with_config(
 ///
 /// # Arguments
 ///
+/// * `transport` - The transport used to submit chunk calls
 /// * `params` - Upload parameters including canister info
-/// * `chunks` - Vector of chunks to upload
+/// * `source` - Source the chunks are read from, one at a time
 /// * `start_from_chunk` - Chunk index to start from (for resume functionality)
 /// * `config` - Upload configuration
 ///
 /// # Returns
 ///
 /// A `ChunkUploadResult` indicating the outcome of the upload operation
-pub fn upload_chunks_with_resume(
+pub fn upload_chunks_with_resume<T: Transport + ?Sized, S: ChunkSource + ?Sized>(
+    transport: &T,
     params: &UploadParams,
-    chunks: &[Vec<u8>],
+    source: &S,
     start_from_chunk: usize,
     config: &UploadConfig,
 ) -> ChunkUploadResult {
-    if chunks.is_empty() {
-        return ChunkUploadResult::Failed("No chunks to upload".to_string());
+    let total_chunks = source.chunk_count();
+
+    if total_chunks == 0 {
+        return ChunkUploadResult::Failed(UploadError::Other("No chunks to upload".to_string()));
     }
 
-    if start_from_chunk >= chunks.len() {
-        return ChunkUploadResult::Failed("Start chunk index exceeds total chunks".to_string());
+    if start_from_chunk >= total_chunks {
+        return ChunkUploadResult::Failed(UploadError::Other("Start chunk index exceeds total chunks".to_string()));
     }
 
-    for (relative_index, chunk) in chunks.iter().enumerate().skip(start_from_chunk) {
-        match upload_chunk_with_config(params, chunk, relative_index, chunks.len(), config) {
-            Ok(()) => continue,
+    if let Some(prepare) = &config.prepare {
+        if let Err(e) = run_hook(transport, params, prepare, "Prepare", &config.observer) {
+            return ChunkUploadResult::Failed(e);
+        }
+    }
+
+    let bucket = match config.target_rate_mibs {
+        Some(rate_mibs) => TokenBucket::new(rate_mibs, config.burst_mib),
+        None => TokenBucket::unlimited(),
+    };
+
+    notify(&config.observer, || ProgressEvent::Started {
+        chunks: total_chunks - start_from_chunk,
+        workers: 1,
+    });
+
+    let started = Instant::now();
+    let mut report = UploadReport::default();
+    let mut outcome = None;
+    for relative_index in start_from_chunk..total_chunks {
+        let chunk_id = relative_index as u32;
+        if let Some(reason) = stop_reason(config.cancellation.as_ref(), config.max_duration, started) {
+            report.stop_reason = Some(reason);
+            outcome = Some(ChunkUploadResult::Stopped { next_chunk: relative_index, reason });
+            break;
+        }
+
+        // Only the chunk being uploaded is held in memory
+        let mut size = 0;
+        let result = source
+            .read_chunk(relative_index)
+            .map_err(|e| AttemptError::fatal(UploadError::io(format!("Failed to read chunk {}", relative_index + 1), e)))
+            .inspect(|chunk| {
+                size = chunk.len();
+                bucket.acquire(size);
+            })
+            .and_then(|chunk| match &config.arg_template {
+                Some(template) => template
+                    .render(&ChunkContext {
+                        name: params.name,
+                        index: chunk_id,
+                        offset: relative_index as u64 * source.chunk_size() as u64,
+                        total: total_chunks as u32,
+                        data: &chunk,
+                    })
+                    .map_err(AttemptError::fatal)
+                    .and_then(|args| {
+                        submit_chunk_with_retry(
+                            transport,
+                            params,
+                            &args,
+                            chunk.len(),
+                            relative_index,
+                            config,
+                            &mut report.retries,
+                        )
+                    }),
+                None => encode_and_submit_chunk(transport, params, &chunk, relative_index, config, &mut report.retries),
+            });
+
+        match result {
+            Ok(()) => {
+                if let Some(journal) = &config.journal {
+                    record_in_journal(journal, chunk_id, &config.observer);
+                }
+                report.successful_chunks.push(chunk_id);
+                report.bytes_uploaded += size as u64;
+            }
             Err(e) => {
-                if config.auto_resume {
-                    return ChunkUploadResult::Interrupted {
+                notify(&config.observer, || ProgressEvent::ChunkFailed { chunk_id, error: e.error.clone() });
+                report.failed_chunks.insert(chunk_id, e.error.clone());
+
+                outcome = Some(if config.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                    // A chunk cancelled while in flight is sent again on resume
+                    report.stop_reason = Some(StopReason::Cancelled);
+                    ChunkUploadResult::Stopped {
+                        next_chunk: relative_index,
+                        reason: StopReason::Cancelled,
+                    }
+                } else if config.auto_resume && !e.is_fatal() {
+                    // Resuming cannot get past a failure that no retry can fix
+                    ChunkUploadResult::Interrupted {
                         failed_at_chunk: relative_index,
-                        error: e,
-                    };
+                        error: e.error,
+                    }
                 } else {
-                    return ChunkUploadResult::Failed(e);
-                }
+                    if e.is_fatal() {
+                        report.fatal_error = Some(e.error.clone());
+                    }
+                    ChunkUploadResult::Failed(e.error)
+                });
             }
         }
+
+        report.duration = started.elapsed();
+        notify(&config.observer, || ProgressEvent::RateSample {
+            rate_mibs: report.rate_mibs(),
+            concurrency: 1,
+        });
+        if outcome.is_some() {
+            break;
+        }
+    }
+
+    // The chunks after a failed one, or from where the upload stopped, were not attempted
+    let first_pending = start_from_chunk + report.successful_chunks.len() + report.failed_chunks.len();
+    let reason = match (report.stop_reason, report.failed_chunks.keys().next()) {
+        (Some(reason), _) => reason.to_string(),
+        (None, Some(failed)) => format!("chunk {} before it failed", failed),
+        (None, None) => String::new(),
+    };
+    for chunk_id in first_pending..total_chunks {
+        report.failed_chunks.insert(chunk_id as u32, UploadError::NotAttempted(reason.clone()));
+    }
+    report.duration = started.elapsed();
+    notify(&config.observer, || ProgressEvent::Finished { report: report.clone() });
+
+    if let Some(outcome) = outcome {
+        return outcome;
+    }
+
+    if let Some(session_id) = config.session_id {
+        if let Err(e) = commit_session(transport, params, session_id, &config.journal, &config.observer) {
+            return ChunkUploadResult::Failed(e);
+        }
+    }
+
+    if let Some(finalize) = &config.finalize {
+        if let Err(e) = run_hook(transport, params, finalize, "Finalize", &config.observer) {
+            return ChunkUploadResult::Failed(e);
+        }
+    }
+
+    if let Some(hash_query) = &config.verify {
+        match verify::verify_upload(transport, params, source, hash_query) {
+            Ok(sha256) => notify(&config.observer, || ProgressEvent::Verified {
+                sha256,
+                method: hash_query.method.clone(),
+            }),
+            Err(e) => return ChunkUploadResult::Failed(e),
+        }
     }
 
     ChunkUploadResult::Success
 }
 
+/// Records an acknowledged chunk in the journal.
+///
+/// A journal that cannot be written only costs the ability to resume, so the
+/// failure is reported to the observer without failing the upload.
+pub(crate) fn record_in_journal(journal: &Mutex<UploadJournal>, chunk_id: u32, observer: &Option<SharedObserver>) {
+    if let Err(error) = journal.lock().unwrap().record(chunk_id) {
+        notify(observer, || ProgressEvent::JournalFailed { chunk_id, error });
+    }
+}
+
 /// Executes a dfx command with the specified arguments.
 ///
 /// # Arguments
@@ -321,8 +684,15 @@ pub fn upload_chunks_with_resume(
 ///
 /// # Returns
 ///
-/// A `Result` containing the output of the command or an error message.
-pub fn dfx(command: &str, subcommand: &str, args: &Vec<&str>, network: Option<&str>) -> Result<std::process::Output, String> {
+/// A `Result` containing the output of the command, or [`UploadError::Spawn`] if dfx could not be started.
+pub fn dfx(command: &str, subcommand: &str, args: &Vec<&str>, network: Option<&str>) -> Result<std::process::Output, UploadError> {
+    dfx_command(command, subcommand, args, network)
+        .output()
+        .map_err(|e| UploadError::Spawn(create_error_string(&format!("Failed to start dfx: {}", e))))
+}
+
+/// Builds a dfx command with the specified arguments without running it, see [`dfx`].
+pub(crate) fn dfx_command(command: &str, subcommand: &str, args: &[&str], network: Option<&str>) -> Command {
     let mut dfx_command = Command::new("dfx");
     dfx_command.arg(command);
     dfx_command.arg(subcommand);
@@ -336,7 +706,7 @@ pub fn dfx(command: &str, subcommand: &str, args: &Vec<&str>, network: Option<&s
         dfx_command.arg(arg);
     }
 
-    dfx_command.output().map_err(|e| e.to_string())
+    dfx_command
 }
 
 /// Creates a formatted error string.
@@ -351,3 +721,321 @@ pub fn dfx(command: &str, subcommand: &str, args: &Vec<&str>, network: Option<&s
 pub fn create_error_string(message: &str) -> String {
     format!("Upload Error: {message}")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Decode;
+    use crate::transport::{test_params, CallError, MockTransport};
+
+    #[test]
+    fn test_blob_args_match_text_encoding() {
+        for data in [vec![], vec![0x00, 0x01, 0x02], vec![0xFF; 300], (0..=255).collect::<Vec<u8>>()] {
+            let from_text = candid_parser::parse_idl_args(&vec_u8_to_blob_string(&data))
+                .unwrap()
+                .to_bytes()
+                .unwrap();
+            assert_eq!(encode_blob_args(&data).unwrap(), from_text);
+        }
+    }
+
+    #[test]
+    fn test_blob_args_round_trip() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let encoded = encode_blob_args(&data).unwrap();
+
+        assert!(encoded.starts_with(b"DIDL"));
+        let decoded = Decode!(&encoded, serde_bytes::ByteBuf).unwrap();
+        assert_eq!(decoded.into_vec(), data);
+    }
+
+    #[test]
+    fn test_sequential_upload_with_mock_transport() {
+        let transport = MockTransport::new();
+        let chunks = vec![vec![0x00, 0x01], vec![0x02], vec![0xFF]];
+
+        let result = upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &UploadConfig::default());
+        assert!(matches!(result, ChunkUploadResult::Success));
+
+        let calls = transport.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(calls.iter().all(|call| call.canister_name == "backend" && call.method == "append_chunk"));
+        assert_eq!(calls[0].args, encode_blob_args(&[0x00, 0x01]).unwrap());
+        assert_eq!(calls[2].args, encode_blob_args(&[0xFF]).unwrap());
+    }
+
+    #[test]
+    fn test_sequential_upload_reports_journal_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_path = dir.path().join("data.bin");
+        std::fs::write(&file_path, [1, 2]).unwrap();
+        let journal = UploadJournal::open_or_create(dir.path(), &file_path, &test_params(), 1, 0).unwrap();
+        // A directory in place of the log cannot be appended to
+        std::fs::create_dir(journal.path().with_extension("log")).unwrap();
+
+        let failed = Arc::new(Mutex::new(Vec::new()));
+        let config = UploadConfig::default().with_journal(Arc::new(Mutex::new(journal))).with_observer({
+            let failed = failed.clone();
+            move |event: &ProgressEvent| {
+                if let ProgressEvent::JournalFailed { chunk_id, error } = event {
+                    failed.lock().unwrap().push((*chunk_id, matches!(error, UploadError::Io { .. })));
+                }
+            }
+        });
+
+        let result = upload_chunks_with_resume(&MockTransport::new(), &test_params(), &vec![vec![1], vec![2]], 0, &config);
+        assert!(matches!(result, ChunkUploadResult::Success));
+        assert_eq!(*failed.lock().unwrap(), vec![(0, true), (1, true)]);
+    }
+
+    #[test]
+    fn test_sequential_upload_starts_from_chunk_offset() {
+        let transport = MockTransport::new();
+        let chunks = vec![vec![1], vec![2], vec![3]];
+
+        let result = upload_chunks_with_resume(&transport, &test_params(), &chunks, 2, &UploadConfig::default());
+        assert!(matches!(result, ChunkUploadResult::Success));
+
+        let calls = transport.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].args, encode_blob_args(&[3]).unwrap());
+    }
+
+    #[test]
+    fn test_single_chunk_errors_name_the_upload() {
+        let transport = MockTransport::with_responder(|_| Err(CallError::Transport("connection reset".to_string())));
+
+        let error = upload_chunk_with_transport(&transport, "model", "backend", &[1], "append_chunk", 2, 4).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to upload model (4 chunks): Chunk 2 failed after 1 attempt"));
+        assert!(matches!(error.root_cause(), UploadError::Transport(_)));
+
+        let config = UploadConfig::default().with_retry_policy(RetryPolicy::fixed(Duration::ZERO));
+        let error = upload_chunk_with_config(&transport, &test_params(), &[1], 0, 3, &config).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to upload test file (3 chunks): "));
+    }
+
+    #[test]
+    fn test_sequential_upload_into_session() {
+        use sha2::{Digest, Sha256};
+
+        let transport = MockTransport::with_responder(|call| match call.method.as_str() {
+            "begin_upload" => Ok(Encode!(&5u64).unwrap()),
+            "commit_upload" => Ok(Encode!(&Ok::<(), String>(())).unwrap()),
+            "sha256" => Ok(Encode!(&serde_bytes::ByteBuf::from(Sha256::digest([1, 2]).to_vec())).unwrap()),
+            _ => Ok(transport::EMPTY_CANDID_REPLY.to_vec()),
+        });
+        let chunks = vec![vec![1], vec![2]];
+        let session_id = session::begin_upload(&transport, &test_params(), "test file", &chunks).unwrap();
+        let config = UploadConfig::default()
+            .with_session(session_id)
+            .with_finalize(hooks::CanisterCall::without_args("save"))
+            .with_verify(hooks::CanisterCall::without_args("sha256"));
+
+        let result = upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config);
+        assert!(matches!(result, ChunkUploadResult::Success));
+
+        let calls = transport.calls();
+        assert_eq!(calls[2].args, session::encode_put_chunk_args(5, 1, &[2]).unwrap());
+        // The committed data is what finalize and verify see
+        let methods: Vec<_> = calls.into_iter().map(|call| call.method).collect();
+        assert_eq!(methods, ["begin_upload", "append_chunk", "append_chunk", "commit_upload", "save", "sha256"]);
+    }
+
+    #[test]
+    fn test_sequential_upload_with_arg_template() {
+        let transport = MockTransport::new();
+        let chunks = vec![vec![1, 2], vec![3]];
+        let template = template::ArgTemplate::parse("(\"{name}\", {offset} : nat64, {blob})").unwrap();
+        let config = UploadConfig::default().with_arg_template(template);
+
+        let result = upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config);
+        assert!(matches!(result, ChunkUploadResult::Success));
+
+        let calls = transport.calls();
+        assert_eq!(calls[0].args, Encode!(&"test file", &0u64, &serde_bytes::Bytes::new(&[1, 2])).unwrap());
+        assert_eq!(calls[1].args, Encode!(&"test file", &2u64, &serde_bytes::Bytes::new(&[3])).unwrap());
+    }
+
+    #[test]
+    fn test_sequential_upload_stops_on_fatal_error() {
+        let transport = MockTransport::with_responder(|_| {
+            Err(CallError::Rejected {
+                reject_code: Some(5),
+                error_code: Some("IC0536".to_string()),
+                message: "Canister has no update method 'append_chunk'".to_string(),
+            })
+        });
+        let chunks = vec![vec![1], vec![2]];
+        let config = UploadConfig::with_auto_resume().with_max_retries(3).with_retry_delay(0);
+
+        // Not retried, and not reported as resumable
+        match upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config) {
+            ChunkUploadResult::Failed(error) => {
+                assert!(matches!(error, UploadError::Chunk { index: 0, attempts: 1, retryable: false, .. }), "{}", error)
+            }
+            other => panic!("expected failure, got {:?}", other),
+        }
+        assert_eq!(transport.calls().len(), 1);
+    }
+
+    #[test]
+    fn test_sequential_upload_rate_limit() {
+        let transport = MockTransport::new();
+        // Four 64 KiB chunks at 1 MiB/s after a burst that only covers the first
+        let chunks = vec![vec![0; 64 * 1024]; 4];
+        let config = UploadConfig::default().with_rate_limit(1.0, 1.0 / 16.0);
+
+        let started = std::time::Instant::now();
+        let result = upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config);
+        assert!(matches!(result, ChunkUploadResult::Success));
+        assert!(started.elapsed() >= Duration::from_millis(180), "{:?}", started.elapsed());
+        assert_eq!(transport.calls().len(), 4);
+    }
+
+    #[test]
+    fn test_sequential_upload_stops_at_max_duration() {
+        let transport = MockTransport::with_responder(|_| {
+            thread::sleep(Duration::from_millis(30));
+            Ok(transport::EMPTY_CANDID_REPLY.to_vec())
+        });
+        let chunks = vec![vec![1]; 10];
+        let config = UploadConfig::default()
+            .with_finalize(hooks::CanisterCall::without_args("save_to_stable"))
+            .with_max_duration(Duration::from_millis(50));
+
+        let next_chunk = match upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config) {
+            ChunkUploadResult::Stopped { next_chunk, reason: StopReason::TimeLimit } => next_chunk,
+            other => panic!("expected the time limit, got {:?}", other),
+        };
+        assert!((1..10).contains(&next_chunk), "{}", next_chunk);
+        assert_eq!(transport.calls().len(), next_chunk);
+    }
+
+    #[test]
+    fn test_sequential_upload_stops_when_cancelled() {
+        let token = CancellationToken::new();
+        let transport = {
+            let token = token.clone();
+            MockTransport::with_responder(move |call| {
+                // Cancelled while the third chunk is in flight, which still succeeds
+                if call.args == Encode!(&serde_bytes::Bytes::new(&[2])).unwrap() {
+                    token.cancel();
+                }
+                Ok(transport::EMPTY_CANDID_REPLY.to_vec())
+            })
+        };
+        let chunks: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i]).collect();
+        let config = UploadConfig::default().with_cancellation(token);
+
+        let result = upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config);
+        assert!(
+            matches!(result, ChunkUploadResult::Stopped { next_chunk: 3, reason: StopReason::Cancelled }),
+            "{:?}",
+            result
+        );
+        assert_eq!(transport.calls().len(), 3);
+    }
+
+    #[test]
+    fn test_sequential_upload_prepare_and_finalize() {
+        let transport = MockTransport::new();
+        let chunks = vec![vec![1], vec![2]];
+        let config = UploadConfig::default()
+            .with_prepare(hooks::CanisterCall::without_args("clear_buffer"))
+            .with_finalize(hooks::CanisterCall::from_text("save_to_stable", "(\"model\")").unwrap());
+
+        let result = upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config);
+        assert!(matches!(result, ChunkUploadResult::Success));
+
+        let methods: Vec<_> = transport.calls().into_iter().map(|call| call.method).collect();
+        assert_eq!(methods, ["clear_buffer", "append_chunk", "append_chunk", "save_to_stable"]);
+    }
+
+    #[test]
+    fn test_sequential_upload_verifies_hash() {
+        use sha2::{Digest, Sha256};
+
+        let chunks = vec![vec![1], vec![2]];
+        let transport = MockTransport::with_responder(|call| {
+            if call.method == "sha256" {
+                Ok(Encode!(&serde_bytes::ByteBuf::from(Sha256::digest([1, 3]).to_vec())).unwrap())
+            } else {
+                Ok(transport::EMPTY_CANDID_REPLY.to_vec())
+            }
+        });
+        let config = UploadConfig::default().with_verify(hooks::CanisterCall::without_args("sha256"));
+
+        match upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config) {
+            ChunkUploadResult::Failed(error) => {
+                assert!(matches!(&error, UploadError::Verification(message) if message.contains("SHA-256 mismatch")))
+            }
+            other => panic!("expected verification failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sequential_upload_skips_finalize_after_failure() {
+        let transport = MockTransport::with_responder(|call| {
+            if call.method == "append_chunk" {
+                Err(CallError::Transport("connection reset".to_string()))
+            } else {
+                Ok(transport::EMPTY_CANDID_REPLY.to_vec())
+            }
+        });
+        let chunks = vec![vec![1]];
+        let config = UploadConfig::default()
+            .with_max_retries(1)
+            .with_finalize(hooks::CanisterCall::without_args("save_to_stable"));
+
+        let result = upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config);
+        assert!(matches!(result, ChunkUploadResult::Failed(_)));
+        assert!(transport.calls().iter().all(|call| call.method != "save_to_stable"));
+    }
+
+    #[test]
+    fn test_sequential_upload_interrupted_after_retries() {
+        let transport = MockTransport::with_responder(|call| {
+            if call.args == encode_blob_args(&[2]).unwrap() {
+                Err(CallError::Transport("connection reset".to_string()))
+            } else {
+                Ok(transport::EMPTY_CANDID_REPLY.to_vec())
+            }
+        });
+        let chunks = vec![vec![1], vec![2], vec![3]];
+        let config = UploadConfig::with_auto_resume().with_max_retries(2).with_retry_delay(0);
+
+        match upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config) {
+            ChunkUploadResult::Interrupted { failed_at_chunk, error } => {
+                assert_eq!(failed_at_chunk, 1);
+                assert!(error.to_string().contains("connection reset"));
+            }
+            other => panic!("expected interruption, got {:?}", other),
+        }
+
+        // One call for the first chunk, two attempts for the second, none for the third
+        assert_eq!(transport.calls().len(), 3);
+    }
+
+    #[test]
+    fn test_sequential_upload_fails_on_err_reply() {
+        let transport = MockTransport::with_responder(|_| {
+            Ok(Encode!(&Err::<(), String>("out of memory".to_string())).unwrap())
+        });
+        let chunks = vec![vec![1], vec![2]];
+        let config = UploadConfig::with_auto_resume().with_max_retries(2).with_retry_delay(0);
+
+        // The same chunk gets the same reply, so it is neither retried nor resumable
+        match upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config) {
+            ChunkUploadResult::Failed(error) => {
+                assert!(matches!(error, UploadError::Chunk { attempts: 1, retryable: false, .. }), "{}", error);
+                assert!(error.to_string().contains("out of memory"), "{}", error);
+            }
+            other => panic!("expected failure, got {:?}", other),
+        }
+        assert_eq!(transport.calls().len(), 1);
+
+        let config = config.with_reply_policy(reply::ReplyPolicy::accept_all());
+        let result = upload_chunks_with_resume(&transport, &test_params(), &chunks, 0, &config);
+        assert!(matches!(result, ChunkUploadResult::Success));
+    }
+}
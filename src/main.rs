@@ -6,20 +6,69 @@
 //! using the Internet Computer protocol. The tool supports various options such as specifying
 //! the canister name, method name, file path, and network type.
 
-use std::fs;
-use clap::Parser;
-use std::path::Path;
+use candid::Encode;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 use ic_file_uploader::{
-    split_into_chunks, upload_chunks_with_resume, UploadConfig, UploadParams, ChunkUploadResult,
+    encode_blob_args, upload_chunks_with_resume, UploadConfig, UploadParams, ChunkUploadResult,
     MAX_CANISTER_HTTP_PAYLOAD_SIZE
 };
+use ic_file_uploader::agent::NativeAgent;
+use ic_file_uploader::assets::{upload_assets, AssetFile};
+use ic_file_uploader::cancel::{CancellationToken, StopReason};
+use ic_file_uploader::error::UploadError;
+use ic_file_uploader::download::{download_to_file, DownloadConfig, ReadMode};
+use ic_file_uploader::hooks::CanisterCall;
+use ic_file_uploader::interface::{MethodKind, ServiceInterface};
+use ic_file_uploader::install::{install_chunked_wasm, InstallConfig, InstallMode, WASM_CHUNK_SIZE};
+use ic_file_uploader::journal::{journal_path, UploadJournal, DEFAULT_JOURNAL_DIR};
+use ic_file_uploader::transport::{DfxTransport, Transport};
+use ic_file_uploader::progress::{ProgressEvent, SharedObserver, UploadReport};
+use ic_file_uploader::ratelimit::DEFAULT_BURST_MIB;
+use ic_file_uploader::reply::{ReplyPolicy, ReplyShape};
+use ic_file_uploader::retry::RetryPolicy;
+use ic_file_uploader::resume::{query_remote_state, RemoteState};
+use ic_file_uploader::session::{
+    begin_upload, commit_upload, encode_begin_upload_args, encode_put_chunk_args, SessionId,
+    BEGIN_UPLOAD_METHOD, COMMIT_UPLOAD_METHOD,
+};
 use ic_file_uploader::parallel::{
-    upload_chunks_parallel, chunks_to_chunk_info, ParallelUploadConfig, ParallelUploadResult
+    encode_chunk_with_id_args, upload_chunks_parallel, DispatchOrder, ParallelUploadConfig, ParallelUploadResult
 };
+use ic_file_uploader::source::{ChunkSource, MmapChunkSource, ReaderChunkSource};
+use ic_file_uploader::template::{ArgTemplate, ChunkContext};
+use ic_file_uploader::verify::verify_upload;
 
-/// Command line arguments for the ic-file-uploader
+/// Command line interface of the ic-file-uploader
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    /// Subcommand to run instead of an upload
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Arguments of an upload, the default command
+    #[command(flatten)]
+    upload: Args,
+}
+
+/// Subcommands besides the default upload
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download a file stored in a canister through a ranged read method
+    Download(DownloadArgs),
+    /// Upload files to an asset canister through the create_batch / create_chunk / commit_batch protocol
+    Assets(AssetsArgs),
+    /// Install a Wasm module of any size through the management canister's chunk store
+    InstallWasm(InstallWasmArgs),
+}
+
+/// Command line arguments for an upload
+#[derive(Parser, Debug, Clone, PartialEq)]
 struct Args {
     /// Name of the canister
     //#[arg(short, long)]
@@ -53,6 +102,22 @@ struct Args {
     #[arg(long, default_value = "3")]
     max_retries: usize,
 
+    /// Delay before the first retry of a chunk in milliseconds; later retries back off exponentially (default: 1000)
+    #[arg(long, default_value = "1000")]
+    retry_delay: u64,
+
+    /// Longest delay between two attempts of a chunk in milliseconds (default: 30000)
+    #[arg(long, default_value = "30000")]
+    max_retry_delay: u64,
+
+    /// Stop retrying a chunk this many seconds after its first attempt
+    #[arg(long, value_name = "SECS")]
+    max_retry_time: Option<u64>,
+
+    /// Stop starting chunks after this many seconds; the journal keeps the progress for the next run
+    #[arg(long, value_name = "SECS")]
+    max_duration: Option<u64>,
+
     /// Enable parallel uploads (experimental)
     #[arg(long)]
     parallel: bool,
@@ -61,57 +126,1158 @@ struct Args {
     #[arg(long, default_value = "4")]
     max_concurrent: usize,
 
-    /// Target upload rate in MiB/s for parallel mode (default: 4.0)
+    /// Concurrent uploads to start parallel mode with; the limit adapts between this and --max-concurrent (default: 1)
+    #[arg(long, default_value = "1")]
+    min_concurrent: usize,
+
+    /// Order in which parallel mode dispatches chunks (default: ascending)
+    #[arg(long, value_enum, default_value = "ascending", conflicts_with = "pipelined")]
+    dispatch_order: DispatchOrderKind,
+
+    /// Send chunks as `(blob)` one at a time in order while preparing up to --max-concurrent ahead, for append-only methods
+    #[arg(long, conflicts_with = "parallel")]
+    pipelined: bool,
+
+    /// Target upload rate in MiB/s in both modes; 0 for no limit (default: 4.0)
     #[arg(long, default_value = "4.0")]
     target_rate: f64,
 
-    /// Retry only specific chunk IDs from a file (comma-separated)
+    /// Largest burst above the target rate in MiB, e.g. after an idle period (default: 4.0)
+    #[arg(long, default_value_t = DEFAULT_BURST_MIB)]
+    burst: f64,
+
+    /// Upload only the pending chunks of a resume file written by an upload that did not complete
     #[arg(long)]
     retry_chunks_file: Option<String>,
+
+    /// Ask the canister which chunks it already holds through this query method
+    /// (e.g. parallel_chunk_ids or buffer_size) and upload only the missing ones
+    #[arg(long, value_name = "QUERY_METHOD", conflicts_with = "retry_chunks_file")]
+    resume_from_canister: Option<String>,
+
+    /// How canister calls are submitted (default: dfx)
+    #[arg(long, value_enum, default_value = "dfx")]
+    transport: TransportKind,
+
+    /// PEM identity file used to sign calls with the native transport (optional)
+    #[arg(long)]
+    identity_pem: Option<String>,
+
+    /// Seconds a canister call may take before it is cancelled; 0 to wait forever (default: 300)
+    #[arg(long, value_name = "SECS", default_value = "300")]
+    call_timeout: u64,
+
+    /// Memory-map the file instead of reading chunks through buffered I/O
+    #[arg(long)]
+    mmap: bool,
+
+    /// Directory the upload journal is kept in
+    #[arg(long, default_value = DEFAULT_JOURNAL_DIR)]
+    journal_dir: String,
+
+    /// Do not record or resume from an upload journal
+    #[arg(long)]
+    no_journal: bool,
+
+    /// Discard any existing journal and upload every chunk again
+    #[arg(long)]
+    fresh: bool,
+
+    /// Upload through a begin_upload / put_chunk / commit_upload session;
+    /// the canister method is called to put each chunk
+    #[arg(long)]
+    session: bool,
+
+    /// Continue an existing upload session instead of beginning a new one
+    #[arg(long, requires = "session")]
+    session_id: Option<SessionId>,
+
+    /// Method called before the first chunk of a fresh upload (e.g. clear_parallel_chunks)
+    #[arg(long)]
+    prepare_method: Option<String>,
+
+    /// Method called once every chunk succeeded (e.g. save_parallel_to_stable)
+    #[arg(long)]
+    finalize_method: Option<String>,
+
+    /// Candid argument of the finalize method, e.g. '("model")' (default: no arguments)
+    #[arg(long, requires = "finalize_method")]
+    finalize_arg: Option<String>,
+
+    /// Query returning the SHA-256 of the uploaded data (blob) or of every chunk (vec blob),
+    /// compared against the local file after the upload (e.g. sha256 or chunk_hashes)
+    #[arg(long)]
+    verify_method: Option<String>,
+
+    /// Candid argument of the verify method, e.g. '("model")' (default: no arguments)
+    #[arg(long, requires = "verify_method")]
+    verify_arg: Option<String>,
+
+    /// Candid argument of every chunk call with placeholders {name}, {index}, {offset}, {total},
+    /// {size}, {hash} and {blob}, e.g. '("{name}", {offset} : nat64, {blob})'
+    #[arg(long, conflicts_with = "session")]
+    arg_template: Option<String>,
+
+    /// Candid interface (.did file) the methods are checked against before uploading
+    /// (default: the canister's candid:service metadata)
+    #[arg(long, value_name = "DID_FILE")]
+    candid: Option<String>,
+
+    /// Do not check the methods against the canister's Candid interface before uploading
+    #[arg(long, conflicts_with = "candid")]
+    skip_interface_check: bool,
+
+    /// Count chunks whose reply is `variant { Err = ... }` or `variant { Error = ... }` as uploaded
+    #[arg(long)]
+    accept_err_replies: bool,
+
+    /// Count chunks whose reply is `false` as failed
+    #[arg(long)]
+    fail_on_false: bool,
+
+    /// Reply shape that means a chunk succeeded; repeat to allow several (default: any reply that is not an error)
+    #[arg(long, value_enum)]
+    success_reply: Vec<ReplyShapeKind>,
+}
+
+/// Command line arguments for a download
+#[derive(clap::Args, Debug)]
+struct DownloadArgs {
+    /// Name of the canister
+    canister_name: String,
+
+    /// Key the file is stored under
+    key: String,
+
+    /// Path the file is written to
+    output_path: String,
+
+    /// Query method returning one piece of the file
+    #[arg(long, default_value = "get_chunk")]
+    read_method: String,
+
+    /// How the read method addresses pieces: `(key, index)` or `(key, offset, len)`
+    #[arg(long, value_enum, default_value = "chunk")]
+    read_mode: ReadModeKind,
+
+    /// Query method returning the file size, called with the key
+    #[arg(long, default_value = "size")]
+    size_method: String,
+
+    /// Size of each piece in bytes; must match the canister's chunking in chunk mode
+    #[arg(long, default_value_t = MAX_CANISTER_HTTP_PAYLOAD_SIZE)]
+    chunk_size: usize,
+
+    /// Maximum concurrent reads (default: 4)
+    #[arg(long, default_value = "4")]
+    max_concurrent: usize,
+
+    /// Maximum retry attempts per piece (default: 3)
+    #[arg(long, default_value = "3")]
+    max_retries: usize,
+
+    /// Network type (optional)
+    #[arg(short, long)]
+    network: Option<String>,
+
+    /// How canister calls are submitted (default: dfx)
+    #[arg(long, value_enum, default_value = "dfx")]
+    transport: TransportKind,
+
+    /// PEM identity file used to sign calls with the native transport (optional)
+    #[arg(long)]
+    identity_pem: Option<String>,
+
+    /// Seconds a canister call may take before it is cancelled; 0 to wait forever (default: 300)
+    #[arg(long, value_name = "SECS", default_value = "300")]
+    call_timeout: u64,
+
+    /// Query returning the SHA-256 of the stored file (e.g. sha256), compared after the download
+    #[arg(long)]
+    verify_method: Option<String>,
+
+    /// Candid argument of the verify method (default: the key)
+    #[arg(long, requires = "verify_method")]
+    verify_arg: Option<String>,
+}
+
+/// Command line arguments for an asset canister upload
+#[derive(clap::Args, Debug)]
+struct AssetsArgs {
+    /// Name of the asset canister
+    canister_name: String,
+
+    /// Files to upload; the files below a directory keep their path relative to it
+    #[arg(required = true)]
+    paths: Vec<String>,
+
+    /// Asset key of a single uploaded file (default: the prefix followed by the file name)
+    #[arg(long)]
+    key: Option<String>,
+
+    /// Prefix of the asset keys (default: /)
+    #[arg(long, default_value = "/")]
+    prefix: String,
+
+    /// Content type of every uploaded file (default: guessed from the extension)
+    #[arg(long)]
+    content_type: Option<String>,
+
+    /// Size of each chunk in bytes
+    #[arg(long, default_value_t = MAX_CANISTER_HTTP_PAYLOAD_SIZE)]
+    chunk_size: usize,
+
+    /// Maximum concurrent chunk uploads (default: 4)
+    #[arg(long, default_value = "4")]
+    max_concurrent: usize,
+
+    /// Concurrent chunk uploads to start with; the limit adapts between this and --max-concurrent (default: 1)
+    #[arg(long, default_value = "1")]
+    min_concurrent: usize,
+
+    /// Target upload rate in MiB/s; 0 for no limit (default: 4.0)
+    #[arg(long, default_value = "4.0")]
+    target_rate: f64,
+
+    /// Largest burst above the target rate in MiB (default: 4.0)
+    #[arg(long, default_value_t = DEFAULT_BURST_MIB)]
+    burst: f64,
+
+    /// Maximum retry attempts per chunk (default: 3)
+    #[arg(long, default_value = "3")]
+    max_retries: usize,
+
+    /// Network type (optional)
+    #[arg(short, long)]
+    network: Option<String>,
+
+    /// How canister calls are submitted (default: dfx)
+    #[arg(long, value_enum, default_value = "dfx")]
+    transport: TransportKind,
+
+    /// PEM identity file used to sign calls with the native transport (optional)
+    #[arg(long)]
+    identity_pem: Option<String>,
+
+    /// Seconds a canister call may take before it is cancelled; 0 to wait forever (default: 300)
+    #[arg(long, value_name = "SECS", default_value = "300")]
+    call_timeout: u64,
+}
+
+/// Command line arguments for a chunked Wasm install
+#[derive(clap::Args, Debug)]
+struct InstallWasmArgs {
+    /// Name or principal of the canister to install the module in
+    canister_name: String,
+
+    /// Path to the Wasm module (optionally gzipped)
+    wasm_path: String,
+
+    /// How the module is installed (default: install)
+    #[arg(long, value_enum, default_value = "install")]
+    mode: InstallModeKind,
+
+    /// Candid init or upgrade argument, e.g. '(record { owner = principal "aaaaa-aa" })' (default: no arguments)
+    #[arg(long)]
+    arg: Option<String>,
+
+    /// Size of each chunk in bytes, at most 1 MiB
+    #[arg(long, default_value_t = WASM_CHUNK_SIZE)]
+    chunk_size: usize,
+
+    /// Clear the chunk store first instead of resuming from the chunks it holds
+    #[arg(long)]
+    fresh: bool,
+
+    /// Leave the uploaded chunks in the chunk store after installing
+    #[arg(long)]
+    keep_chunks: bool,
+
+    /// Maximum concurrent chunk uploads (default: 4)
+    #[arg(long, default_value = "4")]
+    max_concurrent: usize,
+
+    /// Concurrent chunk uploads to start with; the limit adapts between this and --max-concurrent (default: 1)
+    #[arg(long, default_value = "1")]
+    min_concurrent: usize,
+
+    /// Target upload rate in MiB/s; 0 for no limit (default: 4.0)
+    #[arg(long, default_value = "4.0")]
+    target_rate: f64,
+
+    /// Largest burst above the target rate in MiB (default: 4.0)
+    #[arg(long, default_value_t = DEFAULT_BURST_MIB)]
+    burst: f64,
+
+    /// Maximum retry attempts per chunk (default: 3)
+    #[arg(long, default_value = "3")]
+    max_retries: usize,
+
+    /// Network type (optional)
+    #[arg(short, long)]
+    network: Option<String>,
+
+    /// How canister calls are submitted (default: dfx)
+    #[arg(long, value_enum, default_value = "dfx")]
+    transport: TransportKind,
+
+    /// PEM identity file used to sign calls with the native transport (optional)
+    #[arg(long)]
+    identity_pem: Option<String>,
+
+    /// Seconds a canister call may take before it is cancelled; 0 to wait forever (default: 300)
+    #[arg(long, value_name = "SECS", default_value = "300")]
+    call_timeout: u64,
+}
+
+/// Reply shapes selectable from the command line
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ReplyShapeKind {
+    /// No reply values, `()`
+    Empty,
+    /// `variant { Ok }`, with or without a payload
+    Ok,
+    /// `true`
+    True,
+    /// An `opt` with a value
+    Some,
+    /// Any number
+    Number,
+    /// Any text
+    Text,
+}
+
+/// Chunk dispatch orders selectable from the command line
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DispatchOrderKind {
+    /// Lowest chunk ID first
+    Ascending,
+    /// Highest chunk ID first
+    Descending,
+}
+
+/// Install modes selectable from the command line
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum InstallModeKind {
+    /// Install into an empty canister
+    Install,
+    /// Replace the code and discard the canister state
+    Reinstall,
+    /// Replace the code and keep the canister state
+    Upgrade,
+}
+
+/// Read modes selectable from the command line
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ReadModeKind {
+    /// `read_method(key, index)`
+    Chunk,
+    /// `read_method(key, offset, len)`
+    Range,
+}
+
+/// Transports selectable from the command line
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TransportKind {
+    /// Shell out to `dfx canister call` for every chunk
+    Dfx,
+    /// Submit calls in-process through `ic-agent`
+    Native,
+}
+
+/// Creates the transport selected on the command line
+fn build_transport(
+    kind: TransportKind,
+    network: Option<&str>,
+    identity_pem: Option<&str>,
+    call_timeout: u64,
+    cancellation: Option<&CancellationToken>,
+) -> Result<Box<dyn Transport>, UploadError> {
+    let timeout = (call_timeout > 0).then(|| Duration::from_secs(call_timeout));
+    Ok(match kind {
+        TransportKind::Dfx => Box::new(
+            DfxTransport::new(network)
+                .with_timeout(timeout)
+                .with_cancellation(cancellation.cloned()),
+        ),
+        TransportKind::Native => Box::new(
+            NativeAgent::new(network, identity_pem.map(Path::new))?
+                .with_timeout(timeout)
+                .with_cancellation(cancellation.cloned()),
+        ),
+    })
+}
+
+/// Tokens cancelled by the first and the second interrupt of an upload
+static INTERRUPT_TOKENS: OnceLock<(CancellationToken, CancellationToken)> = OnceLock::new();
+
+/// The token cancelled by the first interrupt, which stops dispatching chunks,
+/// and the one cancelled by the second, which kills the calls in flight
+fn interrupt_tokens() -> (CancellationToken, CancellationToken) {
+    INTERRUPT_TOKENS.get_or_init(|| (CancellationToken::new(), CancellationToken::new())).clone()
+}
+
+/// Handles SIGINT and SIGTERM from now on by cancelling the [`interrupt_tokens`]
+/// one after the other; a third interrupt exits at once.
+fn handle_interrupts() {
+    interrupt_tokens();
+
+    #[cfg(unix)]
+    {
+        let handler = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SAFETY: the handler only stores to atomics and calls write(2) and _exit(2)
+        unsafe {
+            libc::signal(libc::SIGINT, handler);
+            libc::signal(libc::SIGTERM, handler);
+        }
+    }
+}
+
+/// Signal handler escalating from stopping the upload to exiting
+#[cfg(unix)]
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    let Some((stop, kill)) = INTERRUPT_TOKENS.get() else {
+        return;
+    };
+
+    let message: &[u8] = if !stop.is_cancelled() {
+        stop.cancel();
+        "\n⏸ Stopping after the chunks in flight; interrupt again to cancel them\n".as_bytes()
+    } else if !kill.is_cancelled() {
+        kill.cancel();
+        "\n⏹ Cancelling the chunks in flight\n".as_bytes()
+    } else {
+        // SAFETY: _exit(2) is async-signal-safe
+        unsafe { libc::_exit(130) }
+    };
+
+    // SAFETY: write(2) is async-signal-safe and the message is a static buffer
+    unsafe {
+        libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len());
+    }
+}
+
+/// Prints the progress of a transfer, numbering chunks out of `total` when it is
+/// known and reporting each completed chunk as `done` (e.g. "Uploaded")
+fn progress_printer(total: Option<usize>, done: &'static str) -> SharedObserver {
+    let label = move |chunk_id: u32| match total {
+        Some(total) => format!("Chunk {}/{}", chunk_id + 1, total),
+        None => format!("Chunk ID {}", chunk_id),
+    };
+
+    SharedObserver::new(move |event: &ProgressEvent| match event {
+        ProgressEvent::Started { chunks, workers } => println!("Starting {} chunks with {} workers", chunks, workers),
+        ProgressEvent::ChunkSucceeded { chunk_id, size, attempts, latency } => {
+            let retried = if *attempts > 1 { format!(" after {} attempts", attempts) } else { String::new() };
+            println!("{}: ✓ {}{} ({} bytes in {:.2}s)", label(*chunk_id), done, retried, size, latency.as_secs_f64());
+        }
+        ProgressEvent::ChunkRetry { chunk_id, attempt, max_attempts, delay, error } => {
+            println!("{}: ⚠ Attempt {}/{} failed, retrying in {:.1}s: {}",
+                     label(*chunk_id), attempt, max_attempts, delay.as_secs_f64(), error);
+        }
+        ProgressEvent::ChunkFailed { chunk_id, error } => println!("{}: ✗ {}", label(*chunk_id), error),
+        ProgressEvent::JournalFailed { chunk_id, error } => {
+            println!("{}: ⚠ Not recorded in the journal, a resumed upload sends it again: {}", label(*chunk_id), error);
+        }
+        // Only print once there is meaningful data
+        ProgressEvent::RateSample { rate_mibs, concurrency } if *rate_mibs > 0.1 => {
+            print!("\rCurrent rate: {:.2} MiB/s, concurrency {}", rate_mibs, concurrency);
+            std::io::Write::flush(&mut std::io::stdout()).unwrap();
+        }
+        ProgressEvent::Finished { report } => {
+            println!("\n{} {:.2} MiB at {:.2} MiB/s", done, report.bytes_uploaded as f64 / (1024.0 * 1024.0), report.rate_mibs());
+        }
+        ProgressEvent::SessionCommitted { session_id } => println!("✓ Committed upload session {}", session_id),
+        ProgressEvent::HookSucceeded { stage, method, reply } => println!("✓ {} {}: {}", stage, method, reply),
+        ProgressEvent::Verified { sha256, method } => {
+            println!("✓ Verified SHA-256 {} with {}", hex::encode(sha256), method);
+        }
+        _ => {}
+    })
+}
+
+/// The command line arguments of an upload with these arguments, leaving out
+/// the options at their default so the printed command stays short
+fn upload_flags(args: &Args) -> Vec<String> {
+    // Destructured so a new option cannot be left out
+    let Args {
+        canister_name, canister_method, file_path, offset, chunk_offset, network, autoresume, max_retries,
+        retry_delay, max_retry_delay, max_retry_time, max_duration, parallel, max_concurrent, min_concurrent,
+        dispatch_order, pipelined, target_rate, burst, retry_chunks_file, resume_from_canister, transport,
+        identity_pem, call_timeout, mmap, journal_dir, no_journal, fresh, session, session_id, prepare_method,
+        finalize_method, finalize_arg, verify_method, verify_arg, arg_template, candid, skip_interface_check,
+        accept_err_replies, fail_on_false, success_reply,
+    } = args;
+    let defaults = Args::parse_from(["ic-file-uploader", "canister", "method", "file"]);
+
+    let mut flags = vec![canister_name.clone(), canister_method.clone(), file_path.clone()];
+    let mut option = |name: &str, value: Option<String>| {
+        if let Some(value) = value {
+            flags.push(format!("--{}", name));
+            flags.push(value);
+        }
+    };
+    let changed = |value: String, default: String| (value != default).then_some(value);
+
+    option("offset", changed(offset.to_string(), defaults.offset.to_string()));
+    option("chunk-offset", changed(chunk_offset.to_string(), defaults.chunk_offset.to_string()));
+    option("network", network.clone());
+    option("max-retries", changed(max_retries.to_string(), defaults.max_retries.to_string()));
+    option("retry-delay", changed(retry_delay.to_string(), defaults.retry_delay.to_string()));
+    option("max-retry-delay", changed(max_retry_delay.to_string(), defaults.max_retry_delay.to_string()));
+    option("max-retry-time", max_retry_time.map(|secs| secs.to_string()));
+    option("max-duration", max_duration.map(|secs| secs.to_string()));
+    option("max-concurrent", changed(max_concurrent.to_string(), defaults.max_concurrent.to_string()));
+    option("min-concurrent", changed(min_concurrent.to_string(), defaults.min_concurrent.to_string()));
+    option("dispatch-order", changed(value_name(*dispatch_order), value_name(defaults.dispatch_order)));
+    option("target-rate", changed(target_rate.to_string(), defaults.target_rate.to_string()));
+    option("burst", changed(burst.to_string(), defaults.burst.to_string()));
+    option("retry-chunks-file", retry_chunks_file.clone());
+    option("resume-from-canister", resume_from_canister.clone());
+    option("transport", changed(value_name(*transport), value_name(defaults.transport)));
+    option("identity-pem", identity_pem.clone());
+    option("call-timeout", changed(call_timeout.to_string(), defaults.call_timeout.to_string()));
+    option("journal-dir", changed(journal_dir.clone(), defaults.journal_dir));
+    option("session-id", session_id.map(|id| id.to_string()));
+    option("prepare-method", prepare_method.clone());
+    option("finalize-method", finalize_method.clone());
+    option("finalize-arg", finalize_arg.clone());
+    option("verify-method", verify_method.clone());
+    option("verify-arg", verify_arg.clone());
+    option("arg-template", arg_template.clone());
+    option("candid", candid.clone());
+    for shape in success_reply {
+        option("success-reply", Some(value_name(*shape)));
+    }
+
+    let switches = [
+        ("autoresume", autoresume),
+        ("parallel", parallel),
+        ("pipelined", pipelined),
+        ("mmap", mmap),
+        ("no-journal", no_journal),
+        ("fresh", fresh),
+        ("session", session),
+        ("skip-interface-check", skip_interface_check),
+        ("accept-err-replies", accept_err_replies),
+        ("fail-on-false", fail_on_false),
+    ];
+    flags.extend(switches.into_iter().filter(|(_, on)| **on).map(|(name, _)| format!("--{}", name)));
+    flags
+}
+
+/// The command that runs an upload with these arguments, quoted for a POSIX shell
+fn upload_command(args: &Args) -> String {
+    let quote = |arg: String| {
+        if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=,@+%".contains(c)) {
+            arg
+        } else {
+            format!("'{}'", arg.replace('\'', "'\\''"))
+        }
+    };
+    std::iter::once("ic-file-uploader".to_string())
+        .chain(upload_flags(args).into_iter().map(quote))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The arguments that continue this upload: in the same session, and without
+/// discarding the journal again
+fn continuation(args: &Args, session_id: Option<SessionId>) -> Args {
+    Args {
+        fresh: false,
+        session: args.session || session_id.is_some(),
+        session_id: session_id.or(args.session_id),
+        ..args.clone()
+    }
+}
+
+/// The command line spelling of a value enum
+fn value_name(value: impl ValueEnum) -> String {
+    value.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default()
+}
+
+/// Opens (or starts) the journal for this upload
+fn open_journal(args: &Args, params: &UploadParams) -> Result<UploadJournal, UploadError> {
+    let journal_dir = Path::new(&args.journal_dir);
+    let file_path = Path::new(&args.file_path);
+    let chunk_size = MAX_CANISTER_HTTP_PAYLOAD_SIZE;
+
+    if args.fresh {
+        let path = journal_path(journal_dir, file_path, params, chunk_size, args.offset as u64);
+        if path.exists() {
+            std::fs::remove_file(&path).map_err(|e| UploadError::io(format!("Failed to remove journal {}", path.display()), e))?;
+        }
+    }
+
+    let journal = UploadJournal::open_or_create(journal_dir, file_path, params, chunk_size, args.offset as u64)?;
+    if !journal.acknowledged.is_empty() {
+        println!("📒 Resuming from journal {}: {} chunks already acknowledged",
+                 journal.path().display(), journal.acknowledged.len());
+    }
+
+    Ok(journal)
 }
 
-/// Progress callback function for upload status
-fn progress_callback(current: usize, total: usize, status: &str) {
-    println!("Chunk {}/{}: {}", current, total, status);
+/// Whether the journal records a chunk as acknowledged
+fn is_acknowledged(journal: &Option<Arc<Mutex<UploadJournal>>>, chunk_id: u32) -> bool {
+    journal.as_ref().is_some_and(|journal| journal.lock().unwrap().is_acknowledged(chunk_id))
 }
 
-/// Progress callback function for parallel upload status
-fn parallel_progress_callback(chunk_id: u32, size: usize, status: &str) {
-    println!("Chunk ID {}: {} ({} bytes)", chunk_id, status, size);
+/// Tells the user that re-running the command resumes from the journal
+fn print_journal_hint(journal: &Option<Arc<Mutex<UploadJournal>>>) {
+    if let Some(journal) = journal {
+        println!("📒 Progress saved to {}; re-running the same command resumes automatically.",
+                 journal.lock().unwrap().path().display());
+    }
+}
+
+/// The chunks of an upload that did not complete, written to `<file_path>.resume`
+/// so `--retry-chunks-file` continues with the pending ones
+#[derive(Debug, PartialEq)]
+struct ResumeFile {
+    /// Chunks already uploaded
+    completed: Vec<u32>,
+    /// Chunks still to upload
+    pending: Vec<u32>,
 }
 
-/// Rate monitoring callback for parallel uploads
-fn rate_callback(current_rate: f64) {
-    if current_rate > 0.1 {  // Only print if we have meaningful data
-        print!("\rCurrent rate: {:.2} MiB/s", current_rate);
-        std::io::Write::flush(&mut std::io::stdout()).unwrap();
+impl ResumeFile {
+    /// Splits the chunks of a file into the pending ones and the rest
+    fn new(chunk_count: usize, mut pending: Vec<u32>) -> Self {
+        pending.sort();
+        let completed = (0..chunk_count as u32).filter(|id| pending.binary_search(id).is_err()).collect();
+        ResumeFile { completed, pending }
+    }
+
+    /// Reads a resume file; a bare list of chunk IDs, as written by older
+    /// versions, lists the pending chunks
+    fn read(path: &str) -> Result<Self, UploadError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| UploadError::io(format!("Failed to read retry chunks file {}", path), e))?;
+        Self::parse(&text).map_err(|e| UploadError::Other(format!("Failed to parse chunk IDs from {}: {}", path, e)))
+    }
+
+    /// Parses the `completed:` and `pending:` lines of a resume file
+    fn parse(text: &str) -> Result<Self, String> {
+        let ids = |list: &str| -> Result<Vec<u32>, String> {
+            list.split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(|id| id.parse::<u32>().map_err(|e| format!("{}: {}", id, e)))
+                .collect()
+        };
+
+        let mut resume = ResumeFile { completed: Vec::new(), pending: Vec::new() };
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            match line.split_once(':') {
+                Some(("completed", list)) => resume.completed = ids(list)?,
+                Some(("pending", list)) => resume.pending = ids(list)?,
+                Some((key, _)) => return Err(format!("unknown entry {}", key)),
+                None => resume.pending = ids(line)?,
+            }
+        }
+        Ok(resume)
+    }
+}
+
+impl std::fmt::Display for ResumeFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let list = |ids: &[u32]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(",");
+        writeln!(f, "completed: {}", list(&self.completed))?;
+        writeln!(f, "pending: {}", list(&self.pending))
+    }
+}
+
+/// Prints the chunks a parallel upload did not upload and writes the resume file
+fn write_failed_chunks(args: &Args, session_id: Option<SessionId>, report: &UploadReport, chunk_count: usize) {
+    let failed_ids = report.failed_chunk_ids();
+    println!("✗ Failed chunks: {:?}", failed_ids);
+    if let Some(first) = failed_ids.first() {
+        println!("  First error: {}", report.failed_chunks[first]);
+    }
+
+    write_resume_file(&ResumeFile::new(chunk_count, failed_ids), continuation(args, session_id));
+}
+
+/// Writes the completed and pending chunks to `<file_path>.resume` and prints
+/// the command that continues the upload with the given arguments from there
+fn write_resume_file(resume: &ResumeFile, continued: Args) {
+    let resume_path = format!("{}.resume", continued.file_path);
+
+    match std::fs::write(&resume_path, resume.to_string()) {
+        Ok(()) => {
+            println!("\n📝 Completed and pending chunk IDs written to: {}", resume_path);
+            println!("To continue the upload, run:");
+            println!("{}", upload_command(&Args {
+                chunk_offset: 0,
+                retry_chunks_file: Some(resume_path),
+                resume_from_canister: None,
+                ..continued
+            }));
+        }
+        Err(e) => {
+            println!("⚠ Could not write resume file {}: {}", resume_path, e);
+            print!("Chunk IDs\n{}", resume);
+        }
+    }
+}
+
+/// The failure a partially failed parallel upload exits with: the fatal error
+/// if there was one, otherwise the first chunk that was attempted and failed
+fn first_failure(mut report: UploadReport) -> UploadError {
+    if let Some(fatal_error) = report.fatal_error {
+        return fatal_error;
+    }
+
+    let failed_ids = report.failed_chunk_ids();
+    let attempted = failed_ids
+        .iter()
+        .find(|id| !matches!(report.failed_chunks[id], UploadError::NotAttempted(_)))
+        .or(failed_ids.first());
+    attempted
+        .and_then(|id| report.failed_chunks.remove(id))
+        .unwrap_or_else(|| UploadError::Other("Some chunks failed to upload".to_string()))
+}
+
+/// Continues the session given on the command line or recorded in the journal,
+/// or begins a new one
+fn open_session(
+    args: &Args,
+    transport: &dyn Transport,
+    params: &UploadParams,
+    source: &dyn ChunkSource,
+    journal: &Option<Arc<Mutex<UploadJournal>>>,
+) -> Result<SessionId, UploadError> {
+    let recorded = journal.as_ref().and_then(|journal| journal.lock().unwrap().session_id);
+
+    let session_id = match args.session_id.or(recorded) {
+        Some(session_id) => {
+            println!("🔗 Continuing upload session {}", session_id);
+            session_id
+        }
+        None => {
+            let session_id = begin_upload(transport, params, params.name, source)?;
+            println!("🔗 Began upload session {} for {}", session_id, params.name);
+            session_id
+        }
+    };
+
+    if let Some(journal) = journal {
+        journal.lock().unwrap().record_session(session_id)?;
+    }
+
+    Ok(session_id)
+}
+
+/// Builds the backoff used between attempts of a chunk
+fn retry_policy(args: &Args) -> RetryPolicy {
+    RetryPolicy {
+        base_delay: Duration::from_millis(args.retry_delay),
+        max_delay: Duration::from_millis(args.max_retry_delay.max(args.retry_delay)),
+        max_elapsed: args.max_retry_time.map(Duration::from_secs),
+        ..Default::default()
+    }
+}
+
+/// Builds the policy deciding which chunk replies count as success
+fn reply_policy(args: &Args) -> ReplyPolicy {
+    ReplyPolicy {
+        fail_on_err: !args.accept_err_replies,
+        fail_on_false: args.fail_on_false,
+        success_shapes: args
+            .success_reply
+            .iter()
+            .map(|shape| match shape {
+                ReplyShapeKind::Empty => ReplyShape::Empty,
+                ReplyShapeKind::Ok => ReplyShape::Ok,
+                ReplyShapeKind::True => ReplyShape::True,
+                ReplyShapeKind::Some => ReplyShape::Some,
+                ReplyShapeKind::Number => ReplyShape::Number,
+                ReplyShapeKind::Text => ReplyShape::Text,
+            })
+            .collect(),
+    }
+}
+
+/// Checks every method the upload calls against the canister's Candid interface,
+/// so a signature mismatch fails once instead of on every chunk
+fn check_interface(
+    args: &Args,
+    transport: &dyn Transport,
+    arg_template: Option<&ArgTemplate>,
+    finalize: &Option<CanisterCall>,
+    verify: &Option<CanisterCall>,
+) -> Result<(), UploadError> {
+    let service = match &args.candid {
+        Some(path) => ServiceInterface::from_file(Path::new(path))?,
+        None => match ServiceInterface::fetch(transport, &args.canister_name) {
+            Ok(service) => service,
+            Err(e) => {
+                println!("⚠ Skipping interface check: {}", e);
+                return Ok(());
+            }
+        },
+    };
+
+    // A one-byte chunk in the shape the selected mode sends
+    let sample_chunk = match (arg_template, args.session, args.parallel) {
+        (Some(template), _, _) => template.render(&ChunkContext {
+            name: "sample",
+            index: 0,
+            offset: 0,
+            total: 1,
+            data: &[0],
+        })?,
+        (None, true, _) => encode_put_chunk_args(0, 0, &[0])?,
+        (None, false, true) => encode_chunk_with_id_args(0, &[0])?,
+        (None, false, false) => encode_blob_args(&[0])?,
+    };
+    service.check_method(&args.canister_method, MethodKind::Update, &sample_chunk)?;
+
+    if args.session {
+        service.check_method(BEGIN_UPLOAD_METHOD, MethodKind::Update, &encode_begin_upload_args("sample", 1, 1, &[0; 32])?)?;
+        service.check_method(COMMIT_UPLOAD_METHOD, MethodKind::Update, &Encode!(&0u64).map_err(|e| UploadError::Candid(e.to_string()))?)?;
+    }
+    if let Some(method) = &args.prepare_method {
+        service.check_method(method, MethodKind::Update, &CanisterCall::without_args(method).args)?;
+    }
+    if let Some(call) = finalize {
+        service.check_method(&call.method, MethodKind::Update, &call.args)?;
+    }
+    if let Some(call) = verify {
+        service.check_method(&call.method, MethodKind::Query, &call.args)?;
+    }
+    if let Some(method) = &args.resume_from_canister {
+        service.check_method(method, MethodKind::Query, &CanisterCall::without_args(method).args)?;
+    }
+
+    println!("✓ Methods match the canister's Candid interface");
+    Ok(())
+}
+
+/// Prints the totals of a parallel upload
+fn print_report(report: &UploadReport) {
+    println!("{:.2} MiB in {:.1}s ({:.2} MiB/s), {} retries",
+             report.bytes_uploaded as f64 / (1024.0 * 1024.0),
+             report.duration.as_secs_f64(),
+             report.rate_mibs(),
+             report.retries);
+}
+
+/// The prepare call, made only when every chunk is about to be uploaded so a
+/// resumed upload does not wipe the chunks already stored
+fn prepare_call(args: &Args, uploads_every_chunk: bool) -> Option<CanisterCall> {
+    let method = args.prepare_method.as_ref()?;
+    if !uploads_every_chunk {
+        println!("Skipping prepare method {}: resuming a partial upload", method);
+        return None;
+    }
+    Some(CanisterCall::without_args(method))
+}
+
+/// Commits the session and makes the finalize and verify calls for an upload
+/// that had no chunks left to send, like the uploaders do after the last chunk
+fn finalize_without_upload(
+    transport: &dyn Transport,
+    params: &UploadParams,
+    source: &dyn ChunkSource,
+    session_id: Option<SessionId>,
+    journal: &Option<Arc<Mutex<UploadJournal>>>,
+    finalize: &Option<CanisterCall>,
+    verify: &Option<CanisterCall>,
+) -> Result<(), UploadError> {
+    // A session committed by an earlier run whose finalize or verify failed is gone from the canister
+    let committed = journal.as_ref().is_some_and(|journal| journal.lock().unwrap().session_committed);
+    if let Some(session_id) = session_id.filter(|_| !committed) {
+        commit_upload(transport, params, session_id)?;
+        println!("✓ Committed upload session {}", session_id);
+        if let Some(journal) = journal {
+            journal.lock().unwrap().record_commit()?;
+        }
+    }
+    if let Some(call) = finalize {
+        let reply = call.invoke(transport, params.canister_name)?;
+        println!("✓ Finalize {}: {}", call.method, reply);
+    }
+    if let Some(hash_query) = verify {
+        let sha256 = verify_upload(transport, params, source, hash_query)?;
+        println!("✓ Verified SHA-256 {} with {}", hex::encode(sha256), hash_query.method);
+    }
+    Ok(())
+}
+
+/// Deletes the journal once every chunk has been uploaded
+fn remove_journal(journal: &Option<Arc<Mutex<UploadJournal>>>) {
+    if let Some(journal) = journal {
+        if let Err(e) = journal.lock().unwrap().remove() {
+            eprintln!("Warning: {}", e);
+        }
     }
 }
 
 /// The main function for the ic-file-uploader crate.
 ///
-/// This function parses command line arguments, reads the specified file,
-/// splits it into chunks, and uploads each chunk to the specified canister method.
-fn main() -> Result<(), String> {
-    let args = Args::parse();
+/// This function parses command line arguments and runs either an upload or
+/// the selected subcommand.
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Some(Command::Download(args)) => download(args),
+        Some(Command::Assets(args)) => assets(args),
+        Some(Command::InstallWasm(args)) => install_wasm(args),
+        None => upload(cli.upload),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(exit_code(&e))
+        }
+    }
+}
+
+/// The process exit code for a failed command, by what caused the failure.
+///
+/// Usage errors exit with 2, as reported by clap.
+fn exit_code(error: &UploadError) -> u8 {
+    match error.root_cause() {
+        UploadError::Other(_) | UploadError::Chunk { .. } | UploadError::Context { .. } => 1,
+        UploadError::Io { .. } => 3,
+        UploadError::Spawn(_) => 4,
+        UploadError::Rejected { .. } => 5,
+        UploadError::Timeout(_) => 6,
+        UploadError::Transport(_) => 7,
+        UploadError::Candid(_) => 8,
+        UploadError::Reply(_) => 9,
+        UploadError::Verification(_) => 10,
+        UploadError::NotAttempted(_) => 11,
+        UploadError::TimeLimit => 12,
+        UploadError::Cancelled => 130,
+    }
+}
+
+/// Fetches a file back from a canister piece by piece.
+fn download(args: DownloadArgs) -> Result<(), UploadError> {
+    let (stop, kill) = interrupt_tokens();
+    let transport = build_transport(args.transport, args.network.as_deref(), args.identity_pem.as_deref(), args.call_timeout, Some(&kill))?;
+
+    // The hash query is called with the key unless told otherwise
+    let verify = match (&args.verify_method, &args.verify_arg) {
+        (Some(method), Some(text)) => Some(CanisterCall::from_text(method, text)?),
+        (Some(method), None) => Some(CanisterCall::new(
+            method,
+            Encode!(&args.key).map_err(|e| UploadError::Candid(e.to_string()))?,
+        )),
+        (None, _) => None,
+    };
+
+    let config = DownloadConfig {
+        read_method: args.read_method.clone(),
+        read_mode: match args.read_mode {
+            ReadModeKind::Chunk => ReadMode::ChunkIndex,
+            ReadModeKind::Range => ReadMode::ByteRange,
+        },
+        size_method: args.size_method.clone(),
+        chunk_size: args.chunk_size,
+        max_concurrent: args.max_concurrent,
+        max_retries: args.max_retries,
+        retry_policy: RetryPolicy::default(),
+        observer: Some(progress_printer(None, "Downloaded")),
+        verify,
+        cancellation: Some(stop.clone()),
+    };
+
+    handle_interrupts();
+    println!("Downloading {} from {}", args.key, args.canister_name);
+
+    let summary = download_to_file(transport.as_ref(), &args.canister_name, &args.key, Path::new(&args.output_path), &config)?;
+    println!("✓ Downloaded {} bytes in {} chunks to {} (sha256 {})",
+             summary.bytes, summary.chunks, args.output_path, hex::encode(summary.sha256));
+    Ok(())
+}
+
+/// Uploads files to an asset canister in a single batch.
+fn assets(args: AssetsArgs) -> Result<(), UploadError> {
+    let (stop, kill) = interrupt_tokens();
+    let transport = build_transport(args.transport, args.network.as_deref(), args.identity_pem.as_deref(), args.call_timeout, Some(&kill))?;
+
+    let mut files = Vec::new();
+    for path in args.paths.iter().map(Path::new) {
+        // The contents of a directory given on the command line go directly under the prefix
+        if path.is_dir() {
+            for entry in directory_entries(path)? {
+                collect_asset_files(&entry, &args.prefix, &mut files)?;
+            }
+        } else {
+            collect_asset_files(path, &args.prefix, &mut files)?;
+        }
+    }
+
+    if let Some(key) = &args.key {
+        match files.as_mut_slice() {
+            [file] => file.key = key.clone(),
+            _ => return Err(UploadError::Other("--key can only be used when uploading a single file".to_string())),
+        }
+    }
+
+    if let Some(content_type) = &args.content_type {
+        files = files.into_iter().map(|file| file.with_content_type(content_type)).collect();
+    }
+
+    let config = ParallelUploadConfig {
+        max_concurrent: args.max_concurrent,
+        min_concurrent: args.min_concurrent,
+        target_rate_mibs: args.target_rate,
+        burst_mib: args.burst,
+        max_retries: args.max_retries,
+        observer: Some(progress_printer(None, "Uploaded")),
+        cancellation: Some(stop.clone()),
+        ..Default::default()
+    };
+
+    handle_interrupts();
+    for file in &files {
+        println!("Uploading {} as {} ({})", file.path.display(), file.key, file.content_type);
+    }
 
+    let summary = upload_assets(transport.as_ref(), &args.canister_name, &files, args.chunk_size, &config)?;
+    println!("✓ Committed batch {} with {} assets in {} chunks",
+             summary.batch_id, summary.assets, summary.chunks);
+    Ok(())
+}
+
+/// Installs a Wasm module through the chunk store of the target canister.
+fn install_wasm(args: InstallWasmArgs) -> Result<(), UploadError> {
+    let (stop, kill) = interrupt_tokens();
+    let transport = build_transport(args.transport, args.network.as_deref(), args.identity_pem.as_deref(), args.call_timeout, Some(&kill))?;
+    let canister_id = transport.canister_id(&args.canister_name)?;
+
+    let install = InstallConfig {
+        mode: match args.mode {
+            InstallModeKind::Install => InstallMode::Install,
+            InstallModeKind::Reinstall => InstallMode::Reinstall,
+            InstallModeKind::Upgrade => InstallMode::Upgrade,
+        },
+        arg: match &args.arg {
+            Some(text) => CanisterCall::from_text("install_chunked_code", text)?.args,
+            None => InstallConfig::default().arg,
+        },
+        chunk_size: args.chunk_size,
+        fresh: args.fresh,
+        keep_chunks: args.keep_chunks,
+    };
+
+    let config = ParallelUploadConfig {
+        max_concurrent: args.max_concurrent,
+        min_concurrent: args.min_concurrent,
+        target_rate_mibs: args.target_rate,
+        burst_mib: args.burst,
+        max_retries: args.max_retries,
+        observer: Some(progress_printer(None, "Uploaded")),
+        cancellation: Some(stop.clone()),
+        ..Default::default()
+    };
+
+    handle_interrupts();
+    println!("Installing {} in {}", args.wasm_path, canister_id);
+
+    let summary = install_chunked_wasm(transport.as_ref(), canister_id, Path::new(&args.wasm_path), &install, &config)?;
+    println!("✓ Installed {} in {} ({} chunks, {} uploaded, module hash {})",
+             args.wasm_path, canister_id, summary.chunks, summary.uploaded, hex::encode(summary.module_hash));
+    Ok(())
+}
+
+/// Adds a file, or every file below a directory, keyed by its path under `prefix`
+fn collect_asset_files(path: &Path, prefix: &str, files: &mut Vec<AssetFile>) -> Result<(), UploadError> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| UploadError::Other(format!("Invalid asset path {}", path.display())))?;
+    let key = format!("{}/{}", prefix.trim_end_matches('/'), name);
+
+    if path.is_dir() {
+        for entry in directory_entries(path)? {
+            collect_asset_files(&entry, &key, files)?;
+        }
+    } else {
+        files.push(AssetFile::new(path, &key));
+    }
+
+    Ok(())
+}
+
+/// Lists the entries of a directory in a stable order
+fn directory_entries(path: &Path) -> Result<Vec<PathBuf>, UploadError> {
+    let mut entries = std::fs::read_dir(path)
+        .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>, _>>())
+        .map_err(|e| UploadError::io(format!("Failed to read {}", path.display()), e))?;
+    entries.sort();
+    Ok(entries)
+}
+
+/// Opens the specified file as a chunk source and uploads each chunk to the
+/// specified canister method.
+fn upload(args: Args) -> Result<(), UploadError> {
     let bytes_path = Path::new(&args.file_path);
     println!("Uploading {}", args.file_path);
 
-    let model_data = fs::read(&bytes_path).map_err(|e| e.to_string())?;
+    // Chunks are read lazily, so only the chunks in flight are held in memory
+    let source: Box<dyn ChunkSource> = if args.mmap {
+        Box::new(MmapChunkSource::open(bytes_path, MAX_CANISTER_HTTP_PAYLOAD_SIZE, args.offset as u64)
+            .map_err(|e| UploadError::io(format!("Failed to open {}", args.file_path), e))?)
+    } else {
+        Box::new(ReaderChunkSource::open(bytes_path, MAX_CANISTER_HTTP_PAYLOAD_SIZE, args.offset as u64)
+            .map_err(|e| UploadError::io(format!("Failed to open {}", args.file_path), e))?)
+    };
+
+    // Parse the finalize argument up front so a typo fails before any chunk is sent
+    let finalize = match (&args.finalize_method, &args.finalize_arg) {
+        (Some(method), Some(text)) => Some(CanisterCall::from_text(method, text)?),
+        (Some(method), None) => Some(CanisterCall::without_args(method)),
+        (None, _) => None,
+    };
+    let verify = match (&args.verify_method, &args.verify_arg) {
+        (Some(method), Some(text)) => Some(CanisterCall::from_text(method, text)?),
+        (Some(method), None) => Some(CanisterCall::without_args(method)),
+        (None, _) => None,
+    };
+
+    let (stop, kill) = interrupt_tokens();
+    let transport = build_transport(args.transport, args.network.as_deref(), args.identity_pem.as_deref(), args.call_timeout, Some(&kill))?;
+
+    // Parse the template up front so a broken template fails before any chunk is sent
+    let arg_template = args.arg_template.as_deref().map(ArgTemplate::parse).transpose()?;
+    let reply_policy = reply_policy(&args);
+    let retry_policy = retry_policy(&args);
+
+    // The file name identifies the upload to the canister, e.g. as {name} in templates
+    let name = bytes_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| args.file_path.clone());
 
     // Create upload parameters
     let params = UploadParams {
-        name: &format!("{} file", args.canister_name),
+        name: &name,
         canister_name: &args.canister_name,
         canister_method: &args.canister_method,
         network: args.network.as_deref(),
     };
 
-    let model_chunks = split_into_chunks(model_data, MAX_CANISTER_HTTP_PAYLOAD_SIZE, args.offset);
+    if !args.skip_interface_check {
+        check_interface(&args, transport.as_ref(), arg_template.as_ref(), &finalize, &verify)?;
+    }
 
+    let journal = if args.no_journal {
+        None
+    } else {
+        Some(Arc::new(Mutex::new(open_journal(&args, &params)?)))
+    };
 
-    println!("Total chunks: {}", model_chunks.len());
+    println!("Total chunks: {}", source.chunk_count());
     if args.offset > 0 {
         println!("Starting from byte offset: {}", args.offset);
     }
@@ -122,109 +1288,142 @@ fn main() -> Result<(), String> {
         println!("Auto-resume enabled with {} max retries per chunk", args.max_retries);
     }
 
+    // A resume file left by an upload that did not complete lists the chunks still to upload
+    let resume = args.retry_chunks_file.as_deref().map(ResumeFile::read).transpose()?;
+    if let Some(resume) = &resume {
+        println!("Resuming with {} chunks pending, {} already completed", resume.pending.len(), resume.completed.len());
+    }
 
-    if args.parallel {
-        println!("🚀 Using parallel upload mode");
-        println!("Max concurrent: {}, Target rate: {:.1} MiB/s",
-                 args.max_concurrent, args.target_rate);
+    // The canister is the source of truth for what it holds, ahead of the journal
+    let remote_state = match &args.resume_from_canister {
+        Some(query_method) => {
+            let state = query_remote_state(transport.as_ref(), &params, query_method)?;
+            match &state {
+                RemoteState::ChunkIds(ids) => println!("🔎 Canister reports {} chunks already stored", ids.len()),
+                RemoteState::ByteCount(bytes) => println!("🔎 Canister reports {} bytes already stored", bytes),
+            }
+            Some(state)
+        }
+        None => None,
+    };
+
+    // Chunks of a session cannot be clobbered by other uploads to the same canister
+    let session_id = if args.session {
+        Some(open_session(&args, transport.as_ref(), &params, source.as_ref(), &journal)?)
+    } else {
+        None
+    };
+
+    // From here on an interrupt stops the upload at a chunk boundary, so it can be resumed
+    handle_interrupts();
+
+    if args.parallel || args.pipelined {
+        println!("🚀 Using {} upload mode", if args.pipelined { "pipelined" } else { "parallel" });
+        println!("Concurrency: {} to {}, Target rate: {:.1} MiB/s",
+                 args.min_concurrent, args.max_concurrent, args.target_rate);
 
         // Configure parallel upload
-        let config = ParallelUploadConfig {
+        let mut config = ParallelUploadConfig {
             max_concurrent: args.max_concurrent,
+            min_concurrent: args.min_concurrent,
             target_rate_mibs: args.target_rate,
+            burst_mib: args.burst,
             max_retries: args.max_retries,
-            retry_delay_ms: 1000,
-            progress_callback: Some(parallel_progress_callback),
-            rate_callback: Some(rate_callback),
+            retry_policy: retry_policy.clone(),
+            observer: Some(progress_printer(Some(source.chunk_count()), "Uploaded")),
+            journal: journal.clone(),
+            session_id,
+            prepare: None,
+            finalize: finalize.clone(),
+            verify: verify.clone(),
+            arg_template: arg_template.clone(),
+            reply_policy: reply_policy.clone(),
+            dispatch_order: match args.dispatch_order {
+                DispatchOrderKind::Ascending => DispatchOrder::Ascending,
+                DispatchOrderKind::Descending => DispatchOrder::Descending,
+            },
+            pipelined: args.pipelined,
+            max_duration: args.max_duration.map(Duration::from_secs),
+            cancellation: Some(stop.clone()),
         };
 
-        // Convert chunks to ChunkInfo with IDs
-        let chunk_infos = chunks_to_chunk_info(&model_chunks);
+        // Chunk IDs are indices into the chunk source
+        let chunk_ids = 0..source.chunk_count() as u32;
 
         // Filter chunks based on retry file or chunk_offset
-        let chunks_to_upload: Vec<_> = if let Some(retry_file) = &args.retry_chunks_file {
-            // Read failed chunk IDs from file
-            match std::fs::read_to_string(retry_file) {
-                Ok(content) => {
-                    let retry_ids: Result<Vec<u32>, _> = content
-                        .trim()
-                        .split(',')
-                        .map(|s| s.trim().parse::<u32>())
-                        .collect();
-
-                    match retry_ids {
-                        Ok(ids) => {
-                            println!("Retrying chunks: {:?}", ids);
-                            let filtered: Vec<_> = chunk_infos
-                                .into_iter()
-                                .filter(|chunk| ids.contains(&chunk.chunk_id))
-                                .collect();
-
-                            if filtered.is_empty() {
-                                return Err("No chunks to upload after applying chunk offset".to_string());
-                            }
-
-                            filtered
-                        }
-                        Err(e) => {
-                            return Err(format!("Failed to parse chunk IDs from {}: {}", retry_file, e));
-                        }
-                    }
-                }
-                Err(e) => {
-                    return Err(format!("Failed to read retry chunks file {}: {}", retry_file, e));
-                }
+        let chunks_to_upload: Vec<_> = if let Some(resume) = &resume {
+            println!("Retrying chunks: {:?}", resume.pending);
+            chunk_ids.filter(|chunk_id| resume.pending.contains(chunk_id)).collect()
+        } else if let Some(state) = &remote_state {
+            // Upload only what the canister is missing
+            let missing: Vec<_> = state
+                .missing_chunks(source.as_ref())?
+                .into_iter()
+                .filter(|chunk_id| *chunk_id as usize >= args.chunk_offset)
+                .collect();
+
+            if missing.is_empty() {
+                println!("✓ Canister already holds every chunk");
+                finalize_without_upload(transport.as_ref(), &params, source.as_ref(), session_id, &journal, &finalize, &verify)?;
+                remove_journal(&journal);
+                return Ok(());
             }
+
+            missing
         } else {
-            // Use normal chunk_offset filtering
-            chunk_infos
-                .into_iter()
+            // Use normal chunk_offset filtering, skipping chunks the journal says are done
+            chunk_ids
                 .skip(args.chunk_offset)
+                .filter(|chunk_id| !is_acknowledged(&journal, *chunk_id))
                 .collect()
         };
 
         if chunks_to_upload.is_empty() {
-            return Err("No chunks to upload after applying chunk offset".to_string());
+            // A run whose finalize or verify failed left every chunk acknowledged in the journal
+            if resume.is_none() && journal.is_some() && args.chunk_offset < source.chunk_count() {
+                println!("✓ Every chunk has already been uploaded");
+                finalize_without_upload(transport.as_ref(), &params, source.as_ref(), session_id, &journal, &finalize, &verify)?;
+                remove_journal(&journal);
+                return Ok(());
+            }
+            return Err(UploadError::Other("No chunks to upload after applying chunk offset".to_string()));
         }
 
         println!("Uploading {} chunks starting from ID {}",
                  chunks_to_upload.len(),
-                 chunks_to_upload[0].chunk_id);
+                 chunks_to_upload[0]);
+        config.prepare = prepare_call(&args, chunks_to_upload.len() == source.chunk_count());
 
 
         // Perform parallel upload
-        match upload_chunks_parallel(&params, chunks_to_upload, &config) {
-            ParallelUploadResult::Success => {
-                println!("\n✓ All chunks uploaded successfully!");
+        match upload_chunks_parallel(transport.as_ref(), &params, source.as_ref(), chunks_to_upload, &config) {
+            ParallelUploadResult::Success(report) => {
+                println!("\n✓ All {} chunks uploaded successfully!", report.successful_chunks.len());
+                print_report(&report);
+                remove_journal(&journal);
                 Ok(())
             }
-            ParallelUploadResult::PartialFailure { successful_chunks, failed_chunks } => {
-                println!("\n⚠ Partial success:");
-                println!("✓ Successful chunks: {:?}", successful_chunks);
-                println!("✗ Failed chunks: {:?}", failed_chunks.keys().collect::<Vec<_>>());
-
-                // Write failed chunk IDs to a file for easy retry
-                let failed_ids: Vec<u32> = failed_chunks.keys().copied().collect();
-                let failed_file = format!("{}.failed_chunks", args.file_path);
-
-                match std::fs::write(&failed_file, failed_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(",")) {
-                    Ok(()) => {
-                        println!("\n📝 Failed chunk IDs written to: {}", failed_file);
-                        println!("To retry failed chunks, run:");
-                        println!("ic-file-uploader {} {} {} --parallel --retry-chunks-file {}{}",
-                                 args.canister_name,
-                                 args.canister_method,
-                                 args.file_path,
-                                 failed_file,
-                                 args.network.as_ref().map(|n| format!(" --network {}", n)).unwrap_or_default());
-                    }
-                    Err(e) => {
-                        println!("⚠ Could not write failed chunks file: {}", e);
-                        println!("Failed chunk IDs: {}", failed_ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(","));
-                    }
+            ParallelUploadResult::PartialFailure(report) => {
+                if let Some(fatal_error) = &report.fatal_error {
+                    println!("\n✗ Upload stopped: {}", fatal_error);
                 }
+                println!("\n⚠ Partial success:");
+                print_report(&report);
+                print_journal_hint(&journal);
+                println!("✓ Successful chunks: {:?}", report.successful_chunks);
+
+                write_failed_chunks(&args, session_id, &report, source.chunk_count());
 
-                Err("Some chunks failed to upload".to_string())
+                Err(first_failure(report))
+            }
+            ParallelUploadResult::Stopped(report) => {
+                let reason = report.stop_reason.unwrap_or(StopReason::Cancelled);
+                println!("\n⏸ Stopped: {}", reason);
+                print_report(&report);
+                print_journal_hint(&journal);
+                write_failed_chunks(&args, session_id, &report, source.chunk_count());
+
+                Err(reason.into())
             }
             ParallelUploadResult::Failed(e) => {
                 println!("\n✗ Upload failed: {}", e);
@@ -236,35 +1435,139 @@ fn main() -> Result<(), String> {
         println!("Using sequential upload mode");
 
         // Configure upload behavior - provide defaults for all parameters
-        let config = UploadConfig {
+        let mut config = UploadConfig {
             max_retries: args.max_retries,
-            retry_delay_ms: 1000,  // Default 1 second delay
+            retry_policy: retry_policy.clone(),
             auto_resume: args.autoresume,
-            progress_callback: Some(progress_callback),
+            observer: Some(progress_printer(Some(source.chunk_count()), "Uploaded")),
+            journal: journal.clone(),
+            session_id,
+            prepare: None,
+            finalize: finalize.clone(),
+            verify: verify.clone(),
+            arg_template: arg_template.clone(),
+            reply_policy: reply_policy.clone(),
+            target_rate_mibs: Some(args.target_rate),
+            burst_mib: args.burst,
+            max_duration: args.max_duration.map(Duration::from_secs),
+            cancellation: Some(stop.clone()),
+        };
+
+        // Chunks are sent in order, so a resume file continues from its first pending chunk
+        let first_chunk = match &resume {
+            Some(resume) => match resume.pending.iter().min() {
+                Some(&chunk_id) => usize::max(args.chunk_offset, chunk_id as usize),
+                None => return Err(UploadError::Other("No chunks to upload after applying chunk offset".to_string())),
+            },
+            None => args.chunk_offset,
+        };
+
+        // Continue after what the canister or the journal says is done, if that is further along
+        let start_chunk = match (&remote_state, &journal) {
+            (Some(state), _) => usize::max(first_chunk, state.sequential_start(source.as_ref())?),
+            (None, Some(journal)) => usize::max(first_chunk, journal.lock().unwrap().first_pending()),
+            (None, None) => first_chunk,
         };
+        if start_chunk > first_chunk {
+            let origin = if remote_state.is_some() { "reported by the canister" } else { "recorded in the journal" };
+            println!("Resuming from chunk {} {}", start_chunk + 1, origin);
+        }
+        if start_chunk > 0 && start_chunk >= source.chunk_count() {
+            println!("✓ Every chunk has already been uploaded");
+            finalize_without_upload(transport.as_ref(), &params, source.as_ref(), session_id, &journal, &finalize, &verify)?;
+            remove_journal(&journal);
+            return Ok(());
+        }
+        config.prepare = prepare_call(&args, start_chunk == 0);
 
         // Perform sequential upload with resume
-        match upload_chunks_with_resume(&params, &model_chunks, args.chunk_offset, &config) {
+        match upload_chunks_with_resume(transport.as_ref(), &params, source.as_ref(), start_chunk, &config) {
             ChunkUploadResult::Success => {
                 println!("✓ Upload completed successfully!");
+                remove_journal(&journal);
                 Ok(())
             }
             ChunkUploadResult::Failed(e) => {
                 eprintln!("Upload failed: {}", e);
                 Err(e)
             }
+            ChunkUploadResult::Stopped { next_chunk, reason } => {
+                println!("⏸ Stopped: {}, with {} of {} chunks uploaded", reason, next_chunk, source.chunk_count());
+                print_journal_hint(&journal);
+                let pending = (next_chunk as u32..source.chunk_count() as u32).collect();
+                write_resume_file(&ResumeFile::new(source.chunk_count(), pending), continuation(&args, session_id));
+                Err(reason.into())
+            }
             ChunkUploadResult::Interrupted { failed_at_chunk, error } => {
                 eprintln!("Upload interrupted at chunk {}: {}", failed_at_chunk + 1, error);
-                println!("\nTo resume from this point, run:");
-                println!("ic-file-uploader {} {} {} --chunk-offset {} --autoresume{}",
-                         args.canister_name,
-                         args.canister_method,
-                         args.file_path,
-                         failed_at_chunk,
-                         args.network.as_ref().map(|n| format!(" --network {}", n)).unwrap_or_default());
-                Err(format!("Upload interrupted at chunk {}", failed_at_chunk + 1))
+                print_journal_hint(&journal);
+                let pending = (failed_at_chunk as u32..source.chunk_count() as u32).collect();
+                write_resume_file(&ResumeFile::new(source.chunk_count(), pending), Args {
+                    autoresume: true,
+                    ..continuation(&args, session_id)
+                });
+                Err(error)
             }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_flags_repeat_the_upload() {
+        let command_lines: [&[&str]; 3] = [
+            &["ic-file-uploader", "my_canister", "append_chunk", "model.bin"],
+            &[
+                "ic-file-uploader", "my_canister", "put_chunk", "model.bin", "-o", "64", "--chunk-offset", "3",
+                "--network", "ic", "--autoresume", "--max-retries", "5", "--retry-delay", "10",
+                "--max-retry-delay", "20", "--max-retry-time", "30", "--max-duration", "40", "--parallel",
+                "--max-concurrent", "8", "--min-concurrent", "2", "--dispatch-order", "descending",
+                "--target-rate", "0.5", "--burst", "16", "--resume-from-canister", "parallel_chunk_ids",
+                "--transport", "native", "--identity-pem", "id.pem", "--call-timeout", "0", "--mmap",
+                "--journal-dir", "/tmp/journal dir", "--fresh", "--session", "--session-id", "7",
+                "--prepare-method", "clear", "--finalize-method", "save", "--finalize-arg", "(\"model\")",
+                "--verify-method", "sha256", "--verify-arg", "(\"it's\")", "--candid", "app.did",
+                "--accept-err-replies", "--fail-on-false", "--success-reply", "empty", "--success-reply", "number",
+            ],
+            &[
+                "ic-file-uploader", "my_canister", "store", "model.bin", "--pipelined", "--no-journal",
+                "--retry-chunks-file", "model.bin.resume", "--arg-template", "(\"{name}\", {offset} : nat64, {blob})",
+                "--skip-interface-check",
+            ],
+        ];
+
+        for command_line in command_lines {
+            let args = Args::parse_from(command_line);
+            let flags = upload_flags(&args);
+            assert_eq!(Args::parse_from(std::iter::once("ic-file-uploader".to_string()).chain(flags)), args);
+        }
+
+        let args = Args::parse_from(command_lines[0]);
+        assert_eq!(upload_command(&args), "ic-file-uploader my_canister append_chunk model.bin");
+        let args = Args { verify_arg: Some("(\"it's\")".to_string()), verify_method: Some("sha256".to_string()), ..args };
+        assert!(upload_command(&args).ends_with(" --verify-arg '(\"it'\\''s\")'"));
+
+        // Pipelined chunks are appended, so they always go in ascending order
+        let reordered = ["ic-file-uploader", "my_canister", "store", "model.bin", "--pipelined", "--dispatch-order", "descending"];
+        assert!(Args::try_parse_from(reordered).is_err());
+    }
+
+    #[test]
+    fn test_resume_file_lists_completed_and_pending_chunks() {
+        let resume = ResumeFile::new(6, vec![4, 1, 5]);
+        assert_eq!(resume, ResumeFile { completed: vec![0, 2, 3], pending: vec![1, 4, 5] });
+        assert_eq!(resume.to_string(), "completed: 0,2,3\npending: 1,4,5\n");
+        assert_eq!(ResumeFile::parse(&resume.to_string()).unwrap(), resume);
+
+        let finished = ResumeFile::new(2, Vec::new());
+        assert_eq!(ResumeFile::parse(&finished.to_string()).unwrap(), finished);
+
+        // A bare list of chunk IDs lists the pending chunks
+        assert_eq!(ResumeFile::parse("3, 7,9\n").unwrap(), ResumeFile { completed: Vec::new(), pending: vec![3, 7, 9] });
+        assert!(ResumeFile::parse("failed: 1").is_err());
+        assert!(ResumeFile::parse("pending: 1,x").is_err());
+    }
+}
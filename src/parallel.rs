@@ -3,41 +3,130 @@
 //! This module provides functionality for uploading multiple chunks in parallel
 //! with automatic rate limiting and chunk ID tracking.
 
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 use std::collections::HashMap;
-use tempfile::NamedTempFile;
-use std::io::Write;
-
-use crate::{dfx, create_error_string, UploadParams};
+use candid::Encode;
+use crate::error::UploadError;
+use crate::{create_error_string, encode_blob_args, record_in_journal, UploadParams};
+use crate::cancel::{stop_reason, CancellationToken, StopReason};
+use crate::concurrency::AdaptiveConcurrency;
+use crate::progress::{notify, ProgressEvent, SharedObserver, UploadReport};
+use crate::ratelimit::{TokenBucket, DEFAULT_BURST_MIB};
+use crate::hooks::{run_hook, CanisterCall};
+use crate::journal::UploadJournal;
+use crate::reply::ReplyPolicy;
+use crate::retry::{AttemptError, RetryPolicy};
+use crate::session::{commit_session, encode_put_chunk_args, SessionId};
+use crate::source::ChunkSource;
+use crate::template::{ArgTemplate, ChunkContext};
+use crate::transport::Transport;
+use crate::verify::verify_upload;
 
 /// Configuration for parallel upload operations
 #[derive(Debug, Clone)]
 pub struct ParallelUploadConfig {
     /// Maximum number of concurrent uploads
     pub max_concurrent: usize,
-    /// Target upload rate in MiB per second
+    /// Number of concurrent uploads to start with and never go below
+    pub min_concurrent: usize,
+    /// Target upload rate in MiB per second; not positive for no limit
     pub target_rate_mibs: f64,
+    /// Largest burst above the target rate in MiB, e.g. after an idle period
+    pub burst_mib: f64,
     /// Maximum retry attempts per chunk
     pub max_retries: usize,
-    /// Delay between retry attempts in milliseconds
-    pub retry_delay_ms: u64,
-    /// Progress callback for individual chunks
-    pub progress_callback: Option<fn(u32, usize, &str)>,
-    /// Rate limiting callback (called with current rate)
-    pub rate_callback: Option<fn(f64)>,
+    /// Backoff between retry attempts
+    pub retry_policy: RetryPolicy,
+    /// Receives the progress events of the upload
+    pub observer: Option<SharedObserver>,
+    /// Optional journal updated after every acknowledged chunk
+    pub journal: Option<Arc<Mutex<UploadJournal>>>,
+    /// Upload session the chunks belong to; chunks are sent as `put_chunk` arguments when set,
+    /// and the session is committed once every chunk succeeded, before finalize and verify
+    pub session_id: Option<SessionId>,
+    /// Optional call made before the first chunk
+    pub prepare: Option<CanisterCall>,
+    /// Optional call made once every chunk succeeded
+    pub finalize: Option<CanisterCall>,
+    /// Optional hash query compared against the local chunks after finalizing
+    pub verify: Option<CanisterCall>,
+    /// Argument shape of the chunk calls; takes precedence over the session and `(nat32, blob)` shapes
+    pub arg_template: Option<ArgTemplate>,
+    /// Which chunk call replies count as success
+    pub reply_policy: ReplyPolicy,
+    /// Order in which chunks are dispatched
+    pub dispatch_order: DispatchOrder,
+    /// Make chunk calls one at a time in ascending order while preparing the next
+    /// `max_concurrent` chunks in parallel, for canisters that append chunks;
+    /// requires [`DispatchOrder::Ascending`] and contiguous chunk IDs
+    pub pipelined: bool,
+    /// Time after which no further chunk is started; `None` for no limit
+    pub max_duration: Option<Duration>,
+    /// Token that stops dispatching chunks once cancelled
+    pub cancellation: Option<CancellationToken>,
+}
+
+impl ParallelUploadConfig {
+    /// This config without the settings of a file upload, for the chunk calls
+    /// of another protocol such as an asset batch or a chunk store
+    pub(crate) fn for_chunk_calls(&self) -> Self {
+        Self {
+            journal: None,
+            session_id: None,
+            arg_template: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// Order in which the chunks of a parallel upload are dispatched
+#[derive(Debug, Clone, Copy, Default)]
+pub enum DispatchOrder {
+    /// Lowest chunk ID first
+    #[default]
+    Ascending,
+    /// Highest chunk ID first
+    Descending,
+    /// Lowest priority value first; chunks of equal priority go in ascending order
+    Priority(fn(u32) -> u64),
+}
+
+impl DispatchOrder {
+    /// Sorts chunk IDs into dispatch order
+    pub fn sort(&self, chunk_ids: &mut [u32]) {
+        match self {
+            DispatchOrder::Ascending => chunk_ids.sort_unstable(),
+            DispatchOrder::Descending => chunk_ids.sort_unstable_by(|a, b| b.cmp(a)),
+            DispatchOrder::Priority(priority) => chunk_ids.sort_unstable_by_key(|&chunk_id| (priority(chunk_id), chunk_id)),
+        }
+    }
 }
 
 impl Default for ParallelUploadConfig {
     fn default() -> Self {
         Self {
             max_concurrent: 4,        // Start conservative
+            min_concurrent: 1,
             target_rate_mibs: 4.0,    // 4 MiB/s target
+            burst_mib: DEFAULT_BURST_MIB,
             max_retries: 3,
-            retry_delay_ms: 1000,
-            progress_callback: None,
-            rate_callback: None,
+            retry_policy: RetryPolicy::default(),
+            observer: None,
+            journal: None,
+            session_id: None,
+            prepare: None,
+            finalize: None,
+            verify: None,
+            arg_template: None,
+            reply_policy: ReplyPolicy::default(),
+            dispatch_order: DispatchOrder::default(),
+            pipelined: false,
+            max_duration: None,
+            cancellation: None,
         }
     }
 }
@@ -45,19 +134,19 @@ impl Default for ParallelUploadConfig {
 /// Result of a parallel upload operation
 #[derive(Debug)]
 pub enum ParallelUploadResult {
-    /// All chunks uploaded successfully
-    Success,
-    /// Some chunks failed after all retries
-    PartialFailure {
-        /// Successfully uploaded chunk IDs
-        successful_chunks: Vec<u32>,
-        /// Failed chunk IDs with errors
-        failed_chunks: HashMap<u32, String>
-    },
-    /// Upload was completely unsuccessful
-    Failed(String),
+    /// All chunks uploaded successfully, and the finalize and verify calls succeeded
+    Success(UploadReport),
+    /// Some or all chunks failed after all retries
+    PartialFailure(UploadReport),
+    /// The upload stopped early, see [`UploadReport::stop_reason`]; the chunks not started are reported as failed
+    Stopped(UploadReport),
+    /// Upload could not start, or a call before or after the chunks failed
+    Failed(UploadError),
 }
 
+/// What a parallel upload did, the same report as that of a sequential upload
+pub type ParallelUploadReport = UploadReport;
+
 /// Information about a chunk to be uploaded
 #[derive(Debug, Clone)]
 pub struct ChunkInfo {
@@ -76,19 +165,22 @@ struct UploadTracker {
     bytes_uploaded: usize,
     /// When the upload session started
     start_time: Instant,
-    /// Currently active uploads
-    active_uploads: usize,
-    /// Completed chunks
-    completed_chunks: Vec<u32>,
+    /// Concurrency limit adjusted from chunk latencies and failures
+    concurrency: AdaptiveConcurrency,
+    /// Set once a chunk failed in a way no retry can fix; no further attempts start
+    stopped: bool,
+    /// Failed attempts that were retried
+    retries: usize,
 }
 
 impl UploadTracker {
-    fn new() -> Self {
+    fn new(config: &ParallelUploadConfig) -> Self {
         Self {
             bytes_uploaded: 0,
             start_time: Instant::now(),
-            active_uploads: 0,
-            completed_chunks: Vec::new(),
+            concurrency: AdaptiveConcurrency::new(config.min_concurrent, config.max_concurrent),
+            stopped: false,
+            retries: 0,
         }
     }
 
@@ -101,28 +193,6 @@ impl UploadTracker {
             0.0
         }
     }
-
-    /// Should we start another upload based on rate limiting?
-    fn should_start_upload(&self, config: &ParallelUploadConfig) -> bool {
-        if self.active_uploads >= config.max_concurrent {
-            return false;
-        }
-
-        let current_rate = self.current_rate_mibs();
-        current_rate < config.target_rate_mibs || self.active_uploads == 0
-    }
-
-    /// Calculate delay needed to maintain target rate
-    fn calculate_delay(&self, config: &ParallelUploadConfig) -> Duration {
-        let current_rate = self.current_rate_mibs();
-        if current_rate > config.target_rate_mibs {
-            // We're going too fast, delay a bit
-            Duration::from_millis(100)
-        } else {
-            // We can go faster or maintain current pace
-            Duration::from_millis(10)
-        }
-    }
 }
 
 /// Converts chunk data with ID to a blob string in Candid format
@@ -144,7 +214,23 @@ pub fn chunk_with_id_to_candid_args(chunk_id: u32, data: &[u8]) -> String {
 }
 
 
+/// Encodes chunk data with ID as binary Candid `(nat32, blob)` arguments
+///
+/// # Arguments
+///
+/// * `chunk_id` - The unique identifier for this chunk
+/// * `data` - The chunk data bytes
+///
+/// # Returns
+///
+/// A `Result` containing the Candid-encoded arguments or an error message
+pub fn encode_chunk_with_id_args(chunk_id: u32, data: &[u8]) -> Result<Vec<u8>, UploadError> {
+    Encode!(&chunk_id, &serde_bytes::Bytes::new(data))
+        .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))
+}
+
 /// Test to create exact working format for debugging
+#[allow(clippy::octal_escapes)]
 pub fn create_test_format(chunk_id: u32) -> String {
     // Create exactly what your test case does for the first few bytes
     match chunk_id {
@@ -153,287 +239,636 @@ pub fn create_test_format(chunk_id: u32) -> String {
     }
 }
 
-/// Upload a chunk with retry logic
-fn upload_chunk_with_retry(
+/// Encodes the call argument of a chunk from its ID and data
+pub(crate) type ChunkEncoder<'a> = dyn Fn(u32, &[u8]) -> Result<Vec<u8>, UploadError> + Sync + 'a;
+
+/// Outcome of the parallel upload loop
+#[derive(Debug, Default)]
+pub(crate) struct ParallelRun {
+    /// Reply of every chunk that succeeded, with its chunk ID
+    pub(crate) replies: Vec<(u32, Vec<u8>)>,
+    /// Error of every chunk that failed, by chunk ID
+    pub(crate) failed_chunks: HashMap<u32, UploadError>,
+    /// The failure that stopped the upload before every chunk was attempted
+    pub(crate) fatal_error: Option<UploadError>,
+    /// Why the upload stopped starting chunks, if it did
+    pub(crate) stop_reason: Option<StopReason>,
+    /// Bytes of chunk data the canister acknowledged
+    pub(crate) bytes_uploaded: u64,
+    /// Failed attempts that were retried
+    pub(crate) retries: usize,
+    /// Time spent in the upload loop
+    pub(crate) duration: Duration,
+}
+
+impl ParallelRun {
+    /// The failure of a run that did not store every chunk, if any: the fatal
+    /// error, otherwise why the run stopped early, otherwise the error of the
+    /// first chunk that was attempted, with the description `context` gives of
+    /// the failed chunk IDs in front of it
+    pub(crate) fn take_failure(&mut self, context: impl FnOnce(&[u32]) -> String) -> Option<UploadError> {
+        let mut failed: Vec<u32> = self.failed_chunks.keys().copied().collect();
+        failed.sort();
+
+        let error = match (self.fatal_error.take(), self.stop_reason) {
+            (Some(fatal_error), _) => fatal_error,
+            (None, Some(reason)) if !failed.is_empty() => reason.into(),
+            (None, _) => {
+                let first = failed
+                    .iter()
+                    .find(|id| !matches!(self.failed_chunks[id], UploadError::NotAttempted(_)))
+                    .or(failed.first())?;
+                self.failed_chunks.remove(first)?
+            }
+        };
+        Some(error.context(context(&failed)))
+    }
+}
+
+/// Upload a chunk with retry logic; a fatal failure stops every worker from retrying
+fn upload_chunk_with_retry<T: Transport + ?Sized>(
+    transport: &T,
     params: &UploadParams<'_>,
     chunk: &ChunkInfo,
     config: &ParallelUploadConfig,
-    tracker: Arc<Mutex<UploadTracker>>,
-) -> Result<(), String> {
+    tracker: &Mutex<UploadTracker>,
+    encode: &ChunkEncoder,
+) -> Result<Vec<u8>, AttemptError> {
+    let candid_args = encode(chunk.chunk_id, &chunk.data).map_err(AttemptError::fatal)?;
+    submit_chunk_with_retry(transport, params, chunk.chunk_id, chunk.size, &candid_args, config, tracker)
+}
+
+/// Submits an encoded chunk, retrying retryable failures as configured
+fn submit_chunk_with_retry<T: Transport + ?Sized>(
+    transport: &T,
+    params: &UploadParams<'_>,
+    chunk_id: u32,
+    size: usize,
+    candid_args: &[u8],
+    config: &ParallelUploadConfig,
+    tracker: &Mutex<UploadTracker>,
+) -> Result<Vec<u8>, AttemptError> {
     let mut attempts = 0;
+    let first_attempt = Instant::now();
+    notify(&config.observer, || ProgressEvent::ChunkStarted { chunk_id, size });
 
     loop {
         attempts += 1;
 
-        let result = upload_chunk_with_id_sync(params, chunk, config);
+        let started = Instant::now();
+        let result = upload_chunk_with_id_sync(transport, params, candid_args, config);
 
         match result {
-            Ok(()) => {
-                // Update tracker
+            Ok(reply) => {
+                if let Some(journal) = &config.journal {
+                    record_in_journal(journal, chunk_id, &config.observer);
+                }
+
+                // Update tracker; the dispatcher frees the slot when it receives the result
                 {
                     let mut tracker = tracker.lock().unwrap();
-                    tracker.bytes_uploaded += chunk.size;
-                    tracker.completed_chunks.push(chunk.chunk_id);
-                    tracker.active_uploads -= 1;
+                    tracker.bytes_uploaded += size;
+                    tracker.concurrency.record_success(started.elapsed());
                 }
-                return Ok(());
+                notify(&config.observer, || ProgressEvent::ChunkSucceeded {
+                    chunk_id,
+                    size,
+                    attempts,
+                    latency: started.elapsed(),
+                });
+                return Ok(reply);
             }
             Err(e) => {
-                if attempts >= config.max_retries {
-                    // Update tracker for failure
-                    {
-                        let mut tracker = tracker.lock().unwrap();
-                        tracker.active_uploads -= 1;
-                    }
-                    return Err(format!(
-                        "Chunk {} failed after {} attempts. Last error: {}",
-                        chunk.chunk_id, attempts, e
-                    ));
+                let stopped = {
+                    let mut tracker = tracker.lock().unwrap();
+                    tracker.concurrency.record_failure();
+                    tracker.stopped |= e.is_fatal();
+                    tracker.stopped || config.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled)
+                };
+
+                if e.is_fatal() {
+                    return Err(AttemptError::fatal(e.into_chunk_error(chunk_id, attempts)));
                 }
 
-                if let Some(callback) = config.progress_callback {
-                    callback(
-                        chunk.chunk_id,
-                        chunk.size,
-                        &format!("⚠ Attempt {}/{} failed, retrying...", attempts, config.max_retries)
-                    );
+                let delay = config.retry_policy.delay(attempts as u32);
+                if stopped
+                    || attempts >= config.max_retries
+                    || !config.retry_policy.allows_retry(first_attempt.elapsed(), delay)
+                {
+                    return Err(AttemptError::retryable(e.into_chunk_error(chunk_id, attempts)));
                 }
+                tracker.lock().unwrap().retries += 1;
+
+                notify(&config.observer, || ProgressEvent::ChunkRetry {
+                    chunk_id,
+                    attempt: attempts,
+                    max_attempts: config.max_retries,
+                    delay,
+                    error: e.error.clone(),
+                });
 
-                thread::sleep(Duration::from_millis(config.retry_delay_ms));
+                thread::sleep(delay);
             }
         }
     }
 }
 
 /// Synchronous version of upload_chunk_with_id with better error handling
-fn upload_chunk_with_id_sync(
+fn upload_chunk_with_id_sync<T: Transport + ?Sized>(
+    transport: &T,
     params: &UploadParams<'_>,
-    chunk: &ChunkInfo,
+    candid_args: &[u8],
     config: &ParallelUploadConfig,
-) -> Result<(), String> {
-    let candid_args = chunk_with_id_to_candid_args(chunk.chunk_id, &chunk.data);
-
-    //println!("Candid Args {}", candid_args);
-
-    // Create temp file with explicit UTF-8 encoding
-    let mut temp_file = NamedTempFile::new()
-        .map_err(|e| create_error_string(&format!("Failed to create temporary file: {}", e)))?;
-
-    // Write the data and explicitly flush to ensure it's written
-    temp_file
-        .as_file_mut()
-        .write_all(candid_args.as_bytes())
-        .map_err(|e| create_error_string(&format!("Failed to write data to temporary file: {}", e)))?;
-
-    // CRITICAL: Flush the file to ensure data is written before dfx reads it
-    temp_file
-        .as_file_mut()
-        .flush()
-        .map_err(|e| create_error_string(&format!("Failed to flush temporary file: {}", e)))?;
-
-    let temp_path = temp_file.path().to_str()
-        .ok_or_else(|| create_error_string("temp_file path could not be converted to &str"))?;
-
-    let output = dfx(
-        "canister",
-        "call",
-        &vec![
-            params.canister_name,
-            params.canister_method,
-            "--argument-file",
-            temp_path,
-        ],
-        params.network,
-    )?;
-
-    if output.status.success() {
-        if let Some(callback) = config.progress_callback {
-            callback(chunk.chunk_id, chunk.data.len(), "✓ Uploaded");
-        }
-        Ok(())
-    } else {
-        let error_message = String::from_utf8_lossy(&output.stderr).to_string();
-        Err(create_error_string(&format!("Chunk {} failed: {}", chunk.chunk_id, error_message)))
-    }
+) -> Result<Vec<u8>, AttemptError> {
+    // A delivered reply can still report a failure
+    transport
+        .call(params.canister_name, params.canister_method, candid_args)
+        .map_err(AttemptError::from_call)
+        .and_then(|reply| {
+            config
+                .reply_policy
+                .check(&reply)
+                .map(|()| reply)
+                .map_err(AttemptError::from_reply)
+        })
 }
 
 /// Upload multiple chunks in parallel with rate limiting
 ///
+/// Chunks are dispatched in `config.dispatch_order`. With `config.pipelined`,
+/// chunk calls are made one at a time in ascending order while the next chunks
+/// are read and encoded in parallel, and chunks without a template or session
+/// are sent as `(blob)` for append-only methods. A pipelined upload fails
+/// before sending anything unless the dispatch order is ascending and the
+/// chunk IDs are contiguous, since appending out of order or past a gap would
+/// corrupt the file.
+///
 /// # Arguments
 ///
+/// * `transport` - The transport used to submit chunk calls
 /// * `params` - Upload parameters including canister info
-/// * `chunks` - Vector of chunks to upload with their IDs
+/// * `source` - Source the chunks are read from when their upload starts
+/// * `chunk_ids` - IDs (indices into `source`) of the chunks to upload
 /// * `config` - Parallel upload configuration
 ///
 /// # Returns
 ///
-/// A `ParallelUploadResult` indicating the outcome
-pub fn upload_chunks_parallel(
+/// A `ParallelUploadResult` indicating the outcome, with a report of the
+/// chunks, bytes, duration and retries unless the upload could not start.
+pub fn upload_chunks_parallel<T: Transport + ?Sized, S: ChunkSource + ?Sized>(
+    transport: &T,
     params: &UploadParams<'_>,
-    chunks: Vec<ChunkInfo>,
+    source: &S,
+    chunk_ids: Vec<u32>,
     config: &ParallelUploadConfig,
 ) -> ParallelUploadResult {
-    if chunks.is_empty() {
-        return ParallelUploadResult::Failed("No chunks to upload".to_string());
+    if chunk_ids.is_empty() {
+        return ParallelUploadResult::Failed(UploadError::Other("No chunks to upload".to_string()));
     }
 
-    // STORE THE ORIGINAL TOTAL
-    let total_chunks_expected = chunks.len() as u32;
+    if config.pipelined {
+        if let Err(e) = check_pipelined(config, &chunk_ids) {
+            return ParallelUploadResult::Failed(e);
+        }
+    }
 
-    let tracker = Arc::new(Mutex::new(UploadTracker::new()));
-    let mut handles = Vec::new();
-    let mut successful_chunks = Vec::new();
-    let mut failed_chunks = HashMap::new();
+    if let Some(prepare) = &config.prepare {
+        if let Err(e) = run_hook(transport, params, prepare, "Prepare", &config.observer) {
+            return ParallelUploadResult::Failed(e);
+        }
+    }
 
-    println!("Starting parallel upload of {} chunks", chunks.len());
-    println!("Target rate: {:.1} MiB/s, Max concurrent: {}",
-             config.target_rate_mibs, config.max_concurrent);
+    // Chunks follow the template if there is one, go into the session when there
+    // is one, and are tagged with their ID otherwise, unless they are appended in order
+    let encode = |chunk_id: u32, data: &[u8]| match (&config.arg_template, config.session_id) {
+        (Some(template), _) => template.render(&ChunkContext {
+            name: params.name,
+            index: chunk_id,
+            offset: chunk_id as u64 * source.chunk_size() as u64,
+            total: source.chunk_count() as u32,
+            data,
+        }),
+        (None, Some(session_id)) => encode_put_chunk_args(session_id, chunk_id, data),
+        (None, None) if config.pipelined => encode_blob_args(data),
+        (None, None) => encode_chunk_with_id_args(chunk_id, data),
+    };
+
+    let run = if config.pipelined {
+        run_pipelined(transport, params, source, chunk_ids, config, &encode)
+    } else {
+        run_parallel(transport, params, source, chunk_ids, config, &encode)
+    };
+
+    let mut successful_chunks: Vec<u32> = run.replies.iter().map(|(chunk_id, _)| *chunk_id).collect();
+    successful_chunks.sort();
+    let report = UploadReport {
+        successful_chunks,
+        failed_chunks: run.failed_chunks,
+        bytes_uploaded: run.bytes_uploaded,
+        duration: run.duration,
+        retries: run.retries,
+        fatal_error: run.fatal_error,
+        stop_reason: run.stop_reason,
+    };
+    notify(&config.observer, || ProgressEvent::Finished { report: report.clone() });
+
+    if report.stop_reason.is_some() {
+        return ParallelUploadResult::Stopped(report);
+    }
+    if !report.failed_chunks.is_empty() {
+        return ParallelUploadResult::PartialFailure(report);
+    }
 
-    let chunks_remaining = Arc::new(Mutex::new(chunks));
+    // Commit, finalize and verify only once every chunk made it
+    if let Some(session_id) = config.session_id {
+        if let Err(e) = commit_session(transport, params, session_id, &config.journal, &config.observer) {
+            return ParallelUploadResult::Failed(e);
+        }
+    }
 
-    // Main upload loop
-    loop {
-        // Check if we should start more uploads
-        let should_start = {
-            let tracker = tracker.lock().unwrap();
-            tracker.should_start_upload(config)
-        };
+    if let Some(finalize) = &config.finalize {
+        if let Err(e) = run_hook(transport, params, finalize, "Finalize", &config.observer) {
+            return ParallelUploadResult::Failed(e);
+        }
+    }
 
-        if should_start {
-            // Get next chunk
-            let next_chunk = {
-                let mut chunks_lock = chunks_remaining.lock().unwrap();
-                chunks_lock.pop()
-            };
+    if let Some(hash_query) = &config.verify {
+        match verify_upload(transport, params, source, hash_query) {
+            Ok(sha256) => notify(&config.observer, || ProgressEvent::Verified {
+                sha256,
+                method: hash_query.method.clone(),
+            }),
+            Err(e) => return ParallelUploadResult::Failed(e),
+        }
+    }
 
-            if let Some(chunk) = next_chunk {
-                // Start upload in a new thread
-                {
-                    let mut tracker = tracker.lock().unwrap();
-                    tracker.active_uploads += 1;
-                }
+    ParallelUploadResult::Success(report)
+}
 
-                // Clone all necessary data for the thread
-                let chunk_clone = chunk.clone();
-                let config_clone = config.clone();
-                let tracker_clone = Arc::clone(&tracker);
-
-                // Create owned copies of the params data for the thread
-                let canister_name = params.canister_name.to_string();
-                let canister_method = params.canister_method.to_string();
-                let name = params.name.to_string();
-                let network = params.network.map(|s| s.to_string());
-
-                let handle = thread::spawn(move || {
-                    // Reconstruct params inside the thread with owned data
-                    let thread_params = UploadParams {
-                        name: &name,
-                        canister_name: &canister_name,
-                        canister_method: &canister_method,
-                        network: network.as_deref(),
-                    };
+/// Checks that a pipelined upload appends its chunks in file order without gaps
+fn check_pipelined(config: &ParallelUploadConfig, chunk_ids: &[u32]) -> Result<(), UploadError> {
+    if !matches!(config.dispatch_order, DispatchOrder::Ascending) {
+        return Err(UploadError::Other(create_error_string(
+            "Pipelined uploads append chunks and must dispatch them in ascending order",
+        )));
+    }
 
-                    upload_chunk_with_retry(&thread_params, &chunk_clone, &config_clone, tracker_clone)
-                });
+    let mut sorted = chunk_ids.to_vec();
+    sorted.sort_unstable();
+    if let Some(gap) = sorted.windows(2).find(|pair| pair[1] != pair[0] + 1) {
+        return Err(UploadError::Other(create_error_string(&format!(
+            "Pipelined uploads append chunks and cannot skip from chunk {} to chunk {}",
+            gap[0], gap[1]
+        ))));
+    }
 
-                handles.push((chunk.chunk_id, handle));
-            }
-        }
+    Ok(())
+}
 
-        // Check for completed uploads
-        let mut completed_handles = Vec::new();
-        for (i, (chunk_id, handle)) in handles.iter().enumerate() {
-            if handle.is_finished() {
-                completed_handles.push((i, *chunk_id));
-            }
+/// Outcome of one chunk, sent from a worker to the dispatcher
+type ChunkResult = (u32, Result<Vec<u8>, AttemptError>);
+
+/// Runs the parallel upload loop, encoding each chunk's call argument with `encode`.
+///
+/// Shared by every protocol built on parallel chunk calls; collects the reply
+/// of each successful chunk so callers can use what the canister returned.
+///
+/// A fixed pool of workers, one per allowed concurrent upload, takes chunk IDs
+/// from a job channel and sends each chunk's outcome back over a result
+/// channel. The calling thread dispatches a chunk whenever fewer are in flight
+/// than the adaptive concurrency limit and the rate limiter admits it, and
+/// otherwise blocks on the result channel, so no thread polls.
+pub(crate) fn run_parallel<T: Transport + ?Sized, S: ChunkSource + ?Sized>(
+    transport: &T,
+    params: &UploadParams<'_>,
+    source: &S,
+    mut chunk_ids: Vec<u32>,
+    config: &ParallelUploadConfig,
+    encode: &ChunkEncoder,
+) -> ParallelRun {
+    // Chunks are taken from the end
+    config.dispatch_order.sort(&mut chunk_ids);
+    chunk_ids.reverse();
+
+    let tracker = Mutex::new(UploadTracker::new(config));
+    let bucket = TokenBucket::new(config.target_rate_mibs, config.burst_mib);
+    let mut run = ParallelRun::default();
+
+    // The concurrency limit never exceeds the pool size
+    let workers = config.max_concurrent.max(config.min_concurrent).max(1).min(chunk_ids.len());
+
+    notify(&config.observer, || ProgressEvent::Started {
+        chunks: chunk_ids.len(),
+        workers,
+    });
+
+    let (job_sender, job_receiver) = mpsc::channel::<u32>();
+    let (result_sender, results) = mpsc::channel::<ChunkResult>();
+    let job_receiver = Mutex::new(job_receiver);
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let result_sender = result_sender.clone();
+            let job_receiver = &job_receiver;
+            let tracker = &tracker;
+
+            scope.spawn(move || {
+                // Take chunks until the dispatcher closes the job channel
+                while let Some(chunk_id) = next_job(job_receiver) {
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        // Read the chunk inside the worker so only in-flight chunks are in memory
+                        let data = source.read_chunk(chunk_id as usize).map_err(|e| {
+                            AttemptError::fatal(UploadError::io(format!("Failed to read chunk {}", chunk_id), e))
+                        })?;
+                        let chunk = ChunkInfo {
+                            chunk_id,
+                            size: data.len(),
+                            data,
+                        };
+
+                        upload_chunk_with_retry(transport, params, &chunk, config, tracker, encode)
+                    }))
+                    .unwrap_or_else(|_| Err(AttemptError::retryable(UploadError::Other("Thread panic".to_string()))));
+
+                    if result_sender.send((chunk_id, result)).is_err() {
+                        break;
+                    }
+                }
+            });
         }
+        drop(result_sender);
 
-        // Process completed uploads
-        for (index, chunk_id) in completed_handles.into_iter().rev() {
-            let (_, handle) = handles.remove(index);
+        // Chunks sent to the workers whose result has not been received yet
+        let mut in_flight = 0;
 
-            // Always decrement active_uploads when a thread completes
-            {
+        loop {
+            let (limit, stopped) = {
                 let mut tracker = tracker.lock().unwrap();
-                tracker.active_uploads -= 1;
+
+                // Once cancelled or past the maximum duration, the chunks in flight finish without retries;
+                // with every chunk dispatched there is nothing left to stop
+                if !tracker.stopped && !chunk_ids.is_empty() {
+                    run.stop_reason = stop_reason(config.cancellation.as_ref(), config.max_duration, tracker.start_time);
+                    tracker.stopped = run.stop_reason.is_some();
+                }
+                (tracker.concurrency.limit(), tracker.stopped)
+            };
+
+            // Dispatch the next chunk if a slot is free and the rate limiter admits its bytes
+            let mut rate_wait = None;
+            if !stopped && in_flight < limit {
+                if let Some(&chunk_id) = chunk_ids.last() {
+                    let size = source
+                        .chunk_range(chunk_id as usize)
+                        .map_or(source.chunk_size(), |(_, size)| size);
+
+                    match bucket.try_acquire(size) {
+                        Ok(()) => {
+                            chunk_ids.pop();
+                            if job_sender.send(chunk_id).is_err() {
+                                break;
+                            }
+                            in_flight += 1;
+                            continue;
+                        }
+                        Err(wait) => rate_wait = Some(wait),
+                    }
+                }
             }
 
-            match handle.join() {
-                Ok(Ok(())) => {
-                    successful_chunks.push(chunk_id);
+            // Wait for a result, or until the rate limiter admits the next chunk
+            let received = match (in_flight, rate_wait) {
+                (0, None) => break,
+                (0, Some(wait)) => {
+                    thread::sleep(wait);
+                    continue;
                 }
-                Ok(Err(e)) => {
-                    failed_chunks.insert(chunk_id, e);
+                (_, None) => match results.recv() {
+                    Ok(received) => received,
+                    Err(_) => break,
+                },
+                (_, Some(wait)) => match results.recv_timeout(wait) {
+                    Ok(received) => received,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+            };
+
+            in_flight -= 1;
+            match received {
+                (chunk_id, Ok(reply)) => {
+                    run.replies.push((chunk_id, reply));
                 }
-                Err(_) => {
-                    failed_chunks.insert(chunk_id, "Thread panic".to_string());
+                (chunk_id, Err(e)) => {
+                    notify(&config.observer, || ProgressEvent::ChunkFailed { chunk_id, error: e.error.clone() });
+                    if e.is_fatal() && run.fatal_error.is_none() {
+                        run.fatal_error = Some(e.error.clone());
+                    }
+                    run.failed_chunks.insert(chunk_id, e.error);
                 }
             }
+
+            notify(&config.observer, || {
+                let tracker = tracker.lock().unwrap();
+                ProgressEvent::RateSample {
+                    rate_mibs: tracker.current_rate_mibs(),
+                    concurrency: tracker.concurrency.limit(),
+                }
+            });
+        }
+
+        // Closing the job channel lets the workers finish
+        drop(job_sender);
+    });
+
+    // After a fatal failure or once stopped early, the chunks not yet started are reported as failed
+    let reason = match run.stop_reason {
+        Some(reason) if run.fatal_error.is_none() => reason.to_string(),
+        _ => "the upload stopped after a fatal error".to_string(),
+    };
+    for chunk_id in chunk_ids.drain(..) {
+        run.failed_chunks.insert(chunk_id, UploadError::NotAttempted(reason.clone()));
+    }
+
+    {
+        let tracker = tracker.lock().unwrap();
+        run.bytes_uploaded = tracker.bytes_uploaded as u64;
+        run.retries = tracker.retries;
+        run.duration = tracker.start_time.elapsed();
+    }
+
+    run
+}
+
+/// A chunk read and encoded ahead of its call: its size and call argument
+type PreparedChunk = (u32, Result<(usize, Vec<u8>), AttemptError>);
+
+/// Runs the pipelined upload loop: chunk calls are made one at a time in
+/// ascending order, while a pool of `max_concurrent` workers reads and encodes
+/// the chunks after the one being sent.
+///
+/// A chunk that fails stops the upload, since the chunks after it cannot be
+/// appended past the gap; they are reported as failed without being sent.
+pub(crate) fn run_pipelined<T: Transport + ?Sized, S: ChunkSource + ?Sized>(
+    transport: &T,
+    params: &UploadParams<'_>,
+    source: &S,
+    mut chunk_ids: Vec<u32>,
+    config: &ParallelUploadConfig,
+    encode: &ChunkEncoder,
+) -> ParallelRun {
+    chunk_ids.sort_unstable();
+
+    let tracker = Mutex::new(UploadTracker::new(config));
+    let bucket = TokenBucket::new(config.target_rate_mibs, config.burst_mib);
+    let mut run = ParallelRun::default();
+    let depth = config.max_concurrent.max(1).min(chunk_ids.len());
+
+    // Chunk calls are made one at a time; `depth` workers prepare the chunks ahead
+    notify(&config.observer, || ProgressEvent::Started {
+        chunks: chunk_ids.len(),
+        workers: 1,
+    });
+
+    let (job_sender, job_receiver) = mpsc::channel::<u32>();
+    let (result_sender, results) = mpsc::channel::<PreparedChunk>();
+    let job_receiver = Mutex::new(job_receiver);
+    let mut failed_at = None;
+    let mut stopped_at = None;
+
+    thread::scope(|scope| {
+        for _ in 0..depth {
+            let result_sender = result_sender.clone();
+            let job_receiver = &job_receiver;
+
+            scope.spawn(move || {
+                while let Some(chunk_id) = next_job(job_receiver) {
+                    let prepared = panic::catch_unwind(AssertUnwindSafe(|| {
+                        let data = source.read_chunk(chunk_id as usize).map_err(|e| {
+                            AttemptError::fatal(UploadError::io(format!("Failed to read chunk {}", chunk_id), e))
+                        })?;
+                        let candid_args = encode(chunk_id, &data).map_err(AttemptError::fatal)?;
+                        Ok((data.len(), candid_args))
+                    }))
+                    .unwrap_or_else(|_| Err(AttemptError::retryable(UploadError::Other("Thread panic".to_string()))));
+
+                    if result_sender.send((chunk_id, prepared)).is_err() {
+                        break;
+                    }
+                }
+            });
         }
+        drop(result_sender);
+
+        // Chunks prepared out of order wait here until their turn
+        let mut prepared: HashMap<u32, Result<(usize, Vec<u8>), AttemptError>> = HashMap::new();
+        let mut next_to_prepare = 0;
 
-        // Rate limiting delay
-        let delay = {
-            let tracker = tracker.lock().unwrap();
-            if let Some(rate_callback) = config.rate_callback {
-                rate_callback(tracker.current_rate_mibs());
+        for (position, &chunk_id) in chunk_ids.iter().enumerate() {
+            let start_time = tracker.lock().unwrap().start_time;
+            if let Some(reason) = stop_reason(config.cancellation.as_ref(), config.max_duration, start_time) {
+                stopped_at = Some((position, reason));
+                break;
             }
-            tracker.calculate_delay(config)
-        };
 
-        thread::sleep(delay);
+            // Keep the chunk being sent and up to `depth - 1` after it prepared or in preparation
+            while next_to_prepare < chunk_ids.len() && next_to_prepare < position + depth {
+                if job_sender.send(chunk_ids[next_to_prepare]).is_err() {
+                    break;
+                }
+                next_to_prepare += 1;
+            }
 
-        // Check if we're done
-        let (chunks_empty, no_active) = {
-            let chunks_lock = chunks_remaining.lock().unwrap();
-            let tracker_lock = tracker.lock().unwrap();
-            (chunks_lock.is_empty(), tracker_lock.active_uploads == 0)
-        };
+            let chunk = loop {
+                if let Some(chunk) = prepared.remove(&chunk_id) {
+                    break chunk;
+                }
+                match results.recv() {
+                    Ok((prepared_id, chunk)) => {
+                        prepared.insert(prepared_id, chunk);
+                    }
+                    Err(_) => break Err(AttemptError::retryable(UploadError::Other("Chunk preparation stopped".to_string()))),
+                }
+            };
 
-        // SIMPLE COMPLETION CHECK: All chunks are accounted for (success + failure)
-        let total_completed = successful_chunks.len() + failed_chunks.len();
-        if total_completed >= total_chunks_expected as usize {
-            break;
+            let result = chunk.and_then(|(size, candid_args)| {
+                bucket.acquire(size);
+                submit_chunk_with_retry(transport, params, chunk_id, size, &candid_args, config, &tracker)
+            });
+
+            match result {
+                Ok(reply) => run.replies.push((chunk_id, reply)),
+                Err(e) => {
+                    notify(&config.observer, || ProgressEvent::ChunkFailed { chunk_id, error: e.error.clone() });
+                    if e.is_fatal() {
+                        run.fatal_error = Some(e.error.clone());
+                    }
+                    run.failed_chunks.insert(chunk_id, e.error);
+                    if config.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled) {
+                        stopped_at = Some((position + 1, StopReason::Cancelled));
+                    } else {
+                        failed_at = Some(position);
+                    }
+                }
+            }
+
+            notify(&config.observer, || ProgressEvent::RateSample {
+                rate_mibs: tracker.lock().unwrap().current_rate_mibs(),
+                concurrency: 1,
+            });
+            if failed_at.is_some() || stopped_at.is_some() {
+                break;
+            }
         }
 
-        if chunks_empty && no_active && handles.is_empty() {
-            break;
+        // Closing the job channel lets the workers finish
+        drop(job_sender);
+    });
+
+    // The chunks after a failed one cannot be appended past the gap
+    if let Some(position) = failed_at {
+        for &chunk_id in &chunk_ids[position + 1..] {
+            run.failed_chunks.insert(
+                chunk_id,
+                UploadError::NotAttempted(format!("chunk {} before it failed", chunk_ids[position])),
+            );
+        }
+    }
+    if let Some((position, reason)) = stopped_at {
+        run.stop_reason = Some(reason);
+        for &chunk_id in &chunk_ids[position..] {
+            run.failed_chunks.insert(chunk_id, UploadError::NotAttempted(reason.to_string()));
         }
     }
 
-    // Final rate report
     {
         let tracker = tracker.lock().unwrap();
-        let final_rate = tracker.current_rate_mibs();
-        let total_mb = tracker.bytes_uploaded as f64 / (1024.0 * 1024.0);
-        println!("Upload completed. Final rate: {:.2} MiB/s, Total: {:.2} MiB",
-                 final_rate, total_mb);
-    }
-
-    // Check completion and force exit before returning results
-    if failed_chunks.is_empty() {
-        // All chunks succeeded, exit cleanly
-        println!("✅ All {} chunks uploaded successfully!", successful_chunks.len());
-        std::process::exit(0);
-    } else if successful_chunks.is_empty() {
-        println!("❌ All chunks failed");
-        std::process::exit(1);
-    } else {
-        println!("❌ Upload completed with {} successes and {} failures",
-                 successful_chunks.len(), failed_chunks.len());
-        std::process::exit(1);
+        run.bytes_uploaded = tracker.bytes_uploaded as u64;
+        run.retries = tracker.retries;
+        run.duration = tracker.start_time.elapsed();
     }
+
+    run
 }
 
-/// Convert regular chunks to ChunkInfo with sequential IDs
+/// Takes the next chunk ID from the job channel shared by the workers
+fn next_job(jobs: &Mutex<Receiver<u32>>) -> Option<u32> {
+    jobs.lock().unwrap().recv().ok()
+}
+
+/// Convert regular chunks to ChunkInfo with sequential IDs starting from 0
+///
+/// Every chunk is copied, so the file is held in memory twice; the uploaders
+/// read chunks lazily from a [`ChunkSource`] instead.
 ///
 /// # Arguments
 ///
 /// * `chunks` - Vector of raw chunk data
-/// * `start_id` - Starting chunk ID (for resume scenarios)
 ///
 /// # Returns
 ///
 /// Vector of ChunkInfo with assigned IDs
+#[deprecated(note = "copies every chunk; upload from a `ChunkSource`, which reads chunks as they are sent")]
 pub fn chunks_to_chunk_info(chunks: &[Vec<u8>]) -> Vec<ChunkInfo> {
     chunks
         .iter()
@@ -450,8 +885,11 @@ pub fn chunks_to_chunk_info(chunks: &[Vec<u8>]) -> Vec<ChunkInfo> {
 
 
 #[cfg(test)]
+#[allow(deprecated)]
 mod tests {
     use super::*;
+    use crate::transport::{fast_config, test_params, CallError, MockTransport};
+    use candid::Decode;
 
     #[test]
     fn test_candid_args_format() {
@@ -474,6 +912,464 @@ mod tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_binary_args_match_text_encoding() {
+        let cases = [(0u32, vec![]), (0, vec![0x00, 0x01, 0x02]), (5, vec![0xFF]), (u32::MAX, vec![0xAB; 512])];
+
+        for (chunk_id, data) in cases {
+            let from_text = candid_parser::parse_idl_args(&chunk_with_id_to_candid_args(chunk_id, &data))
+                .unwrap()
+                .to_bytes()
+                .unwrap();
+            assert_eq!(encode_chunk_with_id_args(chunk_id, &data).unwrap(), from_text);
+        }
+    }
+
+    #[test]
+    fn test_binary_args_round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        let encoded = encode_chunk_with_id_args(42, &data).unwrap();
+
+        let (chunk_id, decoded) = Decode!(&encoded, u32, serde_bytes::ByteBuf).unwrap();
+        assert_eq!(chunk_id, 42);
+        assert_eq!(decoded.into_vec(), data);
+    }
+
+    #[test]
+    fn test_parallel_upload_reports_success() {
+        let transport = MockTransport::new();
+        let chunks = vec![vec![1, 2], vec![3, 4], vec![5]];
+        let config = ParallelUploadConfig {
+            finalize: Some(CanisterCall::without_args("save_to_stable")),
+            ..fast_config()
+        };
+
+        let report = match upload_chunks_parallel(&transport, &test_params(), &chunks, vec![2, 1, 0], &config) {
+            ParallelUploadResult::Success(report) => report,
+            other => panic!("expected success, got {:?}", other),
+        };
+        assert_eq!(report.successful_chunks, vec![0, 1, 2]);
+        assert_eq!((report.bytes_uploaded, report.retries), (5, 0));
+        assert!(report.failed_chunks.is_empty());
+
+        let calls = transport.calls();
+        assert_eq!(calls.len(), 4);
+        assert_eq!(calls.last().unwrap().method, "save_to_stable");
+    }
+
+    #[test]
+    fn test_parallel_upload_reports_failed_chunks() {
+        // Chunk 1 fails every attempt, the others succeed
+        let transport = MockTransport::with_responder(|call| {
+            let (chunk_id, _) = Decode!(&call.args, u32, serde_bytes::ByteBuf).unwrap();
+            if chunk_id == 1 {
+                Err(CallError::Transport("connection reset".to_string()))
+            } else {
+                Ok(crate::transport::EMPTY_CANDID_REPLY.to_vec())
+            }
+        });
+        let chunks = vec![vec![1], vec![2], vec![3]];
+        let config = ParallelUploadConfig {
+            finalize: Some(CanisterCall::without_args("save_to_stable")),
+            ..fast_config()
+        };
+
+        let report = match upload_chunks_parallel(&transport, &test_params(), &chunks, vec![2, 1, 0], &config) {
+            ParallelUploadResult::PartialFailure(report) => report,
+            other => panic!("expected partial failure, got {:?}", other),
+        };
+        assert_eq!(report.successful_chunks, vec![0, 2]);
+        assert_eq!(report.failed_chunk_ids(), vec![1]);
+        assert!(report.failed_chunks[&1].to_string().contains("connection reset"));
+        assert_eq!((report.bytes_uploaded, report.retries), (2, 1));
+        assert!(report.fatal_error.is_none());
+
+        // Finalizing waits until every chunk made it
+        assert!(transport.calls().iter().all(|call| call.method != "save_to_stable"));
+    }
+
+    #[test]
+    fn test_parallel_upload_returns_finalize_failure() {
+        let transport = MockTransport::with_responder(|call| match call.method.as_str() {
+            "save_to_stable" => Err(CallError::Transport("connection reset".to_string())),
+            _ => Ok(crate::transport::EMPTY_CANDID_REPLY.to_vec()),
+        });
+        let chunks = vec![vec![1]];
+        let config = ParallelUploadConfig {
+            finalize: Some(CanisterCall::without_args("save_to_stable")),
+            ..fast_config()
+        };
+
+        let result = upload_chunks_parallel(&transport, &test_params(), &chunks, vec![0], &config);
+        assert!(matches!(result, ParallelUploadResult::Failed(e) if e.to_string().contains("save_to_stable")));
+    }
+
+    #[test]
+    fn test_parallel_upload_commits_session_before_finalize_and_verify() {
+        use crate::session::begin_upload;
+        use sha2::{Digest, Sha256};
+
+        let transport = MockTransport::with_responder(|call| match call.method.as_str() {
+            "begin_upload" => Ok(Encode!(&9u64).unwrap()),
+            "commit_upload" => Ok(Encode!(&Ok::<(), String>(())).unwrap()),
+            "sha256" => Ok(Encode!(&serde_bytes::ByteBuf::from(Sha256::digest([1, 2, 3]).to_vec())).unwrap()),
+            _ => Ok(crate::transport::EMPTY_CANDID_REPLY.to_vec()),
+        });
+        let chunks = vec![vec![1], vec![2], vec![3]];
+        let session_id = begin_upload(&transport, &test_params(), "test file", &chunks).unwrap();
+        let config = ParallelUploadConfig {
+            session_id: Some(session_id),
+            finalize: Some(CanisterCall::without_args("save_to_stable")),
+            verify: Some(CanisterCall::without_args("sha256")),
+            ..fast_config()
+        };
+
+        let result = upload_chunks_parallel(&transport, &test_params(), &chunks, vec![0, 1, 2], &config);
+        assert!(matches!(result, ParallelUploadResult::Success(_)), "{:?}", result);
+
+        let methods: Vec<_> = transport.calls().into_iter().map(|call| call.method).collect();
+        assert_eq!(
+            methods,
+            ["begin_upload", "append_chunk", "append_chunk", "append_chunk", "commit_upload", "save_to_stable", "sha256"]
+        );
+    }
+
+    #[test]
+    fn test_worker_pool_bounds_threads_and_calls_in_flight() {
+        use std::collections::HashSet;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+
+        let transport = {
+            let (in_flight, most_in_flight, threads) = (in_flight.clone(), most_in_flight.clone(), threads.clone());
+            MockTransport::with_responder(move |_| {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most_in_flight.fetch_max(now, Ordering::SeqCst);
+                threads.lock().unwrap().insert(thread::current().id());
+                thread::sleep(Duration::from_micros(100));
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(crate::transport::EMPTY_CANDID_REPLY.to_vec())
+            })
+        };
+        let chunks = vec![vec![0u8; 4]; 2000];
+        let config = ParallelUploadConfig {
+            min_concurrent: 4,
+            max_concurrent: 4,
+            ..fast_config()
+        };
+
+        let run = run_parallel(&transport, &test_params(), &chunks, (0..2000).collect(), &config, &encode_chunk_with_id_args);
+
+        assert_eq!(run.replies.len(), 2000);
+        assert!(run.failed_chunks.is_empty());
+        assert_eq!(run.bytes_uploaded, 8000);
+        assert!(most_in_flight.load(Ordering::SeqCst) <= 4);
+        assert!(threads.lock().unwrap().len() <= 4);
+    }
+
+    #[test]
+    fn test_fatal_error_stops_parallel_run() {
+        let transport = MockTransport::with_responder(|_| {
+            Err(CallError::Rejected {
+                reject_code: Some(5),
+                error_code: Some("IC0536".to_string()),
+                message: "Canister has no update method 'append_chunk'".to_string(),
+            })
+        });
+        let chunks = vec![vec![1], vec![2], vec![3]];
+
+        let run = run_parallel(&transport, &test_params(), &chunks, vec![2, 1, 0], &fast_config(), &encode_chunk_with_id_args);
+
+        assert!(matches!(run.fatal_error, Some(UploadError::Chunk { retryable: false, .. })));
+        assert_eq!(run.failed_chunks.len(), 3);
+        assert!(matches!(run.failed_chunks[&2], UploadError::NotAttempted(_)));
+        assert_eq!(transport.calls().len(), 1);
+    }
+
+    #[test]
+    fn test_dispatch_order() {
+        let mut chunk_ids = vec![3, 0, 2, 1];
+        DispatchOrder::Descending.sort(&mut chunk_ids);
+        assert_eq!(chunk_ids, vec![3, 2, 1, 0]);
+
+        // Odd chunks first, ties in ascending order
+        DispatchOrder::Priority(|chunk_id| (chunk_id % 2 == 0) as u64).sort(&mut chunk_ids);
+        assert_eq!(chunk_ids, vec![1, 3, 0, 2]);
+
+        // A single worker makes the calls in dispatch order
+        let transport = MockTransport::new();
+        let chunks = vec![vec![1], vec![2], vec![3]];
+        let config = ParallelUploadConfig {
+            max_concurrent: 1,
+            dispatch_order: DispatchOrder::Descending,
+            ..fast_config()
+        };
+
+        let run = run_parallel(&transport, &test_params(), &chunks, vec![0, 1, 2], &config, &encode_chunk_with_id_args);
+        assert!(run.failed_chunks.is_empty());
+
+        let sent: Vec<u32> = transport
+            .calls()
+            .iter()
+            .map(|call| Decode!(&call.args, u32, serde_bytes::ByteBuf).unwrap().0)
+            .collect();
+        assert_eq!(sent, vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn test_pipelined_upload_appends_in_order() {
+        let transport = MockTransport::new();
+        let chunks: Vec<Vec<u8>> = (0..50u8).map(|i| vec![i; 3]).collect();
+        let config = ParallelUploadConfig {
+            max_concurrent: 8,
+            pipelined: true,
+            ..fast_config()
+        };
+
+        let report = match upload_chunks_parallel(&transport, &test_params(), &chunks, (0..50).rev().collect(), &config) {
+            ParallelUploadResult::Success(report) => report,
+            other => panic!("expected success, got {:?}", other),
+        };
+        assert_eq!(report.bytes_uploaded, 150);
+
+        // Chunks go out one at a time, in order, as plain blobs
+        let sent: Vec<Vec<u8>> = transport
+            .calls()
+            .iter()
+            .map(|call| Decode!(&call.args, serde_bytes::ByteBuf).unwrap().into_vec())
+            .collect();
+        assert_eq!(sent, chunks);
+    }
+
+    #[test]
+    fn test_pipelined_upload_rejects_reordering_and_gaps() {
+        let transport = MockTransport::new();
+        let chunks: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i]).collect();
+        let config = ParallelUploadConfig {
+            pipelined: true,
+            prepare: Some(CanisterCall::without_args("clear_buffer")),
+            ..fast_config()
+        };
+
+        let descending = ParallelUploadConfig {
+            dispatch_order: DispatchOrder::Descending,
+            ..config.clone()
+        };
+        let result = upload_chunks_parallel(&transport, &test_params(), &chunks, (0..6).collect(), &descending);
+        assert!(matches!(result, ParallelUploadResult::Failed(e) if e.to_string().contains("ascending order")));
+
+        let result = upload_chunks_parallel(&transport, &test_params(), &chunks, vec![5, 2, 3], &config);
+        assert!(matches!(result, ParallelUploadResult::Failed(e) if e.to_string().contains("from chunk 3 to chunk 5")));
+
+        // Nothing is sent, not even the prepare call
+        assert!(transport.calls().is_empty());
+        assert!(matches!(
+            upload_chunks_parallel(&transport, &test_params(), &chunks, vec![4, 2, 3], &config),
+            ParallelUploadResult::Success(_)
+        ));
+    }
+
+    #[test]
+    fn test_pipelined_upload_stops_at_failed_chunk() {
+        let transport = MockTransport::with_responder(|call| {
+            let data = Decode!(&call.args, serde_bytes::ByteBuf).unwrap();
+            if data[0] == 2 {
+                Err(CallError::Transport("connection reset".to_string()))
+            } else {
+                Ok(crate::transport::EMPTY_CANDID_REPLY.to_vec())
+            }
+        });
+        let chunks: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i]).collect();
+        let config = ParallelUploadConfig {
+            max_concurrent: 4,
+            pipelined: true,
+            ..fast_config()
+        };
+
+        let report = match upload_chunks_parallel(&transport, &test_params(), &chunks, (0..6).collect(), &config) {
+            ParallelUploadResult::PartialFailure(report) => report,
+            other => panic!("expected partial failure, got {:?}", other),
+        };
+        assert_eq!(report.successful_chunks, vec![0, 1]);
+        assert_eq!(report.failed_chunk_ids(), vec![2, 3, 4, 5]);
+        assert!(report.failed_chunks[&2].to_string().contains("connection reset"));
+        assert!(matches!(report.failed_chunks[&5], UploadError::NotAttempted(_)));
+
+        // Two chunks, then two attempts of the failed one and nothing after it
+        assert_eq!(transport.calls().len(), 4);
+    }
+
+    #[test]
+    fn test_parallel_upload_stops_at_max_duration() {
+        let transport = MockTransport::with_responder(|_| {
+            thread::sleep(Duration::from_millis(30));
+            Ok(crate::transport::EMPTY_CANDID_REPLY.to_vec())
+        });
+        let chunks = vec![vec![1]; 40];
+
+        for pipelined in [false, true] {
+            let config = ParallelUploadConfig {
+                max_concurrent: 2,
+                finalize: Some(CanisterCall::without_args("save_to_stable")),
+                max_duration: Some(Duration::from_millis(50)),
+                pipelined,
+                ..fast_config()
+            };
+
+            let report = match upload_chunks_parallel(&transport, &test_params(), &chunks, (0..40).collect(), &config) {
+                ParallelUploadResult::Stopped(report) => report,
+                other => panic!("expected the time limit, got {:?}", other),
+            };
+            assert!(!report.successful_chunks.is_empty());
+            assert_eq!(report.successful_chunks.len() + report.failed_chunks.len(), 40);
+            assert!(report.failed_chunks.values().all(|error| error.to_string().contains("maximum duration")));
+        }
+
+        assert!(transport.calls().iter().all(|call| call.method != "save_to_stable"));
+    }
+
+    #[test]
+    fn test_parallel_upload_completes_when_time_limit_passes_on_last_chunk() {
+        let transport = MockTransport::with_responder(|_| {
+            thread::sleep(Duration::from_millis(60));
+            Ok(crate::transport::EMPTY_CANDID_REPLY.to_vec())
+        });
+        let chunks = vec![vec![1]; 2];
+        let config = ParallelUploadConfig {
+            max_concurrent: 2,
+            min_concurrent: 2,
+            finalize: Some(CanisterCall::without_args("save_to_stable")),
+            max_duration: Some(Duration::from_millis(20)),
+            ..fast_config()
+        };
+
+        let report = match upload_chunks_parallel(&transport, &test_params(), &chunks, vec![0, 1], &config) {
+            ParallelUploadResult::Success(report) => report,
+            other => panic!("expected the upload to complete, got {:?}", other),
+        };
+        assert_eq!(report.successful_chunks, vec![0, 1]);
+        assert_eq!(report.stop_reason, None);
+        assert!(transport.calls().iter().any(|call| call.method == "save_to_stable"));
+    }
+
+    #[test]
+    fn test_parallel_upload_stops_when_cancelled() {
+        for pipelined in [false, true] {
+            let token = CancellationToken::new();
+            let transport = {
+                let token = token.clone();
+                MockTransport::with_responder(move |call| {
+                    if call.args.ends_with(&[3]) {
+                        token.cancel();
+                    }
+                    Ok(crate::transport::EMPTY_CANDID_REPLY.to_vec())
+                })
+            };
+            let chunks: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i]).collect();
+            let config = ParallelUploadConfig {
+                max_concurrent: 1,
+                cancellation: Some(token),
+                pipelined,
+                ..fast_config()
+            };
+
+            let report = match upload_chunks_parallel(&transport, &test_params(), &chunks, (0..20).collect(), &config) {
+                ParallelUploadResult::Stopped(report) => report,
+                other => panic!("expected a cancelled upload, got {:?}", other),
+            };
+            // The chunk in flight when the token was cancelled still completes
+            assert_eq!(report.stop_reason, Some(StopReason::Cancelled));
+            assert_eq!(report.successful_chunks, vec![0, 1, 2, 3]);
+            assert_eq!(report.failed_chunk_ids(), (4..20).collect::<Vec<_>>());
+            assert!(report.failed_chunks[&4].to_string().contains("cancelled"));
+        }
+    }
+
+    #[test]
+    fn test_sequential_and_parallel_uploads_emit_the_same_events() {
+        use crate::progress::{ProgressEvent, SharedObserver};
+        use crate::{upload_chunks_with_resume, UploadConfig};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // The first attempt of chunk 1 fails, every other call succeeds
+        fn flaky_transport() -> MockTransport {
+            let failed = AtomicBool::new(false);
+            MockTransport::with_responder(move |call| {
+                if call.args.ends_with(&[1]) && !failed.swap(true, Ordering::SeqCst) {
+                    Err(CallError::Transport("connection reset".to_string()))
+                } else {
+                    Ok(crate::transport::EMPTY_CANDID_REPLY.to_vec())
+                }
+            })
+        }
+
+        fn recorder() -> (SharedObserver, Arc<Mutex<Vec<String>>>) {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let observer = {
+                let events = events.clone();
+                SharedObserver::new(move |event: &ProgressEvent| {
+                    let summary = match event {
+                        ProgressEvent::Started { chunks, workers } => format!("upload {} {}", chunks, workers),
+                        ProgressEvent::ChunkStarted { chunk_id, size } => format!("started {} {}", chunk_id, size),
+                        ProgressEvent::ChunkSucceeded { chunk_id, attempts, .. } => format!("succeeded {} {}", chunk_id, attempts),
+                        ProgressEvent::ChunkRetry { chunk_id, attempt, .. } => format!("retry {} {}", chunk_id, attempt),
+                        ProgressEvent::ChunkFailed { chunk_id, .. } => format!("failed {}", chunk_id),
+                        ProgressEvent::JournalFailed { chunk_id, .. } => format!("journal failed {}", chunk_id),
+                        ProgressEvent::RateSample { concurrency, .. } => format!("rate {}", concurrency),
+                        ProgressEvent::Finished { report } => {
+                            format!("finished {:?} {} {}", report.successful_chunks, report.bytes_uploaded, report.retries)
+                        }
+                        ProgressEvent::SessionCommitted { session_id } => format!("committed {}", session_id),
+                        ProgressEvent::HookSucceeded { stage, method, .. } => format!("{} {}", stage, method),
+                        ProgressEvent::Verified { method, .. } => format!("verified {}", method),
+                    };
+                    events.lock().unwrap().push(summary);
+                })
+            };
+            (observer, events)
+        }
+
+        let chunks = vec![vec![0], vec![1], vec![2]];
+        let (observer, sequential) = recorder();
+        let config = UploadConfig {
+            observer: Some(observer),
+            prepare: Some(CanisterCall::without_args("clear")),
+            ..UploadConfig::default().with_retry_policy(RetryPolicy::fixed(Duration::ZERO))
+        };
+        assert!(matches!(
+            upload_chunks_with_resume(&flaky_transport(), &test_params(), &chunks, 0, &config),
+            crate::ChunkUploadResult::Success
+        ));
+
+        let expected = [
+            "Prepare clear", "upload 3 1",
+            "started 0 1", "succeeded 0 1", "rate 1",
+            "started 1 1", "retry 1 1", "succeeded 1 2", "rate 1",
+            "started 2 1", "succeeded 2 1", "rate 1",
+            "finished [0, 1, 2] 3 1",
+        ];
+        assert_eq!(*sequential.lock().unwrap(), expected);
+
+        for pipelined in [false, true] {
+            let (observer, parallel) = recorder();
+            let config = ParallelUploadConfig {
+                max_concurrent: 1,
+                pipelined,
+                observer: Some(observer),
+                prepare: Some(CanisterCall::without_args("clear")),
+                ..fast_config()
+            };
+            assert!(matches!(
+                upload_chunks_parallel(&flaky_transport(), &test_params(), &chunks, vec![0, 1, 2], &config),
+                ParallelUploadResult::Success(_)
+            ));
+            assert_eq!(*parallel.lock().unwrap(), expected, "pipelined: {}", pipelined);
+        }
+    }
+
     #[test]
     fn test_chunk_info_sequential_ids() {
         let chunks = vec![
@@ -528,6 +1424,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::useless_vec)]
     fn test_retry_chunks_filter() {
         let chunks = vec![
             vec![1, 2],    // chunk_id: 0
@@ -0,0 +1,213 @@
+//! Progress events of the upload pipelines
+//!
+//! The sequential and the parallel uploader report what they do as
+//! [`ProgressEvent`]s, the same events in the same situations, so one
+//! [`ProgressObserver`] can drive a progress bar, a log or metrics for either.
+//! Downloads report their pieces with the same chunk events.
+//! Any `Fn(&ProgressEvent)` closure that is `Send` and `Sync` is an observer
+//! and may capture state; the chunk events of a parallel upload arrive from its
+//! worker threads.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cancel::StopReason;
+use crate::error::UploadError;
+use crate::session::SessionId;
+
+/// Something that happened during an upload
+#[derive(Debug, Clone)]
+pub enum ProgressEvent {
+    /// The chunks to send are known; sent once, before the first chunk
+    Started {
+        /// Chunks this upload or download sends
+        chunks: usize,
+        /// Chunk calls that may be in flight at once
+        workers: usize,
+    },
+    /// The first attempt of a chunk is about to be sent
+    ChunkStarted {
+        /// Index of the chunk in the file (0-based)
+        chunk_id: u32,
+        /// Bytes of chunk data
+        size: usize,
+    },
+    /// The canister acknowledged a chunk
+    ChunkSucceeded {
+        /// Index of the chunk in the file (0-based)
+        chunk_id: u32,
+        /// Bytes of chunk data
+        size: usize,
+        /// Attempts it took, counting the successful one
+        attempts: usize,
+        /// Duration of the successful attempt
+        latency: Duration,
+    },
+    /// An attempt of a chunk failed and the chunk is sent again after `delay`
+    ChunkRetry {
+        /// Index of the chunk in the file (0-based)
+        chunk_id: u32,
+        /// The attempt that failed, counting from 1
+        attempt: usize,
+        /// Attempts allowed per chunk
+        max_attempts: usize,
+        /// Time until the next attempt
+        delay: Duration,
+        /// Why the attempt failed
+        error: UploadError,
+    },
+    /// A chunk failed for good
+    ChunkFailed {
+        /// Index of the chunk in the file (0-based)
+        chunk_id: u32,
+        /// Why it failed, usually an [`UploadError::Chunk`] with the number of attempts
+        error: UploadError,
+    },
+    /// A chunk was acknowledged but could not be recorded in the journal, so a
+    /// resumed upload sends it again
+    JournalFailed {
+        /// Index of the chunk in the file (0-based)
+        chunk_id: u32,
+        /// Why the journal could not be written
+        error: UploadError,
+    },
+    /// The average upload rate so far, sampled whenever a chunk is done
+    RateSample {
+        /// Acknowledged chunk data per second since the start, in MiB
+        rate_mibs: f64,
+        /// Chunk calls allowed in flight at once
+        concurrency: usize,
+    },
+    /// Every chunk was done or the upload stopped; sent before any finalize or verify call
+    Finished {
+        /// What the upload did
+        report: UploadReport,
+    },
+    /// The upload session was committed; sent after [`ProgressEvent::Finished`]
+    SessionCommitted {
+        /// The committed session
+        session_id: SessionId,
+    },
+    /// A prepare or finalize call succeeded
+    HookSucceeded {
+        /// `Prepare` or `Finalize`
+        stage: &'static str,
+        /// The canister method called
+        method: String,
+        /// The reply rendered as Candid text
+        reply: String,
+    },
+    /// The canister's hashes match the local file
+    Verified {
+        /// SHA-256 of the whole file
+        sha256: [u8; 32],
+        /// The hash query that was checked
+        method: String,
+    },
+}
+
+/// Receives the [`ProgressEvent`]s of an upload
+pub trait ProgressObserver: Send + Sync {
+    /// Called for every event, from whichever thread it happened on
+    fn on_event(&self, event: &ProgressEvent);
+}
+
+impl<F: Fn(&ProgressEvent) + Send + Sync> ProgressObserver for F {
+    fn on_event(&self, event: &ProgressEvent) {
+        self(event)
+    }
+}
+
+/// A [`ProgressObserver`] shared by the upload configs and their worker threads
+#[derive(Clone)]
+pub struct SharedObserver(Arc<dyn ProgressObserver>);
+
+impl SharedObserver {
+    /// Shares `observer`
+    pub fn new(observer: impl ProgressObserver + 'static) -> Self {
+        Self(Arc::new(observer))
+    }
+
+    /// Delivers `event` to the observer
+    pub fn notify(&self, event: &ProgressEvent) {
+        self.0.on_event(event)
+    }
+}
+
+impl fmt::Debug for SharedObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedObserver")
+    }
+}
+
+/// Delivers the event built by `event` if there is an observer; without one the event is never built
+pub(crate) fn notify(observer: &Option<SharedObserver>, event: impl FnOnce() -> ProgressEvent) {
+    if let Some(observer) = observer {
+        observer.notify(&event());
+    }
+}
+
+/// What an upload did
+#[derive(Debug, Clone, Default)]
+pub struct UploadReport {
+    /// Successfully uploaded chunk IDs, in ascending order
+    pub successful_chunks: Vec<u32>,
+    /// Failed chunk IDs with errors
+    pub failed_chunks: HashMap<u32, UploadError>,
+    /// Bytes of chunk data the canister acknowledged
+    pub bytes_uploaded: u64,
+    /// Time spent uploading chunks
+    pub duration: Duration,
+    /// Failed attempts that were retried
+    pub retries: usize,
+    /// The failure that stopped the upload before every chunk was attempted
+    pub fatal_error: Option<UploadError>,
+    /// Why the upload stopped starting chunks before every chunk was attempted, if it did
+    pub stop_reason: Option<StopReason>,
+}
+
+impl UploadReport {
+    /// Average upload rate in MiB per second
+    pub fn rate_mibs(&self) -> f64 {
+        let seconds = self.duration.as_secs_f64();
+        if seconds > 0.0 {
+            self.bytes_uploaded as f64 / (1024.0 * 1024.0) / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// Failed chunk IDs in ascending order
+    pub fn failed_chunk_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.failed_chunks.keys().copied().collect();
+        ids.sort();
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_closures_observe_events() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let observer = {
+            let seen = seen.clone();
+            SharedObserver::new(move |event: &ProgressEvent| {
+                if let ProgressEvent::ChunkStarted { chunk_id, .. } = event {
+                    seen.lock().unwrap().push(*chunk_id);
+                }
+            })
+        };
+
+        notify(&Some(observer.clone()), || ProgressEvent::ChunkStarted { chunk_id: 3, size: 10 });
+        observer.notify(&ProgressEvent::RateSample { rate_mibs: 1.0, concurrency: 1 });
+        notify(&None, || unreachable!("events are only built for an observer"));
+
+        assert_eq!(*seen.lock().unwrap(), vec![3]);
+    }
+}
@@ -0,0 +1,144 @@
+//! Token-bucket rate limiting of chunk dispatch
+//!
+//! The bucket fills at the target rate up to the burst size, and a chunk is
+//! only sent once the bucket holds enough bytes for it. Unlike pacing by the
+//! average rate since the start, an idle period can never save up more than
+//! one burst, so the upload does not overshoot after a stall. A chunk larger
+//! than the burst is sent once the bucket is full and leaves it in debt, which
+//! delays the chunks after it accordingly.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Bytes in a MiB
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Default burst size in MiB
+pub const DEFAULT_BURST_MIB: f64 = 4.0;
+
+/// Tokens, in bytes, and when they were last topped up
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// A token bucket shared by every worker of an upload
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Refill rate in bytes per second; `None` when unlimited
+    rate: Option<f64>,
+    /// Capacity in bytes
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket for `rate_mibs` MiB/s with bursts of up to `burst_mib` MiB.
+    ///
+    /// A rate that is not positive and finite means no limit.
+    pub fn new(rate_mibs: f64, burst_mib: f64) -> Self {
+        let rate = (rate_mibs.is_finite() && rate_mibs > 0.0).then_some(rate_mibs * MIB);
+        let burst = burst_mib.max(0.0) * MIB;
+
+        Self {
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// A bucket that never delays a chunk
+    pub fn unlimited() -> Self {
+        Self::new(0.0, 0.0)
+    }
+
+    /// Takes tokens for `bytes` if the bucket holds them.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the chunk may be sent now, or how long to wait before asking again.
+    pub fn try_acquire(&self, bytes: usize) -> Result<(), Duration> {
+        self.try_acquire_at(bytes, Instant::now())
+    }
+
+    /// Blocks until the bucket holds tokens for `bytes` and takes them.
+    pub fn acquire(&self, bytes: usize) {
+        while let Err(wait) = self.try_acquire(bytes) {
+            thread::sleep(wait);
+        }
+    }
+
+    fn try_acquire_at(&self, bytes: usize, now: Instant) -> Result<(), Duration> {
+        let Some(rate) = self.rate else {
+            return Ok(());
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(state.refilled_at).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate).min(self.burst);
+        state.refilled_at = now;
+
+        // A chunk larger than the burst only needs a full bucket
+        let needed = (bytes as f64).min(self.burst);
+        if state.tokens >= needed {
+            state.tokens -= bytes as f64;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((needed - state.tokens) / rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB_BYTES: usize = 1024 * 1024;
+
+    #[test]
+    fn test_burst_then_rate() {
+        let bucket = TokenBucket::new(1.0, 2.0);
+        let start = Instant::now();
+
+        assert!(bucket.try_acquire_at(MIB_BYTES, start).is_ok());
+        assert!(bucket.try_acquire_at(MIB_BYTES, start).is_ok());
+
+        let wait = bucket.try_acquire_at(MIB_BYTES, start).unwrap_err();
+        assert!((wait.as_secs_f64() - 1.0).abs() < 1e-6, "{:?}", wait);
+
+        assert!(bucket.try_acquire_at(MIB_BYTES, start + Duration::from_millis(500)).is_err());
+        assert!(bucket.try_acquire_at(MIB_BYTES, start + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_idle_time_saves_at_most_one_burst() {
+        let bucket = TokenBucket::new(1.0, 2.0);
+        let later = Instant::now() + Duration::from_secs(60);
+
+        assert!(bucket.try_acquire_at(MIB_BYTES, later).is_ok());
+        assert!(bucket.try_acquire_at(MIB_BYTES, later).is_ok());
+        assert!(bucket.try_acquire_at(MIB_BYTES, later).is_err());
+    }
+
+    #[test]
+    fn test_chunks_larger_than_burst_go_into_debt() {
+        let bucket = TokenBucket::new(1.0, 1.0);
+        let start = Instant::now();
+
+        assert!(bucket.try_acquire_at(3 * MIB_BYTES, start).is_ok());
+
+        // Two seconds to pay off the debt, then one to refill for the next chunk
+        let wait = bucket.try_acquire_at(MIB_BYTES, start).unwrap_err();
+        assert!((wait.as_secs_f64() - 3.0).abs() < 1e-6, "{:?}", wait);
+
+        let unlimited = TokenBucket::unlimited();
+        for _ in 0..100 {
+            assert!(unlimited.try_acquire(usize::MAX).is_ok());
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Interpretation of canister replies
+//!
+//! A call that the replica accepted can still report a failure in its reply,
+//! e.g. `variant { Err = "out of memory" }` from a method returning
+//! `Result<(), String>`. A [`ReplyPolicy`] decides which replies count as a
+//! successful chunk, so such failures fail the chunk without being retried, since
+//! the same argument gets the same reply. Queries whose reply carries data,
+//! such as a size or a list of chunk IDs, unwrap it with [`reply_value`].
+
+use candid::{idl_hash, IDLArgs, IDLValue};
+
+use crate::create_error_string;
+use crate::error::UploadError;
+
+/// A kind of reply that can be declared to mean success
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyShape {
+    /// No reply values, `()`
+    Empty,
+    /// A `variant { Ok }`, with or without a payload
+    Ok,
+    /// The boolean `true`
+    True,
+    /// An `opt` with a value
+    Some,
+    /// Any number, e.g. the size stored so far
+    Number,
+    /// Any text
+    Text,
+}
+
+impl ReplyShape {
+    /// Whether a decoded reply has this shape
+    fn matches(self, args: &IDLArgs) -> bool {
+        let Some(value) = args.args.first() else {
+            return self == ReplyShape::Empty;
+        };
+
+        match self {
+            ReplyShape::Empty => false,
+            ReplyShape::Ok => variant_tag_is(value, "Ok"),
+            ReplyShape::True => matches!(value, IDLValue::Bool(true)),
+            ReplyShape::Some => matches!(value, IDLValue::Opt(_)),
+            ReplyShape::Number => matches!(
+                value,
+                IDLValue::Nat(_)
+                    | IDLValue::Nat8(_)
+                    | IDLValue::Nat16(_)
+                    | IDLValue::Nat32(_)
+                    | IDLValue::Nat64(_)
+                    | IDLValue::Int(_)
+                    | IDLValue::Int8(_)
+                    | IDLValue::Int16(_)
+                    | IDLValue::Int32(_)
+                    | IDLValue::Int64(_)
+                    | IDLValue::Number(_)
+            ),
+            ReplyShape::Text => matches!(value, IDLValue::Text(_)),
+        }
+    }
+}
+
+/// Which replies to a chunk call count as success
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyPolicy {
+    /// Treat `variant { Err = ... }` and `variant { Error = ... }` replies as failures
+    pub fail_on_err: bool,
+    /// Treat a `false` reply as a failure
+    pub fail_on_false: bool,
+    /// Reply shapes that mean success; any reply that is not a failure does when empty
+    pub success_shapes: Vec<ReplyShape>,
+}
+
+impl Default for ReplyPolicy {
+    fn default() -> Self {
+        Self {
+            fail_on_err: true,
+            fail_on_false: false,
+            success_shapes: Vec::new(),
+        }
+    }
+}
+
+impl ReplyPolicy {
+    /// Accepts every reply the replica delivered, as before replies were interpreted
+    pub fn accept_all() -> Self {
+        Self {
+            fail_on_err: false,
+            ..Self::default()
+        }
+    }
+
+    /// Checks a binary Candid reply against the policy.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the reply means success, or the error text the canister
+    /// reported (or a description of the unexpected reply) otherwise.
+    pub fn check(&self, reply: &[u8]) -> Result<(), String> {
+        let args = match IDLArgs::from_bytes(reply) {
+            Ok(args) => args,
+            Err(_) if self.success_shapes.is_empty() => return Ok(()),
+            Err(e) => return Err(format!("Undecodable reply: {}", e)),
+        };
+
+        if self.fail_on_err {
+            if let Some(error) = args.args.first().and_then(error_payload) {
+                return Err(format!("Canister returned an error: {}", error));
+            }
+        }
+
+        if self.fail_on_false && matches!(args.args.first(), Some(IDLValue::Bool(false))) {
+            return Err("Canister returned false".to_string());
+        }
+
+        if !self.success_shapes.is_empty() && !self.success_shapes.iter().any(|shape| shape.matches(&args)) {
+            return Err(format!("Unexpected reply {}", args));
+        }
+
+        Ok(())
+    }
+}
+
+/// The error a reply reports through an `Err` or `Error` variant, if it does
+pub fn reply_error(reply: &[u8]) -> Option<String> {
+    IDLArgs::from_bytes(reply).ok()?.args.first().and_then(error_payload)
+}
+
+/// Decodes the first value of a reply, unwrapping `opt` and the `Ok` variant of a `Result`.
+///
+/// # Arguments
+///
+/// * `reply` - The binary Candid reply.
+/// * `method` - The method that replied, or a description of the call, named in errors.
+///
+/// # Returns
+///
+/// The unwrapped value, an [`UploadError::Reply`] if the reply is `null` or
+/// another variant such as `Err`, or an [`UploadError::Candid`] if it cannot be decoded.
+pub fn reply_value(reply: &[u8], method: &str) -> Result<IDLValue, UploadError> {
+    let args = IDLArgs::from_bytes(reply)
+        .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to decode {} reply: {}", method, e))))?;
+    let mut value = args
+        .args
+        .into_iter()
+        .next()
+        .ok_or_else(|| UploadError::Candid(create_error_string(&format!("{} reply is empty", method))))?;
+
+    loop {
+        value = match value {
+            IDLValue::Opt(inner) => *inner,
+            IDLValue::None => return Err(UploadError::Reply(create_error_string(&format!("{} returned no data", method)))),
+            IDLValue::Variant(variant) if variant.0.id.get_id() == idl_hash("Ok") => variant.0.val,
+            IDLValue::Variant(variant) => {
+                return Err(UploadError::Reply(create_error_string(&format!(
+                    "{} returned an error: {}",
+                    method,
+                    payload_text(&variant.0.val)
+                ))));
+            }
+            other => return Ok(other),
+        };
+    }
+}
+
+/// The payload of an `Err` or `Error` variant; text payloads are returned unquoted
+fn error_payload(value: &IDLValue) -> Option<String> {
+    let IDLValue::Variant(variant) = value else {
+        return None;
+    };
+    if !variant_tag_is(value, "Err") && !variant_tag_is(value, "Error") {
+        return None;
+    }
+
+    Some(payload_text(&variant.0.val))
+}
+
+/// A variant payload as text, unquoted if it is text
+fn payload_text(value: &IDLValue) -> String {
+    match value {
+        IDLValue::Text(text) => text.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Whether a value is a variant with the given tag
+fn variant_tag_is(value: &IDLValue, tag: &str) -> bool {
+    matches!(value, IDLValue::Variant(variant) if variant.0.id.get_id() == idl_hash(tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::EMPTY_CANDID_REPLY;
+    use candid::{CandidType, Encode};
+
+    #[test]
+    fn test_default_policy_fails_on_err_variants() {
+        let policy = ReplyPolicy::default();
+
+        let err: Result<(), String> = Err("out of memory".to_string());
+        assert_eq!(policy.check(&Encode!(&err).unwrap()).unwrap_err(), "Canister returned an error: out of memory");
+
+        #[derive(CandidType)]
+        enum UploadResult {
+            #[allow(dead_code)]
+            Stored(u64),
+            Error { code: u32 },
+        }
+        let error = policy.check(&Encode!(&UploadResult::Error { code: 7 }).unwrap()).unwrap_err();
+        // Field names are only known as hashes without the method's types
+        assert!(error.starts_with("Canister returned an error: record {"), "{}", error);
+        assert!(error.contains("= 7 : nat32"), "{}", error);
+
+        assert!(policy.check(&Encode!(&Ok::<(), String>(())).unwrap()).is_ok());
+        assert!(policy.check(EMPTY_CANDID_REPLY).is_ok());
+        assert!(policy.check(&Encode!(&false).unwrap()).is_ok());
+        assert!(ReplyPolicy::accept_all().check(&Encode!(&err).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_reply_value_unwraps_opt_and_ok() {
+        let ok: Result<Option<u64>, String> = Ok(Some(42));
+        assert_eq!(reply_value(&Encode!(&ok).unwrap(), "size").unwrap(), IDLValue::Nat64(42));
+
+        let err: Result<u64, String> = Err("no such key".to_string());
+        let error = reply_value(&Encode!(&err).unwrap(), "size").unwrap_err();
+        assert!(matches!(&error, UploadError::Reply(message) if message.ends_with("size returned an error: no such key")));
+
+        let none: Option<u64> = None;
+        let error = reply_value(&Encode!(&none).unwrap(), "size").unwrap_err();
+        assert!(matches!(&error, UploadError::Reply(message) if message.ends_with("size returned no data")));
+
+        assert!(matches!(reply_value(b"not candid", "size"), Err(UploadError::Candid(_))));
+    }
+
+    #[test]
+    fn test_fail_on_false() {
+        let policy = ReplyPolicy {
+            fail_on_false: true,
+            ..Default::default()
+        };
+
+        assert_eq!(policy.check(&Encode!(&false).unwrap()).unwrap_err(), "Canister returned false");
+        assert!(policy.check(&Encode!(&true).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_declared_success_shapes() {
+        let policy = ReplyPolicy {
+            success_shapes: vec![ReplyShape::Empty, ReplyShape::Number],
+            ..Default::default()
+        };
+
+        assert!(policy.check(EMPTY_CANDID_REPLY).is_ok());
+        assert!(policy.check(&Encode!(&1024u64).unwrap()).is_ok());
+
+        let error = policy.check(&Encode!(&"stored").unwrap()).unwrap_err();
+        assert!(error.contains("Unexpected reply"), "{}", error);
+        assert!(policy.check(b"not candid").is_err());
+    }
+}
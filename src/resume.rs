@@ -0,0 +1,187 @@
+//! Remote-state-driven resume
+//!
+//! Instead of relying on local state, the uploader can ask the canister which
+//! chunks it already holds, through a query such as the demo backend's
+//! `parallel_chunk_ids` (a list of chunk IDs) or `buffer_size` (a byte count
+//! for sequential appends), and upload only what is missing.
+
+use std::collections::BTreeSet;
+
+use candid::IDLValue;
+
+use crate::error::UploadError;
+use crate::reply::reply_value;
+use crate::source::ChunkSource;
+use crate::transport::{Transport, EMPTY_CANDID_REPLY};
+use crate::{create_error_string, UploadParams};
+
+/// What a canister reports it already holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteState {
+    /// IDs of the chunks the canister has stored, e.g. from `parallel_chunk_ids`
+    ChunkIds(BTreeSet<u32>),
+    /// Number of bytes the canister has appended, e.g. from `buffer_size`
+    ByteCount(u64),
+}
+
+impl RemoteState {
+    /// Chunk IDs of `source` that still have to be uploaded, in ascending order.
+    pub fn missing_chunks<S: ChunkSource + ?Sized>(&self, source: &S) -> Result<Vec<u32>, UploadError> {
+        let chunk_count = source.chunk_count() as u32;
+        match self {
+            RemoteState::ChunkIds(present) => Ok((0..chunk_count).filter(|id| !present.contains(id)).collect()),
+            RemoteState::ByteCount(_) => Ok((self.sequential_start(source)? as u32..chunk_count).collect()),
+        }
+    }
+
+    /// First chunk of `source` a sequential (append-only) upload continues from.
+    ///
+    /// For a chunk ID list this is the first ID missing from the canister, since
+    /// appended data cannot have gaps. A byte count must end on a chunk boundary
+    /// or at the end of the source.
+    pub fn sequential_start<S: ChunkSource + ?Sized>(&self, source: &S) -> Result<usize, UploadError> {
+        let chunk_count = source.chunk_count();
+        match self {
+            RemoteState::ChunkIds(present) => {
+                Ok((0..chunk_count).find(|id| !present.contains(&(*id as u32))).unwrap_or(chunk_count))
+            }
+            RemoteState::ByteCount(bytes) if *bytes == source.total_bytes() => Ok(chunk_count),
+            RemoteState::ByteCount(bytes) if *bytes > source.total_bytes() => Err(UploadError::Verification(
+                create_error_string(&format!(
+                    "Canister holds {} bytes, more than the {} bytes being uploaded",
+                    bytes,
+                    source.total_bytes()
+                )),
+            )),
+            RemoteState::ByteCount(bytes) => {
+                let chunk_size = source.chunk_size() as u64;
+                if chunk_size == 0 || bytes % chunk_size != 0 {
+                    return Err(UploadError::Verification(create_error_string(&format!(
+                        "Canister holds {} bytes, which is not a multiple of the chunk size {}",
+                        bytes, chunk_size
+                    ))));
+                }
+                Ok((bytes / chunk_size) as usize)
+            }
+        }
+    }
+}
+
+/// Asks the canister which chunks it already holds.
+///
+/// # Arguments
+///
+/// * `transport` - The transport used to submit the query.
+/// * `params` - Upload parameters including canister info.
+/// * `query_method` - The query method to call. It takes no arguments.
+///
+/// # Returns
+///
+/// A `Result` containing the decoded remote state or an error message.
+pub fn query_remote_state<T: Transport + ?Sized>(
+    transport: &T,
+    params: &UploadParams,
+    query_method: &str,
+) -> Result<RemoteState, UploadError> {
+    let reply = transport
+        .query(params.canister_name, query_method, EMPTY_CANDID_REPLY)
+        .map_err(|e| UploadError::from(e).context(format!("Query {} failed", query_method)))?;
+
+    parse_remote_state(&reply)
+}
+
+/// Decodes the reply of a remote state query.
+///
+/// A `vec` of naturals is read as chunk IDs and a single natural as a byte
+/// count. Replies wrapped in `opt` or in the `Ok` variant of a `Result` are
+/// unwrapped first.
+pub fn parse_remote_state(reply: &[u8]) -> Result<RemoteState, UploadError> {
+    match reply_value(reply, "Query")? {
+        IDLValue::Vec(values) => values
+            .iter()
+            .map(|value| {
+                value_to_u64(value)
+                    .and_then(|id| u32::try_from(id).ok())
+                    .ok_or_else(|| UploadError::Candid(create_error_string(&format!("Invalid chunk ID in query reply: {}", value))))
+            })
+            .collect::<Result<_, _>>()
+            .map(RemoteState::ChunkIds),
+        other => value_to_u64(&other)
+            .map(RemoteState::ByteCount)
+            .ok_or_else(|| UploadError::Candid(create_error_string(&format!("Unsupported query reply: {}", other)))),
+    }
+}
+
+/// Reads any Candid natural number as a `u64`
+pub(crate) fn value_to_u64(value: &IDLValue) -> Option<u64> {
+    match value {
+        IDLValue::Nat(n) => u64::try_from(&n.0).ok(),
+        IDLValue::Nat8(n) => Some(*n as u64),
+        IDLValue::Nat16(n) => Some(*n as u64),
+        IDLValue::Nat32(n) => Some(*n as u64),
+        IDLValue::Nat64(n) => Some(*n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{test_params, MockTransport};
+    use candid::Encode;
+
+    #[test]
+    fn test_parse_chunk_ids() {
+        let reply = Encode!(&vec![0u32, 2, 3]).unwrap();
+        let state = parse_remote_state(&reply).unwrap();
+
+        let source = vec![vec![0u8; 10]; 5];
+
+        assert_eq!(state, RemoteState::ChunkIds(BTreeSet::from([0, 2, 3])));
+        assert_eq!(state.missing_chunks(&source).unwrap(), vec![1, 4]);
+        assert_eq!(state.sequential_start(&source).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_parse_byte_count() {
+        // `usize` is encoded as `nat64`, as returned by the demo `buffer_size`
+        let reply = Encode!(&30usize).unwrap();
+        let state = parse_remote_state(&reply).unwrap();
+
+        let source = vec![vec![0u8; 10], vec![0u8; 10], vec![0u8; 10], vec![0u8; 10], vec![0u8; 5]];
+
+        assert_eq!(state, RemoteState::ByteCount(30));
+        assert_eq!(state.sequential_start(&source).unwrap(), 3);
+        assert_eq!(state.missing_chunks(&source).unwrap(), vec![3, 4]);
+
+        // A complete upload may end in a short chunk
+        assert_eq!(RemoteState::ByteCount(45).sequential_start(&source).unwrap(), 5);
+        assert!(RemoteState::ByteCount(25).sequential_start(&source).is_err());
+        assert!(RemoteState::ByteCount(50).sequential_start(&source).is_err());
+    }
+
+    #[test]
+    fn test_parse_result_reply() {
+        let ok: Result<Vec<u32>, String> = Ok(vec![1]);
+        let err: Result<Vec<u32>, String> = Err("no upload".to_string());
+
+        assert_eq!(
+            parse_remote_state(&Encode!(&ok).unwrap()).unwrap(),
+            RemoteState::ChunkIds(BTreeSet::from([1]))
+        );
+        let error = parse_remote_state(&Encode!(&err).unwrap()).unwrap_err();
+        assert!(matches!(&error, UploadError::Reply(message) if message.contains("no upload")));
+    }
+
+    #[test]
+    fn test_query_remote_state_uses_query() {
+        let transport = MockTransport::with_responder(|_| Ok(Encode!(&vec![0u32]).unwrap()));
+        let state = query_remote_state(&transport, &test_params(), "parallel_chunk_ids").unwrap();
+
+        assert_eq!(state, RemoteState::ChunkIds(BTreeSet::from([0])));
+        let calls = transport.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].method, "parallel_chunk_ids");
+        assert!(calls[0].query);
+    }
+}
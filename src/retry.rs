@@ -0,0 +1,274 @@
+//! Retry policy and classification of failed chunk calls
+//!
+//! A failed attempt is retried only if another attempt could succeed:
+//! timeouts, rate limiting and a busy replica clear up by themselves, while a
+//! missing method, an argument the canister cannot decode or a canister out of
+//! cycles fail the same way every time. Retries back off exponentially with
+//! jitter, so that parallel workers hitting the same congestion do not retry
+//! in lockstep.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use crate::error::UploadError;
+use crate::transport::CallError;
+
+/// Whether another attempt of a failed call could succeed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Transient, e.g. a timeout, rate limiting or a busy replica
+    Retryable,
+    /// Fails the same way every time, e.g. a missing method, an undecodable argument or a canister out of cycles
+    Fatal,
+}
+
+/// A failed attempt and whether it is worth retrying
+#[derive(Debug, Clone)]
+pub struct AttemptError {
+    /// Whether another attempt could succeed
+    pub class: ErrorClass,
+    /// What went wrong
+    pub error: UploadError,
+}
+
+impl AttemptError {
+    /// A failure another attempt could fix
+    pub fn retryable(error: impl Into<UploadError>) -> Self {
+        Self {
+            class: ErrorClass::Retryable,
+            error: error.into(),
+        }
+    }
+
+    /// A failure no attempt can fix
+    pub fn fatal(error: impl Into<UploadError>) -> Self {
+        Self {
+            class: ErrorClass::Fatal,
+            error: error.into(),
+        }
+    }
+
+    /// Classifies a transport error, see [`classify`]
+    pub fn from_call(error: CallError) -> Self {
+        Self {
+            class: classify(&error),
+            error: error.into(),
+        }
+    }
+
+    /// A delivered reply that reports a failure, see [`crate::reply::ReplyPolicy`].
+    ///
+    /// The same argument gets the same reply, whether it is undecodable or an
+    /// `Err` variant, so another attempt cannot succeed.
+    pub fn from_reply(message: String) -> Self {
+        Self::fatal(UploadError::Reply(message))
+    }
+
+    /// Whether no further attempt can succeed
+    pub fn is_fatal(&self) -> bool {
+        self.class == ErrorClass::Fatal
+    }
+
+    /// The error of a chunk whose last attempt, of `attempts`, failed like this
+    pub fn into_chunk_error(self, index: u32, attempts: usize) -> UploadError {
+        UploadError::Chunk {
+            index,
+            attempts,
+            retryable: !self.is_fatal(),
+            source: Box::new(self.error),
+        }
+    }
+}
+
+impl fmt::Display for AttemptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+/// Replica error codes of failures that no retry can fix
+const FATAL_ERROR_CODES: [&str; 5] = [
+    "IC0207", // canister out of cycles
+    "IC0301", // canister not found
+    "IC0302", // canister method not found
+    "IC0536", // canister has no such method
+    "IC0537", // no Wasm module installed
+];
+
+/// Replica error codes of failures that clear up by themselves
+const RETRYABLE_ERROR_CODES: [&str; 6] = [
+    "IC0101", // subnet oversubscribed
+    "IC0201", // canister queue full
+    "IC0202", // ingress message timeout
+    "IC0204", // ingress history full
+    "IC0208", // certified state unavailable
+    "IC0210", // heap delta rate limited
+];
+
+/// Message fragments of failures that no retry can fix
+const FATAL_MESSAGES: [&str; 9] = [
+    "has no update method",
+    "has no query method",
+    "method not found",
+    "out of cycles",
+    "failed to decode",
+    "idl error",
+    "deserialize error",
+    "canister not found",
+    "contains no wasm module",
+];
+
+/// Sorts a failed call into retryable and fatal failures.
+///
+/// Failures to reach the replica and timeouts are always retryable, while a
+/// `dfx` that cannot be started never is. For rejections the replica error code
+/// decides when it is a known one, then the message; a rejection neither
+/// recognizes is retried, as every failure was before.
+pub fn classify(error: &CallError) -> ErrorClass {
+    let (error_code, message) = match error {
+        CallError::Spawn(_) => return ErrorClass::Fatal,
+        CallError::Transport(_) | CallError::Timeout(_) => return ErrorClass::Retryable,
+        CallError::Rejected { error_code, message, .. } => (error_code.as_deref(), message.to_lowercase()),
+    };
+
+    match error_code {
+        Some(code) if FATAL_ERROR_CODES.contains(&code) => return ErrorClass::Fatal,
+        Some(code) if RETRYABLE_ERROR_CODES.contains(&code) => return ErrorClass::Retryable,
+        _ => {}
+    }
+
+    if FATAL_MESSAGES.iter().any(|fragment| message.contains(fragment)) {
+        ErrorClass::Fatal
+    } else {
+        ErrorClass::Retryable
+    }
+}
+
+/// How failed attempts are retried
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Delay before the first retry
+    pub base_delay: Duration,
+    /// Factor the delay grows by with every further retry
+    pub multiplier: f64,
+    /// Longest delay between two attempts
+    pub max_delay: Duration,
+    /// Fraction of each delay that is randomized, from 0 (none) to 1 (anywhere between zero and the delay)
+    pub jitter: f64,
+    /// Time after the first attempt after which no further attempt is started
+    pub max_elapsed: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            max_elapsed: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Retries after the same delay every time, without jitter
+    pub fn fixed(delay: Duration) -> Self {
+        Self {
+            base_delay: delay,
+            multiplier: 1.0,
+            max_delay: delay,
+            jitter: 0.0,
+            max_elapsed: None,
+        }
+    }
+
+    /// The delay before retry number `retry`, counting from 1
+    pub fn delay(&self, retry: u32) -> Duration {
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        self.backoff(retry).mul_f64(1.0 - jitter * random_fraction())
+    }
+
+    /// Whether another attempt may start after `elapsed` since the first one,
+    /// given that it will start after `delay`
+    pub fn allows_retry(&self, elapsed: Duration, delay: Duration) -> bool {
+        self.max_elapsed.is_none_or(|max_elapsed| elapsed + delay <= max_elapsed)
+    }
+
+    /// The delay before retry number `retry` without jitter
+    fn backoff(&self, retry: u32) -> Duration {
+        let factor = self.multiplier.max(1.0).powi(retry.saturating_sub(1).min(64) as i32);
+        let delay = self.base_delay.as_secs_f64() * factor;
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+}
+
+/// A random number in `[0, 1)` from the standard library's randomly keyed hasher
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(error_code: Option<&str>, message: &str) -> CallError {
+        CallError::Rejected {
+            reject_code: Some(5),
+            error_code: error_code.map(str::to_string),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(&CallError::Transport("connection reset".to_string())), ErrorClass::Retryable);
+        assert_eq!(classify(&CallError::Timeout(Duration::from_secs(60))), ErrorClass::Retryable);
+        assert_eq!(classify(&CallError::Spawn("No such file or directory".to_string())), ErrorClass::Fatal);
+        assert_eq!(classify(&rejected(Some("IC0202"), "Ingress message timed out")), ErrorClass::Retryable);
+        assert_eq!(classify(&rejected(Some("IC0101"), "Subnet is oversubscribed")), ErrorClass::Retryable);
+        assert_eq!(classify(&rejected(None, "Canister trapped: buffer busy")), ErrorClass::Retryable);
+
+        assert_eq!(classify(&rejected(Some("IC0536"), "Canister has no update method 'upload'")), ErrorClass::Fatal);
+        assert_eq!(classify(&rejected(Some("IC0207"), "Canister is out of cycles")), ErrorClass::Fatal);
+        assert_eq!(
+            classify(&rejected(Some("IC0503"), "Canister called ic0.trap: IDL error: Failed to decode argument")),
+            ErrorClass::Fatal
+        );
+    }
+
+    #[test]
+    fn test_backoff_grows_to_max_delay() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+
+        let delays: Vec<u64> = (1..=7).map(|retry| policy.delay(retry).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 30, 30]);
+
+        let fixed = RetryPolicy::fixed(Duration::from_millis(250));
+        assert_eq!(fixed.delay(1), fixed.delay(10));
+    }
+
+    #[test]
+    fn test_jitter_and_max_elapsed() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            max_elapsed: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+
+        for _ in 0..100 {
+            let delay = policy.delay(3);
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4), "{:?}", delay);
+        }
+
+        assert!(policy.allows_retry(Duration::from_secs(5), Duration::from_secs(4)));
+        assert!(!policy.allows_retry(Duration::from_secs(8), Duration::from_secs(4)));
+        assert!(RetryPolicy::default().allows_retry(Duration::from_secs(3600), Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,181 @@
+//! Session-based upload protocol
+//!
+//! Instead of appending anonymous chunks to one global buffer, an upload opens
+//! a session on the canister, sends every chunk tagged with the session ID and
+//! its index, and commits the session once all chunks arrived:
+//!
+//! ```text
+//! begin_upload : (name : text, total_size : nat64, chunk_count : nat32, sha256 : blob) -> (nat64);
+//! put_chunk : (session_id : nat64, index : nat32, chunk : blob) -> ();
+//! commit_upload : (session_id : nat64) -> (variant { Ok; Err : text });
+//! ```
+//!
+//! Concurrent uploads use different sessions and therefore cannot clobber
+//! each other's chunks.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use candid::{Decode, Encode};
+use sha2::{Digest, Sha256};
+
+use crate::error::UploadError;
+use crate::journal::UploadJournal;
+use crate::progress::{notify, ProgressEvent, SharedObserver};
+use crate::source::ChunkSource;
+use crate::transport::Transport;
+use crate::{create_error_string, UploadParams};
+
+/// Method that opens an upload session
+pub const BEGIN_UPLOAD_METHOD: &str = "begin_upload";
+
+/// Default method that stores one chunk of a session
+pub const PUT_CHUNK_METHOD: &str = "put_chunk";
+
+/// Method that assembles and verifies the chunks of a session
+pub const COMMIT_UPLOAD_METHOD: &str = "commit_upload";
+
+/// Identifier of an upload session, assigned by the canister
+pub type SessionId = u64;
+
+/// Encodes the `(text, nat64, nat32, blob)` arguments of `begin_upload`
+pub fn encode_begin_upload_args(name: &str, total_size: u64, chunk_count: u32, sha256: &[u8]) -> Result<Vec<u8>, UploadError> {
+    Encode!(&name, &total_size, &chunk_count, &serde_bytes::Bytes::new(sha256))
+        .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))
+}
+
+/// Encodes the `(nat64, nat32, blob)` arguments of `put_chunk`
+pub fn encode_put_chunk_args(session_id: SessionId, index: u32, data: &[u8]) -> Result<Vec<u8>, UploadError> {
+    Encode!(&session_id, &index, &serde_bytes::Bytes::new(data))
+        .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))
+}
+
+/// Computes the SHA-256 of everything a chunk source yields, one chunk at a time.
+pub fn sha256_source<S: ChunkSource + ?Sized>(source: &S) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    for index in 0..source.chunk_count() {
+        hasher.update(source.read_chunk(index)?);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Opens an upload session for the contents of `source`.
+///
+/// # Arguments
+///
+/// * `transport` - The transport used to submit the call.
+/// * `params` - Upload parameters including canister info.
+/// * `name` - Name the canister stores the upload under.
+/// * `source` - Source of the chunks that will be uploaded.
+///
+/// # Returns
+///
+/// A `Result` containing the session ID assigned by the canister or an error message.
+pub fn begin_upload<T: Transport + ?Sized, S: ChunkSource + ?Sized>(
+    transport: &T,
+    params: &UploadParams,
+    name: &str,
+    source: &S,
+) -> Result<SessionId, UploadError> {
+    let sha256 = sha256_source(source).map_err(|e| UploadError::io(format!("Failed to hash {}", name), e))?;
+    let args = encode_begin_upload_args(name, source.total_bytes(), source.chunk_count() as u32, &sha256)?;
+
+    let reply = transport
+        .call(params.canister_name, BEGIN_UPLOAD_METHOD, &args)
+        .map_err(|e| UploadError::from(e).context(format!("{} failed", BEGIN_UPLOAD_METHOD)))?;
+
+    Decode!(&reply, SessionId).map_err(|e| {
+        UploadError::Candid(create_error_string(&format!("Failed to decode {} reply: {}", BEGIN_UPLOAD_METHOD, e)))
+    })
+}
+
+/// Commits an upload session once every chunk has been stored.
+///
+/// # Arguments
+///
+/// * `transport` - The transport used to submit the call.
+/// * `params` - Upload parameters including canister info.
+/// * `session_id` - The session to commit.
+///
+/// # Returns
+///
+/// `Ok(())` if the canister accepted the upload, or the reason it refused it.
+pub fn commit_upload<T: Transport + ?Sized>(
+    transport: &T,
+    params: &UploadParams,
+    session_id: SessionId,
+) -> Result<(), UploadError> {
+    let args = Encode!(&session_id)
+        .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode Candid arguments: {}", e))))?;
+
+    let reply = transport
+        .call(params.canister_name, COMMIT_UPLOAD_METHOD, &args)
+        .map_err(|e| UploadError::from(e).context(format!("{} failed", COMMIT_UPLOAD_METHOD)))?;
+
+    Decode!(&reply, Result<(), String>)
+        .map_err(|e| {
+            UploadError::Candid(create_error_string(&format!("Failed to decode {} reply: {}", COMMIT_UPLOAD_METHOD, e)))
+        })?
+        .map_err(|e| UploadError::Reply(create_error_string(&format!("Session {} was not committed: {}", session_id, e))))
+}
+
+/// Commits the session of an upload whose chunks all succeeded and records the
+/// commit in the journal, so a resumed upload does not commit it again
+pub(crate) fn commit_session<T: Transport + ?Sized>(
+    transport: &T,
+    params: &UploadParams,
+    session_id: SessionId,
+    journal: &Option<Arc<Mutex<UploadJournal>>>,
+    observer: &Option<SharedObserver>,
+) -> Result<(), UploadError> {
+    commit_upload(transport, params, session_id)?;
+    notify(observer, || ProgressEvent::SessionCommitted { session_id });
+
+    match journal {
+        Some(journal) => journal.lock().unwrap().record_commit(),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{test_params, MockTransport};
+
+    #[test]
+    fn test_begin_upload_sends_file_summary() {
+        let transport = MockTransport::with_responder(|_| Ok(Encode!(&7u64).unwrap()));
+        let chunks = vec![b"ab".to_vec(), b"c".to_vec()];
+
+        let session_id = begin_upload(&transport, &test_params(), "model.bin", &chunks).unwrap();
+        assert_eq!(session_id, 7);
+
+        let calls = transport.calls();
+        assert_eq!(calls[0].method, BEGIN_UPLOAD_METHOD);
+        let (name, total_size, chunk_count, sha256) =
+            Decode!(&calls[0].args, String, u64, u32, serde_bytes::ByteBuf).unwrap();
+        assert_eq!((name.as_str(), total_size, chunk_count), ("model.bin", 3, 2));
+        assert_eq!(
+            hex::encode(sha256),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_put_chunk_args_round_trip() {
+        let args = encode_put_chunk_args(9, 3, &[1, 2, 3]).unwrap();
+        let (session_id, index, data) = Decode!(&args, u64, u32, serde_bytes::ByteBuf).unwrap();
+
+        assert_eq!((session_id, index, data.into_vec()), (9, 3, vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_commit_upload_reports_rejection() {
+        let ok = MockTransport::with_responder(|_| Ok(Encode!(&Ok::<(), String>(())).unwrap()));
+        assert!(commit_upload(&ok, &test_params(), 1).is_ok());
+
+        let err = MockTransport::with_responder(|_| Ok(Encode!(&Err::<(), String>("sha256 mismatch".to_string())).unwrap()));
+        let error = commit_upload(&err, &test_params(), 1).unwrap_err();
+        assert!(matches!(&error, UploadError::Reply(message) if message.contains("sha256 mismatch")));
+    }
+}
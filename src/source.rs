@@ -0,0 +1,247 @@
+//! Lazily-read chunk sources
+//!
+//! A [`ChunkSource`] hands out chunks on demand instead of holding the whole
+//! file in memory, so the uploaders only keep the chunks currently in flight.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+
+use memmap2::Mmap;
+
+/// A source of fixed-size chunks that can be read in any order.
+///
+/// Every chunk except possibly the last one is exactly `chunk_size()` bytes long.
+pub trait ChunkSource: Send + Sync {
+    /// Total number of bytes the source will yield
+    fn total_bytes(&self) -> u64;
+
+    /// Size of each chunk in bytes (the last chunk may be shorter)
+    fn chunk_size(&self) -> usize;
+
+    /// Reads the chunk at `index` (0-based).
+    fn read_chunk(&self, index: usize) -> io::Result<Vec<u8>>;
+
+    /// Number of chunks the source will yield
+    fn chunk_count(&self) -> usize {
+        let chunk_size = self.chunk_size() as u64;
+        if chunk_size == 0 {
+            return 0;
+        }
+        self.total_bytes().div_ceil(chunk_size) as usize
+    }
+
+    /// Byte range of the chunk at `index`, relative to the start of the source
+    fn chunk_range(&self, index: usize) -> io::Result<(u64, usize)> {
+        let offset = index as u64 * self.chunk_size() as u64;
+        if index >= self.chunk_count() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Chunk index {} out of range ({} chunks)", index, self.chunk_count()),
+            ));
+        }
+        let len = u64::min(self.chunk_size() as u64, self.total_bytes() - offset) as usize;
+        Ok((offset, len))
+    }
+}
+
+/// Chunk source over any seekable reader, such as an open file
+#[derive(Debug)]
+pub struct ReaderChunkSource<R> {
+    /// The underlying reader, shared between uploader threads
+    reader: Mutex<R>,
+    /// Byte offset in the reader where the first chunk starts
+    start_offset: u64,
+    /// Number of bytes after `start_offset`
+    total_bytes: u64,
+    /// Size of each chunk in bytes
+    chunk_size: usize,
+}
+
+impl<R: Read + Seek> ReaderChunkSource<R> {
+    /// Creates a chunk source reading from `reader`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - A seekable reader over the data.
+    /// * `chunk_size` - The size of each chunk.
+    /// * `start_offset` - Byte offset where chunking starts.
+    pub fn new(mut reader: R, chunk_size: usize, start_offset: u64) -> io::Result<Self> {
+        let end = reader.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            reader: Mutex::new(reader),
+            start_offset,
+            total_bytes: end.saturating_sub(start_offset),
+            chunk_size,
+        })
+    }
+}
+
+impl ReaderChunkSource<File> {
+    /// Opens a file as a chunk source.
+    pub fn open<P: AsRef<Path>>(path: P, chunk_size: usize, start_offset: u64) -> io::Result<Self> {
+        Self::new(File::open(path)?, chunk_size, start_offset)
+    }
+}
+
+impl<R: Read + Seek + Send> ChunkSource for ReaderChunkSource<R> {
+    fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn read_chunk(&self, index: usize) -> io::Result<Vec<u8>> {
+        let (offset, len) = self.chunk_range(index)?;
+        let mut data = vec![0u8; len];
+
+        let mut reader = self.reader.lock().unwrap();
+        reader.seek(SeekFrom::Start(self.start_offset + offset))?;
+        reader.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+}
+
+/// Chunk source over a memory-mapped file
+#[derive(Debug)]
+pub struct MmapChunkSource {
+    /// The mapped file contents
+    mmap: Mmap,
+    /// Byte offset in the file where the first chunk starts
+    start_offset: usize,
+    /// Size of each chunk in bytes
+    chunk_size: usize,
+}
+
+impl MmapChunkSource {
+    /// Memory-maps a file as a chunk source.
+    ///
+    /// The file must not be modified while it is mapped.
+    pub fn open<P: AsRef<Path>>(path: P, chunk_size: usize, start_offset: u64) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the mapping is read-only and callers are told not to modify the file
+        let mmap = unsafe { Mmap::map(&file)? };
+        let start_offset = usize::min(start_offset as usize, mmap.len());
+        Ok(Self {
+            mmap,
+            start_offset,
+            chunk_size,
+        })
+    }
+}
+
+impl ChunkSource for MmapChunkSource {
+    fn total_bytes(&self) -> u64 {
+        (self.mmap.len() - self.start_offset) as u64
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn read_chunk(&self, index: usize) -> io::Result<Vec<u8>> {
+        let (offset, len) = self.chunk_range(index)?;
+        let start = self.start_offset + offset as usize;
+        Ok(self.mmap[start..start + len].to_vec())
+    }
+}
+
+/// Pre-split chunks already held in memory (e.g. from [`crate::split_into_chunks`])
+impl ChunkSource for [Vec<u8>] {
+    fn total_bytes(&self) -> u64 {
+        self.iter().map(|chunk| chunk.len() as u64).sum()
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.first().map(|chunk| chunk.len()).unwrap_or(0)
+    }
+
+    fn chunk_count(&self) -> usize {
+        self.len()
+    }
+
+    fn read_chunk(&self, index: usize) -> io::Result<Vec<u8>> {
+        self.get(index).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Chunk index {} out of range ({} chunks)", index, self.len()),
+            )
+        })
+    }
+}
+
+impl ChunkSource for Vec<Vec<u8>> {
+    fn total_bytes(&self) -> u64 {
+        self.as_slice().total_bytes()
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.as_slice().chunk_size()
+    }
+
+    fn chunk_count(&self) -> usize {
+        self.len()
+    }
+
+    fn read_chunk(&self, index: usize) -> io::Result<Vec<u8>> {
+        self.as_slice().read_chunk(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    #[test]
+    fn test_reader_source_chunks() {
+        let data: Vec<u8> = (0..10).collect();
+        let source = ReaderChunkSource::new(Cursor::new(data), 4, 0).unwrap();
+
+        assert_eq!(source.total_bytes(), 10);
+        assert_eq!(source.chunk_count(), 3);
+        assert_eq!(source.read_chunk(0).unwrap(), vec![0, 1, 2, 3]);
+        assert_eq!(source.read_chunk(2).unwrap(), vec![8, 9]);
+        assert!(source.read_chunk(3).is_err());
+    }
+
+    #[test]
+    fn test_reader_source_start_offset() {
+        let data: Vec<u8> = (0..10).collect();
+        let source = ReaderChunkSource::new(Cursor::new(data), 4, 3).unwrap();
+
+        assert_eq!(source.total_bytes(), 7);
+        assert_eq!(source.chunk_count(), 2);
+        assert_eq!(source.read_chunk(0).unwrap(), vec![3, 4, 5, 6]);
+        assert_eq!(source.read_chunk(1).unwrap(), vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn test_mmap_source_matches_reader_source() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        file.write_all(&data).unwrap();
+
+        let mmap = MmapChunkSource::open(file.path(), 300, 10).unwrap();
+        let reader = ReaderChunkSource::open(file.path(), 300, 10).unwrap();
+
+        assert_eq!(mmap.chunk_count(), 4);
+        for index in 0..mmap.chunk_count() {
+            assert_eq!(mmap.read_chunk(index).unwrap(), reader.read_chunk(index).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_split_chunks_source() {
+        let chunks = vec![vec![1, 2], vec![3, 4], vec![5]];
+
+        assert_eq!(chunks.total_bytes(), 5);
+        assert_eq!(chunks.chunk_count(), 3);
+        assert_eq!(chunks.read_chunk(2).unwrap(), vec![5]);
+        assert!(chunks.read_chunk(3).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+//! User-defined Candid argument templates for chunk calls
+//!
+//! By default chunks are sent as `(blob)` or `(nat32, blob)`. A template lets
+//! the chunk method take any argument shape, written as Candid text with
+//! placeholders that are filled in for every chunk:
+//!
+//! ```text
+//! (record { key = "{name}"; index = {index} : nat64; offset = {offset} : nat64; data = {blob} })
+//! ```
+//!
+//! | Placeholder | Value |
+//! |-------------|-------|
+//! | `{name}`    | name of the upload, escaped for use inside a Candid string |
+//! | `{index}`   | chunk index, starting at 0 |
+//! | `{offset}`  | byte offset of the chunk in the upload |
+//! | `{total}`   | total number of chunks |
+//! | `{size}`    | size of the chunk in bytes |
+//! | `{hash}`    | SHA-256 of the chunk, as a `blob` |
+//! | `{blob}`    | the chunk data, as a `blob` |
+
+use std::fmt;
+
+use candid::IDLValue;
+use sha2::{Digest, Sha256};
+
+use crate::create_error_string;
+use crate::error::UploadError;
+
+/// Stands in for the chunk data while the template text is parsed
+const BLOB_SENTINEL: &[u8] = b"\x00ic-file-uploader:chunk\x00";
+
+/// A value substituted into a template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Name,
+    Index,
+    Offset,
+    Total,
+    Size,
+    Hash,
+    Blob,
+}
+
+impl Placeholder {
+    const ALL: [Placeholder; 7] = [
+        Placeholder::Name,
+        Placeholder::Index,
+        Placeholder::Offset,
+        Placeholder::Total,
+        Placeholder::Size,
+        Placeholder::Hash,
+        Placeholder::Blob,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Placeholder::Name => "name",
+            Placeholder::Index => "index",
+            Placeholder::Offset => "offset",
+            Placeholder::Total => "total",
+            Placeholder::Size => "size",
+            Placeholder::Hash => "hash",
+            Placeholder::Blob => "blob",
+        }
+    }
+}
+
+/// A piece of template text
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+/// The chunk a template is rendered for
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkContext<'a> {
+    /// Name of the upload
+    pub name: &'a str,
+    /// Chunk index, starting at 0
+    pub index: u32,
+    /// Byte offset of the chunk in the upload
+    pub offset: u64,
+    /// Total number of chunks
+    pub total: u32,
+    /// The chunk data
+    pub data: &'a [u8],
+}
+
+/// A validated Candid argument template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArgTemplate {
+    /// The template as written
+    text: String,
+    /// The template split at its placeholders
+    segments: Vec<Segment>,
+}
+
+impl fmt::Display for ArgTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl ArgTemplate {
+    /// Parses and validates a template.
+    ///
+    /// The template must contain `{blob}` and must render to valid Candid,
+    /// which is checked here with sample values so that a broken template is
+    /// reported before any chunk is sent.
+    pub fn parse(text: &str) -> Result<Self, UploadError> {
+        let template = Self {
+            text: text.to_string(),
+            segments: split_segments(text),
+        };
+
+        if !template.uses(Placeholder::Blob) {
+            return Err(UploadError::Candid(create_error_string("Argument template must contain {blob}")));
+        }
+
+        let sample = ChunkContext {
+            name: "name",
+            index: 0,
+            offset: 0,
+            total: 1,
+            data: &[0],
+        };
+        template
+            .render(&sample)
+            .map_err(|e| e.context(format!("Invalid argument template {}", text)))?;
+
+        Ok(template)
+    }
+
+    /// Renders the template for one chunk into binary Candid.
+    pub fn render(&self, chunk: &ChunkContext) -> Result<Vec<u8>, UploadError> {
+        let mut text = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(literal) => text.push_str(literal),
+                Segment::Placeholder(placeholder) => text.push_str(&self.value(*placeholder, chunk)),
+            }
+        }
+
+        let mut args = candid_parser::parse_idl_args(&text)
+            .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to parse rendered argument: {}", e))))?;
+        for value in args.args.iter_mut() {
+            fill_blob(value, chunk.data);
+        }
+
+        args.to_bytes()
+            .map_err(|e| UploadError::Candid(create_error_string(&format!("Failed to encode rendered argument: {}", e))))
+    }
+
+    /// Whether the template contains a placeholder
+    fn uses(&self, placeholder: Placeholder) -> bool {
+        self.segments.contains(&Segment::Placeholder(placeholder))
+    }
+
+    /// The Candid text substituted for a placeholder
+    fn value(&self, placeholder: Placeholder, chunk: &ChunkContext) -> String {
+        match placeholder {
+            Placeholder::Name => chunk.name.replace('\\', "\\\\").replace('"', "\\\""),
+            Placeholder::Index => chunk.index.to_string(),
+            Placeholder::Offset => chunk.offset.to_string(),
+            Placeholder::Total => chunk.total.to_string(),
+            Placeholder::Size => chunk.data.len().to_string(),
+            Placeholder::Hash => blob_literal(&Sha256::digest(chunk.data)),
+            Placeholder::Blob => blob_literal(BLOB_SENTINEL),
+        }
+    }
+}
+
+/// Splits template text at known placeholders; other braces are Candid syntax
+fn split_segments(text: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('{') {
+        let placeholder = rest[start + 1..].find('}').and_then(|end| {
+            let name = &rest[start + 1..start + 1 + end];
+            Placeholder::ALL.into_iter().find(|p| p.name() == name)
+        });
+
+        match placeholder {
+            Some(placeholder) => {
+                literal.push_str(&rest[..start]);
+                if !literal.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Placeholder(placeholder));
+                rest = &rest[start + placeholder.name().len() + 2..];
+            }
+            None => {
+                literal.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        segments.push(Segment::Text(literal));
+    }
+    segments
+}
+
+/// Writes bytes as a Candid `blob` literal
+fn blob_literal(bytes: &[u8]) -> String {
+    let mut literal = String::with_capacity(bytes.len() * 3 + 7);
+    literal.push_str("blob \"");
+    for byte in bytes {
+        literal.push_str(&format!("\\{:02x}", byte));
+    }
+    literal.push('"');
+    literal
+}
+
+/// Replaces the blob sentinel anywhere inside a value with the chunk data
+fn fill_blob(value: &mut IDLValue, data: &[u8]) {
+    match value {
+        IDLValue::Blob(bytes) if bytes.as_slice() == BLOB_SENTINEL => *bytes = data.to_vec(),
+        IDLValue::Opt(inner) => fill_blob(inner, data),
+        IDLValue::Vec(values) => values.iter_mut().for_each(|value| fill_blob(value, data)),
+        IDLValue::Record(fields) => fields.iter_mut().for_each(|field| fill_blob(&mut field.val, data)),
+        IDLValue::Variant(variant) => fill_blob(&mut variant.0.val, data),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::{CandidType, Decode, Deserialize, Encode};
+    use serde_bytes::ByteBuf;
+
+    fn chunk(data: &[u8]) -> ChunkContext<'_> {
+        ChunkContext {
+            name: "model \"v2\".bin",
+            index: 3,
+            offset: 6,
+            total: 4,
+            data,
+        }
+    }
+
+    #[test]
+    fn test_render_positional_arguments() {
+        let template = ArgTemplate::parse("(\"{name}\", {offset} : nat64, {blob})").unwrap();
+        let args = template.render(&chunk(&[7, 8])).unwrap();
+
+        let (key, offset, data) = Decode!(&args, String, u64, ByteBuf).unwrap();
+        assert_eq!((key.as_str(), offset, data.into_vec()), ("model \"v2\".bin", 6, vec![7, 8]));
+    }
+
+    #[test]
+    fn test_render_record() {
+        #[derive(CandidType, Deserialize, Debug, PartialEq)]
+        struct Chunk {
+            index: u32,
+            total: u32,
+            size: u64,
+            hash: ByteBuf,
+            data: ByteBuf,
+        }
+
+        let template = ArgTemplate::parse(
+            "(record { index = {index} : nat32; total = {total} : nat32; size = {size} : nat64; hash = {hash}; data = {blob} })",
+        )
+        .unwrap();
+        let args = template.render(&chunk(b"abc")).unwrap();
+
+        let expected = Chunk {
+            index: 3,
+            total: 4,
+            size: 3,
+            hash: ByteBuf::from(Sha256::digest(b"abc").to_vec()),
+            data: ByteBuf::from(b"abc".to_vec()),
+        };
+        assert_eq!(args, Encode!(&expected).unwrap());
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_templates() {
+        let missing_blob = ArgTemplate::parse("({index} : nat32)").unwrap_err();
+        assert!(matches!(&missing_blob, UploadError::Candid(message) if message.contains("{blob}")));
+
+        let invalid = ArgTemplate::parse("(record { data = {blob} )").unwrap_err();
+        assert!(invalid.to_string().contains("Invalid argument template"));
+        assert!(matches!(invalid.root_cause(), UploadError::Candid(_)));
+
+        // Braces that are not placeholders are left to Candid
+        assert!(ArgTemplate::parse("(variant { chunk = {blob} }, record {})").is_ok());
+    }
+}
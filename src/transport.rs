@@ -0,0 +1,546 @@
+//! Pluggable transports for canister calls
+//!
+//! Every canister call made by the uploaders goes through the [`Transport`] trait,
+//! so the same upload pipelines can run on top of `dfx`, a native `ic-agent`
+//! client, or an in-memory mock in tests.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use candid::Principal;
+use tempfile::NamedTempFile;
+
+use crate::cancel::CancellationToken;
+use crate::error::UploadError;
+use crate::{create_error_string, dfx_command};
+
+/// The binary Candid encoding of an empty argument or reply, `()`.
+pub const EMPTY_CANDID_REPLY: &[u8] = b"DIDL\x00\x00";
+
+/// Error returned by a transport when a canister call does not produce a reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallError {
+    /// The `dfx` process making the call could not be started
+    Spawn(String),
+    /// The call could not be submitted (I/O or connection failure)
+    Transport(String),
+    /// The call reached the replica but was rejected by it or by the canister
+    Rejected {
+        /// Numeric reject code from the interface spec, when known
+        reject_code: Option<u8>,
+        /// Replica error code such as `IC0503`, when known
+        error_code: Option<String>,
+        /// The rejection message
+        message: String,
+    },
+    /// No reply arrived within the transport's timeout; the call may still be executed
+    Timeout(Duration),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Spawn(message) | CallError::Transport(message) => write!(f, "{}", message),
+            CallError::Rejected { error_code: Some(code), message, .. } => write!(f, "{}: {}", code, message),
+            CallError::Rejected { message, .. } => write!(f, "{}", message),
+            CallError::Timeout(timeout) => write!(f, "No reply within {:.1}s", timeout.as_secs_f64()),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
+/// A mechanism for submitting update calls to canisters.
+///
+/// Implementations must be shareable between threads so the parallel uploader
+/// can issue calls from several workers at once.
+pub trait Transport: Send + Sync {
+    /// Calls an update method and waits for its reply.
+    ///
+    /// # Arguments
+    ///
+    /// * `canister_name` - The name or principal of the canister.
+    /// * `method` - The name of the canister method to call.
+    /// * `args` - The call argument in binary Candid (DIDL) format.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the raw Candid reply or a classified `CallError`.
+    fn call(&self, canister_name: &str, method: &str, args: &[u8]) -> Result<Vec<u8>, CallError>;
+
+    /// Calls a query method and returns its reply.
+    ///
+    /// Queries do not go through consensus, so they are cheap but must not be
+    /// used for anything that changes canister state.
+    ///
+    /// # Arguments
+    ///
+    /// * `canister_name` - The name or principal of the canister.
+    /// * `method` - The name of the canister query method to call.
+    /// * `args` - The call argument in binary Candid (DIDL) format.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the raw Candid reply or a classified `CallError`.
+    fn query(&self, canister_name: &str, method: &str, args: &[u8]) -> Result<Vec<u8>, CallError>;
+
+    /// Resolves a canister name or principal text to the canister's principal.
+    ///
+    /// Needed where a canister is passed as an argument rather than called,
+    /// e.g. in management canister calls. The default only accepts principals.
+    fn canister_id(&self, canister_name: &str) -> Result<Principal, UploadError> {
+        Principal::from_text(canister_name)
+            .map_err(|_| UploadError::Other(create_error_string(&format!("'{}' is not a canister principal", canister_name))))
+    }
+
+    /// Reads a public metadata section of a canister, such as `candid:service`.
+    ///
+    /// The default reports that the transport cannot read metadata.
+    fn metadata(&self, canister_name: &str, name: &str) -> Result<Vec<u8>, UploadError> {
+        Err(UploadError::Other(create_error_string(&format!(
+            "Cannot read {} metadata of {} through this transport",
+            name, canister_name
+        ))))
+    }
+}
+
+/// How often a `dfx` process running under a timeout or cancellation token is checked for completion
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Transport that shells out to `dfx canister call` for every call
+#[derive(Debug, Clone, Default)]
+pub struct DfxTransport {
+    /// Optional network passed to `dfx` as `--network`
+    pub network: Option<String>,
+    /// How long a call may take before the `dfx` process is killed; `None` waits forever
+    pub timeout: Option<Duration>,
+    /// Token that kills the `dfx` processes of calls in flight once cancelled
+    pub cancellation: Option<CancellationToken>,
+}
+
+impl DfxTransport {
+    /// Creates a dfx transport for the given network.
+    pub fn new(network: Option<&str>) -> Self {
+        Self {
+            network: network.map(|n| n.to_string()),
+            timeout: None,
+            cancellation: None,
+        }
+    }
+
+    /// Kills calls that take longer than `timeout` and fails them with [`CallError::Timeout`]
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Kills the calls in flight once `token`, if any, is cancelled
+    pub fn with_cancellation(mut self, token: Option<CancellationToken>) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Runs `dfx canister call` with a raw argument, as a query when `query` is set.
+    fn canister_call(&self, canister_name: &str, method: &str, args: &[u8], query: bool) -> Result<Vec<u8>, CallError> {
+        let mut temp_file = NamedTempFile::new()
+            .map_err(|_| CallError::Transport(create_error_string("Failed to create temporary file")))?;
+
+        // `--type raw` expects the Candid bytes hex-encoded
+        temp_file
+            .as_file_mut()
+            .write_all(hex::encode(args).as_bytes())
+            .map_err(|_| CallError::Transport(create_error_string("Failed to write data to temporary file")))?;
+
+        // Flush so the data is on disk before dfx reads it
+        temp_file
+            .as_file_mut()
+            .flush()
+            .map_err(|_| CallError::Transport(create_error_string("Failed to flush temporary file")))?;
+
+        let temp_path = temp_file.path().to_str().ok_or_else(|| {
+            CallError::Transport(create_error_string("temp_file path could not be converted to &str"))
+        })?;
+
+        let mut dfx_args = vec![
+            canister_name,
+            method,
+            "--type",
+            "raw",
+            "--argument-file",
+            temp_path,
+            "--output",
+            "raw",
+        ];
+        if query {
+            dfx_args.push("--query");
+        }
+
+        let output = self.run_canister_command("call", &dfx_args)?;
+        if !output.status.success() {
+            return Err(failed_call_error(String::from_utf8_lossy(&output.stderr).to_string()));
+        }
+
+        // `--output raw` prints the reply as hex-encoded Candid
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        hex::decode(stdout.trim())
+            .map_err(|e| CallError::Transport(create_error_string(&format!("Failed to decode dfx reply: {}", e))))
+    }
+
+    /// Runs `dfx canister <subcommand>` under the timeout and cancellation token of this transport
+    fn run_canister_command(&self, subcommand: &str, args: &[&str]) -> Result<Output, CallError> {
+        let mut command = dfx_command("canister", subcommand, args, self.network.as_deref());
+        // In a process group of its own, dfx does not receive the Ctrl-C meant for the
+        // uploader, so a call in flight completes unless the cancellation token kills it
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+
+        match (self.timeout, &self.cancellation) {
+            (None, None) => command.output().map_err(spawn_error),
+            (timeout, cancellation) => wait_for_output(command, timeout, cancellation.as_ref()),
+        }
+    }
+}
+
+impl Transport for DfxTransport {
+    fn call(&self, canister_name: &str, method: &str, args: &[u8]) -> Result<Vec<u8>, CallError> {
+        self.canister_call(canister_name, method, args, false)
+    }
+
+    fn query(&self, canister_name: &str, method: &str, args: &[u8]) -> Result<Vec<u8>, CallError> {
+        self.canister_call(canister_name, method, args, true)
+    }
+
+    fn canister_id(&self, canister_name: &str) -> Result<Principal, UploadError> {
+        if let Ok(principal) = Principal::from_text(canister_name) {
+            return Ok(principal);
+        }
+
+        let output = self
+            .run_canister_command("id", &[canister_name])
+            .map_err(|e| UploadError::from(e).context(format!("Failed to look up canister {}", canister_name)))?;
+        if !output.status.success() {
+            return Err(UploadError::Other(create_error_string(&format!(
+                "Failed to look up canister {}: {}",
+                canister_name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))));
+        }
+
+        let id = String::from_utf8_lossy(&output.stdout);
+        Principal::from_text(id.trim()).map_err(|e| {
+            UploadError::Other(create_error_string(&format!("dfx returned an invalid principal for {}: {}", canister_name, e)))
+        })
+    }
+
+    fn metadata(&self, canister_name: &str, name: &str) -> Result<Vec<u8>, UploadError> {
+        let context = || format!("Failed to read {} metadata of {}", name, canister_name);
+        let output = self
+            .run_canister_command("metadata", &[canister_name, name])
+            .map_err(|e| UploadError::from(e).context(context()))?;
+        if !output.status.success() {
+            let error = failed_call_error(String::from_utf8_lossy(&output.stderr).trim().to_string());
+            return Err(UploadError::from(error).context(context()));
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+/// Runs a command to completion, killing it once it took longer than `timeout`
+/// or `cancellation` was cancelled
+fn wait_for_output(
+    mut command: Command,
+    timeout: Option<Duration>,
+    cancellation: Option<&CancellationToken>,
+) -> Result<Output, CallError> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(spawn_error)?;
+
+    // The pipes are drained while waiting, so the child never blocks on a full pipe
+    let stdout = read_to_end(child.stdout.take());
+    let stderr = read_to_end(child.stderr.take());
+
+    let started = Instant::now();
+    let status = loop {
+        let error = match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if cancellation.is_some_and(CancellationToken::is_cancelled) => {
+                CallError::Transport(create_error_string("Call cancelled"))
+            }
+            Ok(None) => match timeout {
+                Some(timeout) if started.elapsed() >= timeout => CallError::Timeout(timeout),
+                _ => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            },
+            Err(e) => CallError::Transport(e.to_string()),
+        };
+
+        kill(&mut child);
+        return Err(error);
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// The error of a `dfx` process that could not be started
+fn spawn_error(error: std::io::Error) -> CallError {
+    CallError::Spawn(create_error_string(&format!("Failed to start dfx: {}", error)))
+}
+
+/// Kills a `dfx` process along with the processes it started and reaps it
+fn kill(child: &mut Child) {
+    // The child leads its own process group, see `DfxTransport::canister_call`
+    #[cfg(unix)]
+    // SAFETY: kill(2) has no memory safety requirements
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Reads a child process pipe to the end on a separate thread
+fn read_to_end<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+/// The error of a failed `dfx` call from its output.
+///
+/// Only output naming a reject or replica error code comes from the replica;
+/// anything else, e.g. a refused connection or a missing identity, means the
+/// call never got there.
+fn failed_call_error(message: String) -> CallError {
+    match (parse_reject_code(&message), parse_error_code(&message)) {
+        (None, None) => CallError::Transport(message),
+        (reject_code, error_code) => CallError::Rejected {
+            reject_code,
+            error_code,
+            message,
+        },
+    }
+}
+
+/// Extracts a replica error code such as `IC0503` from an error message
+fn parse_error_code(message: &str) -> Option<String> {
+    message
+        .match_indices("IC")
+        .map(|(i, _)| &message[i..])
+        .find(|rest| rest.len() >= 6 && rest[2..6].bytes().all(|b| b.is_ascii_digit()))
+        .map(|rest| rest[..6].to_string())
+}
+
+/// Extracts a numeric reject code from `dfx` output such as `Reject code: 5`
+fn parse_reject_code(message: &str) -> Option<u8> {
+    let lower = message.to_lowercase();
+    let start = lower.find("reject code")? + "reject code".len();
+    lower[start..]
+        .trim_start_matches([':', ' '])
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect::<String>()
+        .parse()
+        .ok()
+}
+
+/// A canister call recorded by the [`MockTransport`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedCall {
+    /// The canister the call was addressed to
+    pub canister_name: String,
+    /// The method that was called
+    pub method: String,
+    /// The binary Candid argument of the call
+    pub args: Vec<u8>,
+    /// Whether the call was made as a query
+    pub query: bool,
+}
+
+/// Function deciding the reply of a [`MockTransport`] call
+pub type MockResponder = Box<dyn Fn(&RecordedCall) -> Result<Vec<u8>, CallError> + Send + Sync>;
+
+/// In-memory transport that records calls instead of contacting a replica.
+///
+/// Useful for testing upload pipelines without `dfx` or a running replica.
+pub struct MockTransport {
+    /// Every call made through this transport, in order
+    calls: Mutex<Vec<RecordedCall>>,
+    /// Produces the reply for each call
+    responder: MockResponder,
+}
+
+impl fmt::Debug for MockTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockTransport")
+            .field("calls", &self.calls.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockTransport {
+    /// Creates a mock transport that answers every call with an empty reply.
+    pub fn new() -> Self {
+        Self::with_responder(|_| Ok(EMPTY_CANDID_REPLY.to_vec()))
+    }
+
+    /// Creates a mock transport whose replies are produced by `responder`.
+    pub fn with_responder<F>(responder: F) -> Self
+    where
+        F: Fn(&RecordedCall) -> Result<Vec<u8>, CallError> + Send + Sync + 'static,
+    {
+        Self {
+            calls: Mutex::new(Vec::new()),
+            responder: Box::new(responder),
+        }
+    }
+
+    /// Returns a copy of all calls made so far.
+    pub fn calls(&self) -> Vec<RecordedCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Records a call and asks the responder for its reply.
+    fn record(&self, canister_name: &str, method: &str, args: &[u8], query: bool) -> Result<Vec<u8>, CallError> {
+        let call = RecordedCall {
+            canister_name: canister_name.to_string(),
+            method: method.to_string(),
+            args: args.to_vec(),
+            query,
+        };
+        self.calls.lock().unwrap().push(call.clone());
+        (self.responder)(&call)
+    }
+}
+
+impl Transport for MockTransport {
+    fn call(&self, canister_name: &str, method: &str, args: &[u8]) -> Result<Vec<u8>, CallError> {
+        self.record(canister_name, method, args, false)
+    }
+
+    fn query(&self, canister_name: &str, method: &str, args: &[u8]) -> Result<Vec<u8>, CallError> {
+        self.record(canister_name, method, args, true)
+    }
+}
+
+/// Parameters of an upload of `test file` to the `backend` canister, shared by the tests of every module
+#[cfg(test)]
+pub(crate) fn test_params() -> crate::UploadParams<'static> {
+    crate::UploadParams {
+        name: "test file",
+        canister_name: "backend",
+        canister_method: "append_chunk",
+        network: None,
+    }
+}
+
+/// Parallel upload settings without rate limit or retry delay, shared by the tests of every module
+#[cfg(test)]
+pub(crate) fn fast_config() -> crate::parallel::ParallelUploadConfig {
+    crate::parallel::ParallelUploadConfig {
+        max_retries: 2,
+        retry_policy: crate::retry::RetryPolicy::fixed(Duration::ZERO),
+        target_rate_mibs: 0.0,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_error_code() {
+        let stderr = "Error: Failed update call.\nCaused by: The replica returned a rejection error: reject code CanisterError, reject message Error from Canister: IC0503: Canister trapped";
+        assert_eq!(parse_error_code(stderr), Some("IC0503".to_string()));
+        assert_eq!(parse_error_code("no code here, ICU"), None);
+    }
+
+    #[test]
+    fn test_parse_reject_code() {
+        assert_eq!(parse_reject_code("The Replica returned an error: code 5, Reject code: 5"), Some(5));
+        assert_eq!(parse_reject_code("reject code CanisterError"), None);
+    }
+
+    #[test]
+    fn test_failures_without_a_code_are_transport_errors() {
+        let rejected = failed_call_error("Error: IC0536: Canister has no update method 'upload'".to_string());
+        assert!(matches!(rejected, CallError::Rejected { error_code: Some(code), .. } if code == "IC0536"));
+
+        let refused = failed_call_error("Error: Failed to connect to http://127.0.0.1:4943: Connection refused".to_string());
+        assert!(matches!(refused, CallError::Transport(_)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hung_process_is_killed() {
+        let mut sleep = Command::new("sleep");
+        sleep.arg("10");
+        let started = Instant::now();
+        assert_eq!(
+            wait_for_output(sleep, Some(Duration::from_millis(100)), None).unwrap_err(),
+            CallError::Timeout(Duration::from_millis(100))
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let mut sleep = Command::new("sleep");
+        sleep.arg("10");
+        let error = wait_for_output(sleep, None, Some(&token)).unwrap_err();
+        assert!(error.to_string().contains("cancelled"), "{}", error);
+
+        let mut echo = Command::new("echo");
+        echo.arg("4449444c0000");
+        let output = wait_for_output(echo, Some(Duration::from_secs(10)), None).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"4449444c0000\n");
+    }
+
+    #[test]
+    fn test_mock_records_calls() {
+        let transport = MockTransport::new();
+        let reply = transport.call("backend", "append_chunk", EMPTY_CANDID_REPLY).unwrap();
+        transport.query("backend", "buffer_size", EMPTY_CANDID_REPLY).unwrap();
+
+        assert_eq!(reply, EMPTY_CANDID_REPLY);
+        assert_eq!(transport.calls(), vec![
+            RecordedCall {
+                canister_name: "backend".to_string(),
+                method: "append_chunk".to_string(),
+                args: EMPTY_CANDID_REPLY.to_vec(),
+                query: false,
+            },
+            RecordedCall {
+                canister_name: "backend".to_string(),
+                method: "buffer_size".to_string(),
+                args: EMPTY_CANDID_REPLY.to_vec(),
+                query: true,
+            },
+        ]);
+    }
+}
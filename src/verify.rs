@@ -0,0 +1,235 @@
+//! End-to-end integrity verification
+//!
+//! After an upload, the uploader can ask the canister for the SHA-256 of what
+//! it assembled, either of the whole file (a `blob`) or of every chunk (a
+//! `vec blob`), and compare it against hashes of the local chunks. Per-chunk
+//! hashes pinpoint exactly which chunks were dropped, reordered or corrupted.
+
+use std::io;
+
+use candid::IDLValue;
+use sha2::{Digest, Sha256};
+
+use crate::error::UploadError;
+use crate::hooks::CanisterCall;
+use crate::reply::reply_value;
+use crate::source::ChunkSource;
+use crate::transport::Transport;
+use crate::{create_error_string, UploadParams};
+
+/// SHA-256 hashes of the data being uploaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalHashes {
+    /// Hash of all chunks concatenated
+    pub file: [u8; 32],
+    /// Hash of every chunk, by chunk index
+    pub chunks: Vec<[u8; 32]>,
+}
+
+impl LocalHashes {
+    /// Hashes every chunk of a source and the source as a whole, one chunk at a time.
+    pub fn compute<S: ChunkSource + ?Sized>(source: &S) -> io::Result<Self> {
+        let mut file = Sha256::new();
+        let mut chunks = Vec::with_capacity(source.chunk_count());
+
+        for index in 0..source.chunk_count() {
+            let chunk = source.read_chunk(index)?;
+            file.update(&chunk);
+            chunks.push(Sha256::digest(&chunk).into());
+        }
+
+        Ok(Self {
+            file: file.finalize().into(),
+            chunks,
+        })
+    }
+
+    /// Compares these hashes with what the canister reported.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if they match, or an error describing the mismatch, listing
+    /// the mismatched chunk indices when the canister reported chunk hashes.
+    pub fn compare(&self, remote: &RemoteHashes) -> Result<(), UploadError> {
+        match remote {
+            RemoteHashes::File(hash) if hash.as_slice() == self.file.as_slice() => Ok(()),
+            RemoteHashes::File(hash) => Err(UploadError::Verification(create_error_string(&format!(
+                "SHA-256 mismatch: local {}, canister {}",
+                hex::encode(self.file),
+                hex::encode(hash)
+            )))),
+            RemoteHashes::Chunks(hashes) => {
+                let mismatched: Vec<usize> = self
+                    .chunks
+                    .iter()
+                    .zip(hashes)
+                    .enumerate()
+                    .filter(|(_, (local, remote))| local.as_slice() != remote.as_slice())
+                    .map(|(index, _)| index)
+                    .collect();
+
+                let mut problems = Vec::new();
+                if !mismatched.is_empty() {
+                    problems.push(format!("mismatched chunks {:?}", mismatched));
+                }
+                if hashes.len() < self.chunks.len() {
+                    problems.push(format!("missing chunks {:?}", (hashes.len()..self.chunks.len()).collect::<Vec<_>>()));
+                }
+                if hashes.len() > self.chunks.len() {
+                    problems.push(format!("{} unexpected extra chunks", hashes.len() - self.chunks.len()));
+                }
+
+                if problems.is_empty() {
+                    Ok(())
+                } else {
+                    Err(UploadError::Verification(create_error_string(&format!(
+                        "SHA-256 verification failed ({} local chunks, {} on the canister): {}",
+                        self.chunks.len(),
+                        hashes.len(),
+                        problems.join("; ")
+                    ))))
+                }
+            }
+        }
+    }
+}
+
+/// Hashes reported by the canister
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteHashes {
+    /// SHA-256 of the assembled file
+    File(Vec<u8>),
+    /// SHA-256 of every chunk, by chunk index
+    Chunks(Vec<Vec<u8>>),
+}
+
+/// Asks the canister for its hashes through a query.
+///
+/// # Arguments
+///
+/// * `transport` - The transport used to submit the query.
+/// * `params` - Upload parameters including canister info.
+/// * `hash_query` - The query method and its argument.
+///
+/// # Returns
+///
+/// A `Result` containing the decoded hashes or an error message.
+pub fn query_remote_hashes<T: Transport + ?Sized>(
+    transport: &T,
+    params: &UploadParams,
+    hash_query: &CanisterCall,
+) -> Result<RemoteHashes, UploadError> {
+    let reply = transport
+        .query(params.canister_name, &hash_query.method, &hash_query.args)
+        .map_err(|e| UploadError::from(e).context(format!("Query {} failed", hash_query.method)))?;
+
+    parse_remote_hashes(&reply)
+}
+
+/// Decodes a hash query reply: a `blob` for the whole file or a `vec blob` per chunk,
+/// optionally wrapped in `opt` or the `Ok` variant of a `Result`.
+pub fn parse_remote_hashes(reply: &[u8]) -> Result<RemoteHashes, UploadError> {
+    let value = reply_value(reply, "Hash query")?;
+    match &value {
+        IDLValue::Vec(values) if !values.is_empty() && values.iter().all(|value| matches!(value, IDLValue::Nat8(_))) => {
+            value_to_bytes(&value).map(RemoteHashes::File)
+        }
+        IDLValue::Vec(values) => values
+            .iter()
+            .map(value_to_bytes)
+            .collect::<Result<_, _>>()
+            .map(RemoteHashes::Chunks),
+        other => value_to_bytes(other).map(RemoteHashes::File),
+    }
+}
+
+/// Reads a Candid `blob` (or `vec nat8`)
+fn value_to_bytes(value: &IDLValue) -> Result<Vec<u8>, UploadError> {
+    match value {
+        IDLValue::Blob(bytes) => Ok(bytes.clone()),
+        IDLValue::Vec(values) => values
+            .iter()
+            .map(|value| match value {
+                IDLValue::Nat8(byte) => Ok(*byte),
+                other => Err(UploadError::Candid(create_error_string(&format!("Unexpected value in hash: {}", other)))),
+            })
+            .collect(),
+        other => Err(UploadError::Candid(create_error_string(&format!("Unsupported hash reply: {}", other)))),
+    }
+}
+
+/// Verifies that the canister holds exactly the contents of `source`.
+///
+/// # Arguments
+///
+/// * `transport` - The transport used to submit the query.
+/// * `params` - Upload parameters including canister info.
+/// * `source` - Source the uploaded chunks were read from.
+/// * `hash_query` - The query method returning the canister's hashes, and its argument.
+///
+/// # Returns
+///
+/// The SHA-256 of the file if the hashes match, or an error describing the mismatch.
+pub fn verify_upload<T: Transport + ?Sized, S: ChunkSource + ?Sized>(
+    transport: &T,
+    params: &UploadParams,
+    source: &S,
+    hash_query: &CanisterCall,
+) -> Result<[u8; 32], UploadError> {
+    let local = LocalHashes::compute(source).map_err(|e| UploadError::io(format!("Failed to hash {}", params.name), e))?;
+    let remote = query_remote_hashes(transport, params, hash_query)?;
+
+    local.compare(&remote)?;
+    Ok(local.file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{test_params, MockTransport};
+    use candid::Encode;
+    use serde_bytes::ByteBuf;
+
+    fn chunk_hash(data: &[u8]) -> ByteBuf {
+        ByteBuf::from(Sha256::digest(data).to_vec())
+    }
+
+    #[test]
+    fn test_verify_file_hash() {
+        let chunks = vec![b"ab".to_vec(), b"c".to_vec()];
+        let transport = MockTransport::with_responder(|_| Ok(Encode!(&Some(chunk_hash(b"abc"))).unwrap()));
+
+        verify_upload(&transport, &test_params(), &chunks, &CanisterCall::without_args("sha256")).unwrap();
+        assert!(transport.calls()[0].query);
+
+        let wrong = vec![b"ba".to_vec(), b"c".to_vec()];
+        let error = verify_upload(&transport, &test_params(), &wrong, &CanisterCall::without_args("sha256")).unwrap_err();
+        assert!(matches!(&error, UploadError::Verification(message) if message.contains("SHA-256 mismatch")));
+    }
+
+    #[test]
+    fn test_verify_chunk_hashes_lists_mismatches() {
+        let chunks = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()];
+        let local = LocalHashes::compute(&chunks).unwrap();
+
+        // Chunks 1 and 2 swapped, chunk 3 missing
+        let reply = Encode!(&vec![chunk_hash(b"a"), chunk_hash(b"c"), chunk_hash(b"b")]).unwrap();
+        let error = local.compare(&parse_remote_hashes(&reply).unwrap()).unwrap_err();
+
+        assert!(error.to_string().contains("mismatched chunks [1, 2]"));
+        assert!(error.to_string().contains("missing chunks [3]"));
+
+        let reply = Encode!(&vec![chunk_hash(b"a"), chunk_hash(b"b"), chunk_hash(b"c"), chunk_hash(b"d")]).unwrap();
+        assert!(local.compare(&parse_remote_hashes(&reply).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_parse_hash_errors() {
+        let none: Option<ByteBuf> = None;
+        assert!(parse_remote_hashes(&Encode!(&none).unwrap()).unwrap_err().to_string().contains("no data"));
+
+        let err: Result<ByteBuf, String> = Err("No data for key".to_string());
+        let error = parse_remote_hashes(&Encode!(&err).unwrap()).unwrap_err();
+        assert!(matches!(&error, UploadError::Reply(message) if message.contains("No data for key")));
+    }
+}